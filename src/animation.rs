@@ -0,0 +1,132 @@
+use egui::color::Color32;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder},
+    AnimationDecoder, DynamicImage, Frames, ImageFormat, RgbImage,
+};
+use palette::Lab;
+use std::io::Cursor;
+
+use crate::cleanup::{self, CleanupParams};
+use crate::util::{self, KmeansParams};
+use crate::weights;
+
+/// At most this many frames are sampled for the shared palette, so long
+/// animations don't make k-means crawl.
+const MAX_PALETTE_FRAMES: usize = 16;
+
+#[derive(Clone)]
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// Decodes every frame of an animated GIF or APNG. Returns `None` for
+/// anything that isn't animated, including single frame GIFs.
+pub fn decode_frames(bytes: &[u8]) -> Option<Vec<AnimationFrame>> {
+    let frames = match image::guess_format(bytes).ok()? {
+        ImageFormat::Gif => collect_frames(GifDecoder::new(Cursor::new(bytes)).ok()?.into_frames()),
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes)).ok()?;
+            if !decoder.is_apng() {
+                return None;
+            }
+            collect_frames(decoder.apng().into_frames())
+        }
+        _ => None,
+    }?;
+
+    if frames.len() > 1 {
+        Some(frames)
+    } else {
+        None
+    }
+}
+
+fn collect_frames(frames: Frames<'_>) -> Option<Vec<AnimationFrame>> {
+    frames
+        .map(|frame| {
+            let frame = frame.ok()?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            Some(AnimationFrame {
+                delay_ms: numer / denom.max(1),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            })
+        })
+        .collect()
+}
+
+/// Runs k-means over (a sample of) all frames at once so every frame shares
+/// the same palette.
+pub fn calculate_shared_kmeans(
+    frames: &[AnimationFrame],
+    params: KmeansParams,
+    pinned: &[[u8; 3]],
+) -> Option<(Vec<Color32>, Vec<Lab>, Vec<f32>)> {
+    let first = frames.first()?;
+    let (width, height) = (first.image.width(), first.image.height());
+    let step = (frames.len() + MAX_PALETTE_FRAMES - 1) / MAX_PALETTE_FRAMES;
+    let sampled: Vec<&AnimationFrame> = frames.iter().step_by(step.max(1)).collect();
+
+    let mut stacked = RgbImage::new(width, height * sampled.len() as u32);
+    for (i, frame) in sampled.iter().enumerate() {
+        image::imageops::replace(
+            &mut stacked,
+            &frame.image.to_rgb8(),
+            0,
+            (i as u32 * height) as i64,
+        );
+    }
+    weights::pinned_kmeans(DynamicImage::ImageRgb8(stacked), params, pinned)
+}
+
+/// Pixelizes every frame with the same settings and palette, keeping the
+/// frame timing.
+pub fn pixelize_frames(
+    frames: &[AnimationFrame],
+    pixel_size: usize,
+    colors: &[Lab],
+    dither: f32,
+    cleanup_params: CleanupParams,
+) -> Option<Vec<AnimationFrame>> {
+    let size = util::calc_target_size(frames.first()?.image.clone(), pixel_size)?;
+    Some(
+        frames
+            .iter()
+            .map(|frame| {
+                let output = util::generate_image(
+                    frame.image.clone(),
+                    pixel_size,
+                    size,
+                    colors.to_vec(),
+                    dither,
+                );
+                let output = if cleanup_params.is_enabled() {
+                    cleanup::cleanup_image(output, cleanup_params)
+                } else {
+                    output
+                };
+                AnimationFrame {
+                    image: output,
+                    delay_ms: frame.delay_ms,
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Index of the frame showing at `time_ms`, looping over the animation.
+pub fn frame_at(frames: &[AnimationFrame], time_ms: u64) -> usize {
+    let total: u64 = frames.iter().map(|f| f.delay_ms.max(1) as u64).sum();
+    if total == 0 {
+        return 0;
+    }
+    let mut t = time_ms % total;
+    for (i, frame) in frames.iter().enumerate() {
+        let delay = frame.delay_ms.max(1) as u64;
+        if t < delay {
+            return i;
+        }
+        t -= delay;
+    }
+    0
+}
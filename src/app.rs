@@ -2,14 +2,62 @@ use eframe::egui;
 use egui_extras::image::RetainedImage;
 #[cfg(target_arch = "wasm32")]
 use futures::Future;
-use image::{codecs::png::PngEncoder, DynamicImage, ImageEncoder};
+use image::{DynamicImage, GenericImageView, Pixel};
+use palette::{FromColor, Srgb};
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashMap;
 #[cfg(not(target_arch = "wasm32"))]
 use std::path::PathBuf;
 
-use crate::util::{self, dynamic_image_to_color_image, KmeansParams};
+use crate::animation::{self, AnimationFrame};
+use crate::canvas::{self, CompareMode, View};
+use crate::cleanup::{self, CleanupParams};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::cli::CliArgs;
+use crate::editor::{self, PaletteIndices, Tool, ToolEvent, ToolStroke};
+use crate::export::{self, ExportOptions, OutputFormat};
+use crate::hardware::{self, HardwareParams};
+use crate::history::{History, Snapshot};
+use crate::input;
+use crate::native;
+use crate::pipeline::{PaletteParams, Pipeline};
+use crate::preset::{self, PipelineSettings, Preset};
+use crate::project::{self, PaletteEntry, Project, ProjectImage};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::sequence;
+use crate::slic::Pixelization;
+use crate::tiles::TilemapFormat;
+use crate::transform::{self, CropMode, CropSettings, Transform};
+use crate::util::{self, dynamic_image_to_color_image, KmeansParams, PaletteSort};
+use crate::weights::{ImportanceMask, WeightParams, Weighting, Weights};
 
 const DEBUG: bool = false;
 
+/// Name of the native window, which also names where eframe keeps its state.
+pub const APP_NAME: &str = "pixelite";
+
+/// Seconds the settings have to stay put before the auto preview runs.
+const PREVIEW_DEBOUNCE: f64 = 0.3;
+
+/// The auto preview finds its palette in at most this many sampled pixels.
+const PREVIEW_SAMPLE_PIXELS: u32 = 64 * 1024;
+
+/// Everything that changes the picture the auto preview shows.
+#[derive(Clone, PartialEq)]
+struct PreviewKey {
+    input_revision: u64,
+    transform: Transform,
+    pixel_size: usize,
+    grid_offset: [u32; 2],
+    pixelization: Pixelization,
+    kmeans_params: KmeansParams,
+    weights: Option<(WeightParams, Option<u64>)>,
+    pinned_colors: Vec<[u8; 3]>,
+    dither: f32,
+    cleanup_params: CleanupParams,
+    hardware_params: HardwareParams,
+}
+
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -33,10 +81,18 @@ pub struct PixeliteApp {
     output_window: bool,
     info_window: bool,
     color_palette_window: bool,
+    history_window: bool,
+    compare_window: bool,
     is_loading: bool,
 
     pixel_size: usize,
 
+    /// Where the block grid starts, in pixels from the top left corner.
+    grid_offset: [u32; 2],
+
+    /// How the input is divided into output pixels.
+    pixelization: Pixelization,
+
     raw_input: Option<Vec<u8>>,
     raw_output: Option<Vec<u8>>,
 
@@ -46,9 +102,39 @@ pub struct PixeliteApp {
     #[serde(skip)]
     output_img_dyn: Option<DynamicImage>,
 
+    #[serde(skip)]
+    frames: Option<Vec<AnimationFrame>>,
+
+    #[serde(skip)]
+    output_frames: Option<Vec<AnimationFrame>>,
+
+    #[serde(skip)]
+    output_frame_images: Option<Vec<RetainedImage>>,
+
+    /// Palette entry of every output pixel, looked up from the colors when
+    /// first needed.
+    #[serde(skip)]
+    output_indices: Option<PaletteIndices>,
+
     #[serde(skip)]
     color_palette: Option<Vec<egui::Color32>>,
 
+    #[serde(skip)]
+    lab_palette: Option<Vec<palette::Lab>>,
+
+    #[serde(skip)]
+    palette_coverage: Option<Vec<f32>>,
+
+    palette_sort: PaletteSort,
+
+    export_options: ExportOptions,
+
+    #[serde(with = "preset::stored")]
+    presets: Vec<Preset>,
+
+    /// Name typed in for saving the current settings as a preset.
+    preset_name: String,
+
     #[serde(skip)]
     image: Option<RetainedImage>,
 
@@ -57,6 +143,113 @@ pub struct PixeliteApp {
 
     #[serde(skip)]
     kmeans_params: KmeansParams,
+
+    #[serde(skip)]
+    weight_params: WeightParams,
+
+    /// Colors always in the palette, k-means picks the rest.
+    #[serde(skip)]
+    pinned_colors: Vec<[u8; 3]>,
+
+    #[serde(skip)]
+    cleanup_params: CleanupParams,
+
+    #[serde(skip)]
+    dither: f32,
+
+    #[serde(skip)]
+    hardware_params: HardwareParams,
+
+    /// Sub-palettes chosen by the hardware constraints, with the number of
+    /// cells using each.
+    #[serde(skip)]
+    sub_palettes: Option<Vec<(Vec<usize>, usize)>>,
+
+    #[serde(skip)]
+    history: History,
+
+    #[serde(skip)]
+    sequence_frames: Vec<std::path::PathBuf>,
+
+    compare_mode: CompareMode,
+
+    /// Position of the split line in the compare window, from 0 to 1.
+    compare_split: f32,
+
+    /// Opacity of the output over the source in onion skin mode.
+    onion_opacity: f32,
+
+    /// Zoom and pan shared by both sides of the compare window.
+    #[serde(skip)]
+    compare_view: Option<View>,
+
+    output_grid: bool,
+
+    #[serde(skip)]
+    output_view: Option<View>,
+
+    tool: Tool,
+
+    /// Palette index the editing tools draw with.
+    #[serde(skip)]
+    tool_color: usize,
+
+    #[serde(skip)]
+    tool_stroke: Option<ToolStroke>,
+
+    /// Bumped whenever a new picture is loaded, so cached results of the old
+    /// one are not reused.
+    #[serde(skip)]
+    input_revision: u64,
+
+    auto_preview: bool,
+
+    /// The settings the output was last rendered with.
+    #[serde(skip)]
+    preview_key: Option<PreviewKey>,
+
+    /// When the pending auto preview runs, in seconds of app time.
+    #[serde(skip)]
+    preview_due: Option<f64>,
+
+    /// Intermediate results of the current input.
+    #[serde(skip)]
+    pipeline: Pipeline,
+
+    /// Rotation, flips, straightening and crop of the current input.
+    #[serde(skip)]
+    transform: Transform,
+
+    crop_settings: CropSettings,
+
+    /// The input as rotated, flipped and straightened for the input window,
+    /// with the input revision and orientation it shows. No picture when
+    /// the input is shown as loaded.
+    #[serde(skip)]
+    oriented_image: Option<(u64, Transform, Option<RetainedImage>)>,
+
+    #[serde(skip)]
+    input_view: Option<View>,
+
+    /// Shows the block grid over the input.
+    input_grid: bool,
+
+    /// Importance painted over the input, for weighted palettes.
+    #[serde(skip)]
+    importance_mask: Option<ImportanceMask>,
+
+    /// The mask as shown over the input, with the mask revision it shows.
+    #[serde(skip)]
+    mask_image: Option<(u64, RetainedImage)>,
+
+    /// The input window paints the mask instead of cropping.
+    #[serde(skip)]
+    paint_importance: bool,
+
+    brush_radius: f32,
+
+    #[serde(skip)]
+    brush_erase: bool,
 }
 
 impl Default for PixeliteApp {
@@ -72,13 +265,27 @@ impl Default for PixeliteApp {
             output_window: true,
             info_window: false,
             color_palette_window: false,
+            history_window: false,
+            compare_window: false,
             is_loading: false,
             pixel_size: 16,
+            grid_offset: [0, 0],
+            pixelization: Pixelization::Blocks,
             raw_input: None,
             raw_output: None,
             img_dyn: None,
             output_img_dyn: None,
+            frames: None,
+            output_frames: None,
+            output_frame_images: None,
+            output_indices: None,
             color_palette: None,
+            lab_palette: None,
+            palette_coverage: None,
+            palette_sort: PaletteSort::Unsorted,
+            export_options: ExportOptions::default(),
+            presets: Vec::new(),
+            preset_name: String::new(),
             image: None,
             output_image: None,
             kmeans_params: KmeansParams {
@@ -89,6 +296,51 @@ impl Default for PixeliteApp {
                 verbose: false,
                 seed: 0,
             },
+            cleanup_params: CleanupParams {
+                remove_orphans: false,
+                orphan_strength: 1,
+                smooth_jaggies: false,
+                jaggy_strength: 1,
+                merge_islands: false,
+                island_min_size: 3,
+            },
+            weight_params: WeightParams::default(),
+            pinned_colors: Vec::new(),
+            dither: 0.0,
+            hardware_params: HardwareParams {
+                enabled: false,
+                cell_width: 8,
+                cell_height: 8,
+                sub_palettes: 4,
+                colors_per_palette: 4,
+            },
+            sub_palettes: None,
+            history: History::default(),
+            sequence_frames: Vec::new(),
+            compare_mode: CompareMode::Split,
+            compare_split: 0.5,
+            onion_opacity: 0.5,
+            compare_view: None,
+            output_grid: false,
+            output_view: None,
+            tool: Tool::Pan,
+            tool_color: 0,
+            tool_stroke: None,
+            input_revision: 0,
+            auto_preview: false,
+            preview_key: None,
+            preview_due: None,
+            pipeline: Pipeline::default(),
+            transform: Transform::default(),
+            crop_settings: CropSettings::default(),
+            oriented_image: None,
+            input_view: None,
+            input_grid: false,
+            importance_mask: None,
+            mask_image: None,
+            paint_importance: false,
+            brush_radius: 16.0,
+            brush_erase: false,
         }
     }
 }
@@ -109,6 +361,901 @@ impl PixeliteApp {
     }
 }
 
+impl PixeliteApp {
+    /// Runs the pipeline on the loaded picture, and on all of its frames if
+    /// it is animated. Animations always use grid blocks.
+    fn generate(&mut self) {
+        if self.pixelization == Pixelization::Superpixels && self.frames.is_none() {
+            self.generate_superpixels();
+            return;
+        }
+        if let Some(image) = &self.img_dyn {
+            let revision = self.input_revision;
+            let frames = self.frames.as_ref().map(|frames| {
+                if self.transform.is_identity() {
+                    frames.clone()
+                } else {
+                    self.transform.apply_frames(frames)
+                }
+            });
+            let weights = palette_weights(
+                self.weight_params,
+                &self.importance_mask,
+                (revision, self.transform.orientation()),
+            );
+            let params = PaletteParams {
+                kmeans: self.kmeans_params,
+                weights,
+                pinned: &self.pinned_colors,
+            };
+            let palette =
+                self.pipeline
+                    .palette(revision, image, &self.transform, frames.as_deref(), params);
+            let (rgb_palette, lab_palette, coverage) = match palette.cloned() {
+                Some(palette) => palette,
+                None => {
+                    self.information = "No palette could be found in the picture".to_string();
+                    return;
+                }
+            };
+            self.color_palette = Some(rgb_palette);
+            self.lab_palette = Some(lab_palette.clone());
+            self.palette_coverage = Some(coverage);
+
+            let blocks = self
+                .pipeline
+                .block_averages(
+                    revision,
+                    image,
+                    &self.transform,
+                    self.pixel_size,
+                    self.grid_offset(),
+                )
+                .cloned();
+            if let Some(blocks) = blocks {
+                let output_img = self.render_still(&blocks, &lab_palette);
+                let output_frames = frames.as_ref().and_then(|frames| {
+                    let offset = self.grid_offset();
+                    let frames: Vec<AnimationFrame> = frames
+                        .iter()
+                        .map(|frame| AnimationFrame {
+                            image: util::offset_grid(&frame.image, offset),
+                            delay_ms: frame.delay_ms,
+                        })
+                        .collect();
+                    let mut output = animation::pixelize_frames(
+                        &frames,
+                        self.pixel_size,
+                        &lab_palette,
+                        self.dither,
+                        self.cleanup_params,
+                    )?;
+                    if self.hardware_params.enabled {
+                        for frame in output.iter_mut() {
+                            if let Some(result) = hardware::apply_constraints(
+                                &frame.image,
+                                &lab_palette,
+                                self.hardware_params,
+                            ) {
+                                frame.image = result.image;
+                            }
+                        }
+                    }
+                    Some(output)
+                });
+                self.set_output(Some(output_img), output_frames);
+                let label = format!("{} px, {} colors", self.pixel_size, self.kmeans_params.k);
+                self.history.push(self.snapshot(label));
+                // A pending preview would replace the full render.
+                self.preview_key = Some(self.preview_key());
+                self.preview_due = None;
+            } else {
+                self.information = "Pixel size is too large".to_string();
+            }
+
+            self.color_palette_window = true;
+            self.output_window = true;
+        } else {
+            self.information = "Please load a picture first!".to_string();
+        }
+    }
+
+    /// The experimental alternative to block averages and k-means: the
+    /// palette and the superpixels making up the output pixels are found
+    /// together.
+    fn generate_superpixels(&mut self) {
+        let image = match &self.img_dyn {
+            Some(image) => image,
+            None => {
+                self.information = "Please load a picture first!".to_string();
+                return;
+            }
+        };
+        let result = self
+            .pipeline
+            .superpixels(
+                self.input_revision,
+                image,
+                &self.transform,
+                self.pixel_size,
+                self.kmeans_params.k,
+                &self.pinned_colors,
+            )
+            .cloned();
+        let (means, (colors, lab_palette, coverage)) = match result {
+            Some(result) => result,
+            None => {
+                self.information = "Pixel size is too large".to_string();
+                return;
+            }
+        };
+        let output_img = self.render_still(&means, &lab_palette);
+        self.color_palette = Some(colors);
+        self.lab_palette = Some(lab_palette);
+        self.palette_coverage = Some(coverage);
+        self.set_output(Some(output_img), None);
+        let label = format!(
+            "{} px superpixels, {} colors",
+            self.pixel_size, self.kmeans_params.k
+        );
+        self.history.push(self.snapshot(label));
+        self.preview_key = Some(self.preview_key());
+        self.preview_due = None;
+        self.color_palette_window = true;
+        self.output_window = true;
+    }
+
+    /// Finishes a still picture from its block averages: maps them to the
+    /// palette, then applies cleanup and hardware constraints.
+    fn render_still(
+        &mut self,
+        blocks: &image::RgbImage,
+        lab_palette: &[palette::Lab],
+    ) -> DynamicImage {
+        let output_img = util::map_to_palette(blocks, lab_palette, self.dither);
+        let output_img = if self.cleanup_params.is_enabled() {
+            cleanup::cleanup_image(output_img, self.cleanup_params)
+        } else {
+            output_img
+        };
+        let constrained = if self.hardware_params.enabled {
+            hardware::apply_constraints(&output_img, lab_palette, self.hardware_params)
+        } else {
+            None
+        };
+        match constrained {
+            Some(result) => {
+                self.information = format!(
+                    "{} sub-palettes, mean error {:.1}",
+                    result.sub_palettes.len(),
+                    result.error
+                );
+                let used = |i: usize| result.cell_palettes.iter().filter(|p| **p == i).count();
+                self.sub_palettes = Some(
+                    result
+                        .sub_palettes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, p)| (p.clone(), used(i)))
+                        .collect(),
+                );
+                result.image
+            }
+            None => {
+                self.sub_palettes = None;
+                output_img
+            }
+        }
+    }
+
+    /// The grid offset in use, within one block.
+    fn grid_offset(&self) -> [u32; 2] {
+        let size = self.pixel_size.max(1) as u32;
+        [self.grid_offset[0] % size, self.grid_offset[1] % size]
+    }
+
+    /// Moves the grid to where the blocks of the pre-processed input vary
+    /// the least.
+    fn auto_align_grid(&mut self) {
+        if let Some(image) = &self.img_dyn {
+            let image = self
+                .pipeline
+                .preprocessed(self.input_revision, image, &self.transform);
+            self.grid_offset = util::best_grid_offset(image, self.pixel_size);
+        }
+    }
+
+    /// Treats the pre-processed input as upscaled pixel art: finds the grid
+    /// it was drawn on and takes the picture and palette back to their
+    /// native form, instead of averaging blocks of the set pixel size.
+    fn depixelize(&mut self) {
+        let image = match &self.img_dyn {
+            Some(image) => image,
+            None => {
+                self.information = "Please load a picture first!".to_string();
+                return;
+            }
+        };
+        let image = self
+            .pipeline
+            .preprocessed(self.input_revision, image, &self.transform);
+        let native = match native::depixelize(image) {
+            Some(native) => native,
+            None => {
+                self.information = "No pixel grid found in the input".to_string();
+                return;
+            }
+        };
+        self.pixel_size = native.grid.scale;
+        self.grid_offset = native.grid.offset;
+        self.color_palette = Some(
+            native
+                .palette
+                .iter()
+                .map(|(c, _)| egui::Color32::from_rgb(c[0], c[1], c[2]))
+                .collect(),
+        );
+        self.lab_palette = Some(
+            native
+                .palette
+                .iter()
+                .map(|(c, _)| {
+                    palette::Lab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>())
+                })
+                .collect(),
+        );
+        self.palette_coverage = Some(native.palette.iter().map(|(_, share)| *share).collect());
+        self.sub_palettes = None;
+        self.information = format!(
+            "{} px grid at ({}, {}), {}x{} pixels, {} colors",
+            native.grid.scale,
+            native.grid.offset[0],
+            native.grid.offset[1],
+            native.image.width(),
+            native.image.height(),
+            native.palette.len()
+        );
+        let label = format!("Native, {} colors", native.palette.len());
+        self.set_output(Some(native.image), None);
+        self.history.push(self.snapshot(label));
+        self.preview_key = Some(self.preview_key());
+        self.preview_due = None;
+        self.color_palette_window = true;
+        self.output_window = true;
+    }
+
+    fn preview_key(&self) -> PreviewKey {
+        PreviewKey {
+            input_revision: self.input_revision,
+            transform: self.transform,
+            pixel_size: self.pixel_size,
+            grid_offset: self.grid_offset(),
+            pixelization: self.pixelization,
+            kmeans_params: self.kmeans_params,
+            weights: palette_weights(
+                self.weight_params,
+                &self.importance_mask,
+                (self.input_revision, self.transform.orientation()),
+            )
+            .key(),
+            pinned_colors: self.pinned_colors.clone(),
+            dither: self.dither,
+            cleanup_params: self.cleanup_params,
+            hardware_params: self.hardware_params,
+        }
+    }
+
+    /// A quick render of the first frame for the auto preview. The palette
+    /// comes from a sample of the picture with a single k-means run, and is
+    /// reused while only the pixel size, dithering or cleanup change.
+    fn preview(&mut self) {
+        if self.pixelization == Pixelization::Superpixels {
+            self.information = "Superpixels are rendered by Generate only".to_string();
+            return;
+        }
+        let image = match &self.img_dyn {
+            Some(image) => image,
+            None => return,
+        };
+        let revision = self.input_revision;
+        let weights = palette_weights(
+            self.weight_params,
+            &self.importance_mask,
+            (revision, self.transform.orientation()),
+        );
+        let params = PaletteParams {
+            kmeans: self.kmeans_params,
+            weights,
+            pinned: &self.pinned_colors,
+        };
+        let palette = self.pipeline.preview_palette(
+            revision,
+            image,
+            &self.transform,
+            params,
+            PREVIEW_SAMPLE_PIXELS,
+        );
+        let (colors, lab_palette, coverage) = match palette {
+            Some(palette) => palette.clone(),
+            None => return,
+        };
+        let blocks = match self.pipeline.block_averages(
+            revision,
+            image,
+            &self.transform,
+            self.pixel_size,
+            self.grid_offset(),
+        ) {
+            Some(blocks) => blocks.clone(),
+            None => {
+                self.information = "Pixel size is too large".to_string();
+                return;
+            }
+        };
+        self.information = "Preview. Click Generate for the full render.".to_string();
+        let output = self.render_still(&blocks, &lab_palette);
+        self.color_palette = Some(colors);
+        self.lab_palette = Some(lab_palette);
+        self.palette_coverage = Some(coverage);
+        self.set_output(Some(output), None);
+    }
+
+    /// Keeps the input window's picture turned like the input is.
+    fn refresh_oriented_image(&mut self) {
+        let orientation = self.transform.orientation();
+        let fresh = matches!(
+            &self.oriented_image,
+            Some((revision, shown, _)) if *revision == self.input_revision && *shown == orientation
+        );
+        if fresh {
+            return;
+        }
+        let oriented = match &self.img_dyn {
+            Some(image) if !orientation.is_identity() => Some(RetainedImage::from_color_image(
+                "oriented",
+                dynamic_image_to_color_image(orientation.orient(image)),
+            )),
+            _ => None,
+        };
+        self.oriented_image = Some((self.input_revision, orientation, oriented));
+        self.input_view = None;
+    }
+
+    /// Keeps the mask shown over the input in step with the painted one.
+    fn refresh_mask_image(&mut self) {
+        let mask = match &self.importance_mask {
+            Some(mask) if self.paint_importance => mask,
+            _ => {
+                self.mask_image = None;
+                return;
+            }
+        };
+        if matches!(&self.mask_image, Some((revision, _)) if *revision == mask.revision) {
+            return;
+        }
+        let pixels: Vec<u8> = mask
+            .image
+            .pixels()
+            .flat_map(|p| [255, 96, 0, (p[0] as u32 * 160 / 255) as u8])
+            .collect();
+        let size = [mask.image.width() as usize, mask.image.height() as usize];
+        let image = egui::ColorImage::from_rgba_unmultiplied(size, &pixels);
+        self.mask_image = Some((
+            mask.revision,
+            RetainedImage::from_color_image("importance mask", image),
+        ));
+    }
+
+    /// Shows a new output, replacing the textures of the output window.
+    fn set_output(&mut self, output: Option<DynamicImage>, frames: Option<Vec<AnimationFrame>>) {
+        // Keep the zoom while the output keeps its size.
+        let size = |img: &DynamicImage| (img.width(), img.height());
+        if self.output_img_dyn.as_ref().map(size) != output.as_ref().map(size) {
+            self.output_view = None;
+        }
+        self.raw_output = output.as_ref().map(|img| img.to_rgb8().to_vec());
+        self.output_image = output.as_ref().map(|img| {
+            RetainedImage::from_color_image("output", dynamic_image_to_color_image(img.clone()))
+                .with_texture_filter(egui::TextureFilter::Nearest)
+        });
+        self.output_img_dyn = output;
+        self.output_frame_images = frames.as_ref().map(|frames| {
+            frames
+                .iter()
+                .map(|f| {
+                    RetainedImage::from_color_image(
+                        "output",
+                        dynamic_image_to_color_image(f.image.clone()),
+                    )
+                    .with_texture_filter(egui::TextureFilter::Nearest)
+                })
+                .collect()
+        });
+        self.output_frames = frames;
+        self.output_indices = None;
+    }
+
+    /// The palette entries of the output pixels, looking them up from the
+    /// colors if an edit hasn't kept them.
+    fn take_output_indices(&mut self) -> PaletteIndices {
+        if let Some(indices) = self.output_indices.take() {
+            return indices;
+        }
+        let palette = self.palette_rgb();
+        let index = |image: &DynamicImage| editor::index_pixels(&image.to_rgba8(), &palette);
+        PaletteIndices {
+            output: self.output_img_dyn.as_ref().map(index).unwrap_or_default(),
+            frames: self
+                .output_frames
+                .iter()
+                .flatten()
+                .map(|f| index(&f.image))
+                .collect(),
+        }
+    }
+
+    fn palette_rgb(&self) -> Vec<[u8; 3]> {
+        self.color_palette
+            .iter()
+            .flatten()
+            .map(|c| [c.r(), c.g(), c.b()])
+            .collect()
+    }
+
+    fn snapshot(&self, label: String) -> Snapshot {
+        Snapshot {
+            label,
+            settings: self.pipeline_settings(),
+            color_palette: self.color_palette.clone(),
+            lab_palette: self.lab_palette.clone(),
+            palette_coverage: self.palette_coverage.clone(),
+            sub_palettes: self.sub_palettes.clone(),
+            output: self.output_img_dyn.clone(),
+            output_frames: self.output_frames.clone(),
+            output_indices: self.output_indices.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.apply_settings(&snapshot.settings);
+        self.color_palette = snapshot.color_palette;
+        self.lab_palette = snapshot.lab_palette;
+        self.palette_coverage = snapshot.palette_coverage;
+        self.sub_palettes = snapshot.sub_palettes;
+        self.tool_stroke = None;
+        self.set_output(snapshot.output, snapshot.output_frames);
+        self.output_indices = snapshot.output_indices;
+        // Restored states are shown as they were, not previewed again.
+        self.preview_key = Some(self.preview_key());
+        self.preview_due = None;
+    }
+
+    fn undo(&mut self) {
+        if let Some(snapshot) = self.history.undo().cloned() {
+            self.restore(snapshot);
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(snapshot) = self.history.redo().cloned() {
+            self.restore(snapshot);
+        }
+    }
+
+    /// Changes one palette color and repaints the output with it.
+    fn edit_palette_color(&mut self, index: usize, color: egui::Color32) {
+        let in_palette = |len: Option<usize>| len.map_or(false, |len| index < len);
+        if !in_palette(self.color_palette.as_ref().map(Vec::len))
+            || !in_palette(self.lab_palette.as_ref().map(Vec::len))
+        {
+            return;
+        }
+        // Find the pixels of each entry before two entries may share a color.
+        let indices = self.take_output_indices();
+        let (colors, lab) = match (&mut self.color_palette, &mut self.lab_palette) {
+            (Some(colors), Some(lab)) => (colors, lab),
+            _ => return,
+        };
+        let before = colors[index];
+        colors[index] = color;
+        lab[index] = palette::Lab::from_color(
+            Srgb::new(color.r(), color.g(), color.b()).into_format::<f32>(),
+        );
+
+        let recolor = |image: &DynamicImage, indices: &[Option<u16>]| {
+            let mut rgba = image.to_rgba8();
+            editor::recolor(&mut rgba, indices, index, [color.r(), color.g(), color.b()]);
+            DynamicImage::ImageRgba8(rgba)
+        };
+        let output = self
+            .output_img_dyn
+            .as_ref()
+            .map(|img| recolor(img, &indices.output));
+        let frames = self.output_frames.as_ref().map(|frames| {
+            frames
+                .iter()
+                .zip(&indices.frames)
+                .map(|(f, indices)| AnimationFrame {
+                    image: recolor(&f.image, indices),
+                    delay_ms: f.delay_ms,
+                })
+                .collect()
+        });
+        self.set_output(output, frames);
+        self.output_indices = Some(indices);
+
+        // A pinned color stays pinned as edited.
+        let before = [before.r(), before.g(), before.b()];
+        let after = [color.r(), color.g(), color.b()];
+        if let Some(i) = self.pinned_colors.iter().position(|p| *p == before) {
+            if self.pinned_colors.contains(&after) {
+                self.pinned_colors.remove(i);
+            } else {
+                self.pinned_colors[i] = after;
+            }
+            // The output already shows the new pin.
+            self.preview_key = Some(self.preview_key());
+            self.preview_due = None;
+        }
+        self.history
+            .amend(self.snapshot(format!("Edit color {}", index + 1)));
+    }
+
+    /// Applies the selected tool to a still output. Edits are drawn with the
+    /// selected palette color and recorded in the history once the pointer
+    /// is released.
+    fn use_tool(&mut self, event: ToolEvent) {
+        let color = match (&self.color_palette, &self.output_img_dyn) {
+            (Some(colors), Some(_)) if self.output_frames.is_none() => {
+                match colors.get(self.tool_color) {
+                    Some(c) => image::Rgba([c.r(), c.g(), c.b(), 255]),
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        let color = if self.tool == Tool::Eraser {
+            editor::TRANSPARENT
+        } else {
+            color
+        };
+        let mut image = match &self.output_img_dyn {
+            Some(output) => output.to_rgba8(),
+            None => return,
+        };
+
+        match event {
+            ToolEvent::Press(x, y) => {
+                let pos = (x, y);
+                let before = image.clone();
+                match self.tool {
+                    Tool::Pan => return,
+                    Tool::Eyedropper => {
+                        if let Some(pixel) = editor::get_pixel(&image, pos) {
+                            let picked = egui::Color32::from_rgb(pixel[0], pixel[1], pixel[2]);
+                            let colors = self.color_palette.as_deref().unwrap_or_default();
+                            if let Some(index) = colors.iter().position(|c| *c == picked) {
+                                self.tool_color = index;
+                            }
+                        }
+                        return;
+                    }
+                    Tool::Pencil | Tool::Eraser | Tool::Line | Tool::Rectangle => {
+                        editor::put_pixel(&mut image, pos, color)
+                    }
+                    Tool::Fill => editor::flood_fill(&mut image, pos, color),
+                    Tool::Replace => editor::replace_color(&mut image, pos, color),
+                }
+                self.tool_stroke = Some(ToolStroke {
+                    start: pos,
+                    last: pos,
+                    before,
+                });
+            }
+            ToolEvent::Drag(x, y) => {
+                let stroke = match &mut self.tool_stroke {
+                    Some(stroke) if stroke.last != (x, y) => stroke,
+                    _ => return,
+                };
+                match self.tool {
+                    Tool::Pencil | Tool::Eraser => {
+                        editor::draw_line(&mut image, stroke.last, (x, y), color)
+                    }
+                    Tool::Line => {
+                        image = stroke.before.clone();
+                        editor::draw_line(&mut image, stroke.start, (x, y), color);
+                    }
+                    Tool::Rectangle => {
+                        image = stroke.before.clone();
+                        editor::draw_rectangle(&mut image, stroke.start, (x, y), color);
+                    }
+                    _ => return,
+                }
+                stroke.last = (x, y);
+            }
+            ToolEvent::Release => {
+                if let Some(stroke) = self.tool_stroke.take() {
+                    if stroke.before != image {
+                        self.history
+                            .push(self.snapshot(self.tool.name().to_string()));
+                    }
+                }
+                return;
+            }
+        }
+        let mut indices = self.take_output_indices();
+        if let Some(before) = &self.output_img_dyn {
+            let palette = self.palette_rgb();
+            editor::reindex(
+                &mut indices.output,
+                &before.to_rgba8(),
+                &image,
+                &palette,
+                self.tool_color,
+            );
+        }
+        self.set_output(Some(DynamicImage::ImageRgba8(image)), None);
+        self.output_indices = Some(indices);
+    }
+
+    /// The palette in the order it is shown, which indexed exports keep.
+    fn display_palette(&self) -> Vec<egui::Color32> {
+        match (
+            &self.color_palette,
+            &self.lab_palette,
+            &self.palette_coverage,
+        ) {
+            (Some(colors), Some(lab), Some(coverage)) => {
+                util::sort_palette(lab, coverage, self.palette_sort)
+                    .into_iter()
+                    .map(|i| colors[i])
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn pipeline_settings(&self) -> PipelineSettings {
+        PipelineSettings {
+            pixel_size: Some(self.pixel_size),
+            grid_offset: Some(self.grid_offset),
+            pixelization: Some(self.pixelization),
+            kmeans_params: Some(self.kmeans_params),
+            weight_params: Some(self.weight_params),
+            pinned_colors: Some(self.pinned_colors.clone()),
+            dither: Some(self.dither),
+            cleanup_params: Some(self.cleanup_params),
+            hardware_params: Some(self.hardware_params),
+            palette_sort: Some(self.palette_sort),
+            export_options: Some(self.export_options.clone()),
+        }
+    }
+
+    fn apply_settings(&mut self, settings: &PipelineSettings) {
+        if let Some(pixel_size) = settings.pixel_size {
+            self.pixel_size = pixel_size.max(1);
+        }
+        if let Some(offset) = settings.grid_offset {
+            self.grid_offset = offset;
+        }
+        if let Some(pixelization) = settings.pixelization {
+            self.pixelization = pixelization;
+        }
+        if let Some(params) = settings.kmeans_params {
+            self.kmeans_params = params;
+        }
+        if let Some(params) = settings.weight_params {
+            self.weight_params = params;
+        }
+        if let Some(colors) = &settings.pinned_colors {
+            self.pinned_colors = colors.clone();
+        }
+        if let Some(dither) = settings.dither {
+            self.dither = dither.clamp(0.0, 1.0);
+        }
+        if let Some(params) = settings.cleanup_params {
+            self.cleanup_params = params;
+        }
+        if let Some(params) = settings.hardware_params {
+            self.hardware_params = params;
+        }
+        if let Some(sort) = settings.palette_sort {
+            self.palette_sort = sort;
+        }
+        if let Some(options) = &settings.export_options {
+            self.export_options = options.clone();
+        }
+    }
+
+    /// The file name of the opened picture, without its folders.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn source_name(&self) -> Option<String> {
+        let name = self.open_file_path.as_ref()?.file_name()?;
+        name.to_str().map(str::to_string)
+    }
+
+    /// The file name of the opened picture. On the web only a name is
+    /// known, folders are dropped in case it has any.
+    #[cfg(target_arch = "wasm32")]
+    fn source_name(&self) -> Option<String> {
+        let path = self.open_file_path.as_deref()?;
+        path.rsplit(['/', '\\']).next().map(str::to_string)
+    }
+
+    fn to_project(&self) -> std::io::Result<Project> {
+        let palette = match (&self.color_palette, &self.palette_coverage) {
+            (Some(colors), Some(coverage)) => Some(
+                colors
+                    .iter()
+                    .zip(coverage)
+                    .map(|(c, coverage)| PaletteEntry {
+                        rgb: [c.r(), c.g(), c.b()],
+                        coverage: *coverage,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let output = match &self.output_img_dyn {
+            Some(image) => Some(ProjectImage::new(image, 0)?),
+            None => None,
+        };
+        let output_frames = match &self.output_frames {
+            Some(frames) => Some(
+                frames
+                    .iter()
+                    .map(|f| ProjectImage::new(&f.image, f.delay_ms))
+                    .collect::<std::io::Result<_>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Project {
+            source_name: self.source_name(),
+            source: self.raw_input.clone().unwrap_or_default(),
+            settings: self.pipeline_settings(),
+            transform: self.transform,
+            palette,
+            sub_palettes: self.sub_palettes.clone(),
+            output,
+            output_frames,
+            ..Default::default()
+        })
+    }
+
+    /// Replaces the current work with the project. Returns false, leaving
+    /// everything as it was, if the source picture can't be decoded.
+    fn load_project(&mut self, project: Project) -> bool {
+        let (img, retained, frames) =
+            match decode_input(&project.source, project.source_name.as_deref()) {
+                Some(decoded) => decoded,
+                None => return false,
+            };
+        self.raw_input = Some(project.source);
+        self.img_dyn = Some(img);
+        self.input_revision += 1;
+        self.transform = Transform::default();
+        self.image = Some(retained);
+        self.frames = frames;
+        self.sequence_frames.clear();
+
+        self.apply_settings(&project.settings);
+        self.transform = project.transform;
+
+        let palette = project.palette.unwrap_or_default();
+        self.color_palette = Some(palette.iter().map(PaletteEntry::color).collect());
+        self.lab_palette = Some(
+            palette
+                .iter()
+                .map(|entry| {
+                    let [r, g, b] = entry.rgb;
+                    palette::Lab::from_color(Srgb::new(r, g, b).into_format::<f32>())
+                })
+                .collect(),
+        );
+        self.palette_coverage = Some(palette.iter().map(|entry| entry.coverage).collect());
+        if palette.is_empty() {
+            self.color_palette = None;
+            self.lab_palette = None;
+            self.palette_coverage = None;
+        }
+        self.sub_palettes = project.sub_palettes;
+
+        let output = project.output.as_ref().and_then(ProjectImage::decode);
+        let output_frames = project
+            .output_frames
+            .and_then(|frames| frames.iter().map(ProjectImage::decode).collect());
+        self.set_output(output.map(|f| f.image), output_frames);
+        self.history.clear();
+        self.history.push(self.snapshot("Open project".to_string()));
+        true
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PixeliteApp {
+    /// Starts the app the way the command line asks for.
+    pub fn with_args(cc: &eframe::CreationContext<'_>, args: &CliArgs) -> Self {
+        let mut app = Self::new(cc);
+        if let Err(err) = app.apply_args(args) {
+            app.information = err;
+        }
+        app
+    }
+
+    /// Pixelizes the input of the command line into its output, without
+    /// opening a window. Returns what was written.
+    pub fn run_headless(args: &CliArgs) -> Result<String, String> {
+        let mut app = Self::default();
+        // Presets saved in the window can be picked by name too.
+        if let Some(stored) = Self::stored() {
+            app.presets = stored.presets;
+        }
+        app.apply_args(args)?;
+        let output = args.output.as_ref().ok_or("No output file given")?;
+        if app.img_dyn.is_none() {
+            return Err("No input picture given".to_string());
+        }
+
+        app.generate();
+        let output_img = app.output_img_dyn.as_ref().ok_or(&app.information)?;
+        save_output(
+            output,
+            output_img,
+            app.output_frames.as_deref(),
+            app.img_dyn.as_ref(),
+            &app.display_palette(),
+            &app.export_options,
+        )
+        .map_err(|err| format!("Failed to save {}: {}", output.display(), err))?;
+        Ok(format!("Wrote {}", output.display()))
+    }
+
+    /// The state the window saved when it was last closed, read the same
+    /// way eframe's own file storage reads it.
+    fn stored() -> Option<Self> {
+        let dirs = directories_next::ProjectDirs::from("", "", APP_NAME)?;
+        let file = std::fs::File::open(dirs.data_dir().join("app.ron")).ok()?;
+        let values: HashMap<String, String> = ron::de::from_reader(file).ok()?;
+        ron::from_str(values.get(eframe::APP_KEY)?).ok()
+    }
+
+    /// Imports the presets file, applies the named preset and opens the
+    /// input picture.
+    fn apply_args(&mut self, args: &CliArgs) -> Result<(), String> {
+        if let Some(path) = &args.presets_file {
+            let imported = std::fs::read(path)
+                .and_then(|bytes| preset::presets_from_json(&bytes))
+                .map_err(|err| format!("Can't read presets from {}: {}", path.display(), err))?;
+            preset::merge(&mut self.presets, imported);
+        }
+        if let Some(name) = &args.preset {
+            let preset = preset::find(&self.presets, name)
+                .ok_or_else(|| format!("There is no preset named {}", name))?
+                .clone();
+            self.apply_settings(&preset.settings);
+            self.preset_name = preset.name;
+        }
+        if !args.pinned_colors.is_empty() {
+            self.pinned_colors = args.pinned_colors.clone();
+        }
+        if let Some(path) = &args.input {
+            let bytes = std::fs::read(path)
+                .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+            let (img, retained, frames) = decode_input(&bytes, path.to_str())
+                .ok_or_else(|| format!("{} is not a supported picture", path.display()))?;
+            self.open_file_path = Some(path.clone());
+            self.raw_input = Some(bytes);
+            self.img_dyn = Some(img);
+            self.input_revision += 1;
+            self.transform = Transform::default();
+            self.image = Some(retained);
+            self.frames = frames;
+        }
+        Ok(())
+    }
+}
+
 impl eframe::App for PixeliteApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
@@ -118,6 +1265,19 @@ impl eframe::App for PixeliteApp {
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.refresh_oriented_image();
+        self.refresh_mask_image();
+        // Exports and the hover readout number colors as they are shown.
+        let shown_palette = self.display_palette();
+        // Superpixels don't follow the block grid.
+        let output_grid_offset =
+            if self.pixelization == Pixelization::Superpixels && self.frames.is_none() {
+                [0, 0]
+            } else {
+                self.grid_offset()
+            };
+        let animated = self.frames.is_some();
+
         let Self {
             dropped_files,
             dropped_file,
@@ -125,22 +1285,96 @@ impl eframe::App for PixeliteApp {
             information,
             dbg_information,
             pixel_size,
+            grid_offset,
+            pixelization,
             setting_window,
             input_window,
             output_window,
             info_window,
             color_palette_window,
+            history_window,
+            compare_window,
             is_loading,
             raw_input,
             raw_output,
             img_dyn,
             output_img_dyn,
+            frames,
+            output_frames,
+            output_frame_images,
+            output_indices: _,
             color_palette,
+            lab_palette,
+            palette_coverage,
+            palette_sort,
+            export_options,
+            presets,
+            preset_name,
             image,
             output_image,
             kmeans_params,
+            weight_params,
+            pinned_colors,
+            cleanup_params,
+            dither,
+            hardware_params,
+            sub_palettes,
+            history,
+            sequence_frames,
+            compare_mode,
+            compare_split,
+            onion_opacity,
+            compare_view,
+            output_grid,
+            output_view,
+            tool,
+            tool_color,
+            tool_stroke,
+            input_revision,
+            auto_preview,
+            preview_key,
+            preview_due,
+            pipeline,
+            transform,
+            crop_settings,
+            oriented_image,
+            input_view,
+            input_grid,
+            importance_mask,
+            mask_image,
+            paint_importance,
+            brush_radius,
+            brush_erase,
         } = self;
 
+        // Projects replace the whole state, so they are opened and saved once
+        // the windows are done with it.
+        #[cfg(not(target_arch = "wasm32"))]
+        let mut open_project: Option<PathBuf> = None;
+        #[cfg(not(target_arch = "wasm32"))]
+        let mut save_project: Option<PathBuf> = None;
+        let mut apply_preset: Option<PipelineSettings> = None;
+        let mut palette_edit: Option<(usize, egui::Color32)> = None;
+        let mut history_jump: Option<usize> = None;
+        let mut auto_align = false;
+        let mut depixelize = false;
+        let mut tool_events: Vec<ToolEvent> = Vec::new();
+
+        // Text fields keep Ctrl+Z for themselves.
+        let (mut undo, mut redo) = if ctx.wants_keyboard_input() {
+            (false, false)
+        } else {
+            let input = ctx.input();
+            let z = input.modifiers.command && input.key_pressed(egui::Key::Z);
+            (
+                z && !input.modifiers.shift,
+                z && input.modifiers.shift
+                    || input.modifiers.command && input.key_pressed(egui::Key::Y),
+            )
+        };
+        let mut save_preset: Option<String> = None;
+        let mut generate = false;
+
         // Examples of how to create different panels and windows.
         // Pick whichever suits you.
         // Tip: a good default choice is to just keep the `CentralPanel`.
@@ -153,31 +1387,76 @@ impl eframe::App for PixeliteApp {
                 ui.menu_button("File", |ui| {
                     #[cfg(not(target_arch = "wasm32"))]
                     if ui.button("Open").clicked() {
-                        self.open_file_path = choose_file(frame);
-                        let file_bytes = std::fs::read(self.open_file_path.as_ref().unwrap())
-                            .unwrap_or_default();
-                        self.raw_input = Some(file_bytes.clone());
-                        self.image =
-                            Some(RetainedImage::from_image_bytes("process", &file_bytes).unwrap());
+                        if let Some(path) = choose_file(frame) {
+                            let file_bytes = std::fs::read(&path).unwrap_or_default();
+                            if let Some((img, retained, frames)) =
+                                decode_input(&file_bytes, path.to_str())
+                            {
+                                self.open_file_path = Some(path);
+                                self.raw_input = Some(file_bytes);
+                                self.img_dyn = Some(img);
+                                self.input_revision += 1;
+                                self.transform = Transform::default();
+                                self.image = Some(retained);
+                                self.frames = frames;
+                            } else {
+                                *information = "Unsupported picture format.".to_string();
+                            }
+                        }
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    if ui.button("Open Project").clicked() {
+                        open_project = rfd::FileDialog::new()
+                            .add_filter("Pixelite project", &[project::PROJECT_EXTENSION])
+                            .pick_file();
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    if ui.button("Save Project").clicked() {
+                        if self.raw_input.is_some() {
+                            let name = self
+                                .open_file_path
+                                .as_deref()
+                                .and_then(|path| path.file_stem())
+                                .and_then(|stem| stem.to_str())
+                                .unwrap_or("project");
+                            save_project = rfd::FileDialog::new()
+                                .add_filter("Pixelite project", &[project::PROJECT_EXTENSION])
+                                .set_file_name(&format!("{}.{}", name, project::PROJECT_EXTENSION))
+                                .save_file();
+                        } else {
+                            *information = "Load a picture before saving a project".to_string();
+                        }
                     }
                     if ui.button("Save as").clicked() {
                         if self.raw_output.is_some() {
                             #[cfg(not(target_arch = "wasm32"))]
                             {
-                                let save = rfd::FileDialog::new()
-                                    .set_file_name("output.png")
+                                let selected = self.export_options.format;
+                                let mut dialog = rfd::FileDialog::new()
+                                    .add_filter(selected.name(), selected.extensions());
+                                for format in OutputFormat::ALL {
+                                    if format != selected {
+                                        dialog =
+                                            dialog.add_filter(format.name(), format.extensions());
+                                    }
+                                }
+                                let save = dialog
+                                    .set_file_name(&default_file_name(
+                                        self.open_file_path.as_deref(),
+                                        selected,
+                                    ))
                                     .save_file();
                                 if let Some(path) = save {
-                                    let mut file = std::fs::File::create(path).unwrap();
-                                    let mut encoder = PngEncoder::new(&mut file);
-                                    encoder
-                                        .write_image(
-                                            self.raw_output.as_ref().unwrap(),
-                                            self.output_img_dyn.as_ref().unwrap().width(),
-                                            self.output_img_dyn.as_ref().unwrap().height(),
-                                            image::ColorType::Rgb8,
-                                        )
-                                        .unwrap();
+                                    if let Err(err) = save_output(
+                                        &path,
+                                        self.output_img_dyn.as_ref().unwrap(),
+                                        self.output_frames.as_deref(),
+                                        self.img_dyn.as_ref(),
+                                        &shown_palette,
+                                        &self.export_options,
+                                    ) {
+                                        *information = format!("Failed to save: {}", err);
+                                    }
                                 }
                             }
                         } else {
@@ -190,6 +1469,24 @@ impl eframe::App for PixeliteApp {
                         frame.close();
                     }
                 });
+                ui.menu_button("Edit", |ui| {
+                    let undo_button = egui::Button::new("Undo (Ctrl+Z)");
+                    if ui
+                        .add_enabled(self.history.can_undo(), undo_button)
+                        .clicked()
+                    {
+                        undo = true;
+                        ui.close_menu();
+                    }
+                    let redo_button = egui::Button::new("Redo (Ctrl+Shift+Z)");
+                    if ui
+                        .add_enabled(self.history.can_redo(), redo_button)
+                        .clicked()
+                    {
+                        redo = true;
+                        ui.close_menu();
+                    }
+                });
                 ui.menu_button("Window", |ui| {
                     if ui.button("Setting").clicked() {
                         self.setting_window = !self.setting_window;
@@ -203,6 +1500,12 @@ impl eframe::App for PixeliteApp {
                     if ui.button("Color").clicked() {
                         self.color_palette_window = !self.color_palette_window;
                     }
+                    if ui.button("History").clicked() {
+                        self.history_window = !self.history_window;
+                    }
+                    if ui.button("Compare").clicked() {
+                        self.compare_window = !self.compare_window;
+                    }
                 });
                 if ui.button("Rearrenge").clicked() {
                     ui.ctx().memory().reset_areas();
@@ -221,18 +1524,22 @@ impl eframe::App for PixeliteApp {
                         #[cfg(not(target_arch = "wasm32"))]
                         {
                             if ui.button("Open").clicked() {
-                                {
-                                    self.open_file_path = choose_file(frame);
-                                    let file_bytes =
-                                        std::fs::read(self.open_file_path.as_ref().unwrap())
-                                            .unwrap();
-                                    self.raw_input = Some(file_bytes.clone());
-                                    self.img_dyn =
-                                        Some(image::load_from_memory(&file_bytes).unwrap());
-                                    self.image = Some(
-                                        RetainedImage::from_image_bytes("process", &file_bytes)
-                                            .unwrap(),
-                                    );
+                                if let Some(path) = choose_file(frame) {
+                                    let file_bytes = std::fs::read(&path).unwrap_or_default();
+                                    if let Some((img, retained, frames)) =
+                                        decode_input(&file_bytes, path.to_str())
+                                    {
+                                        self.open_file_path = Some(path);
+                                        self.raw_input = Some(file_bytes);
+                                        self.img_dyn = Some(img);
+                                        self.input_revision += 1;
+                                        self.transform = Transform::default();
+                                        self.image = Some(retained);
+                                        self.frames = frames;
+                                    } else {
+                                        self.information =
+                                            "Unsupported picture format.".to_string();
+                                    }
                                 }
                             }
                             if let Some(path) = &self.open_file_path {
@@ -266,12 +1573,131 @@ impl eframe::App for PixeliteApp {
                     });
                     ui.end_row();
 
+                    ui.label("Grid offset: ");
+                    ui.horizontal(|ui| {
+                        let max = pixel_size.saturating_sub(1) as u32;
+                        ui.label("X");
+                        ui.add(egui::DragValue::new(&mut grid_offset[0]).clamp_range(0..=max));
+                        ui.label("Y");
+                        ui.add(egui::DragValue::new(&mut grid_offset[1]).clamp_range(0..=max));
+                        if ui.button("Auto-align").clicked() {
+                            auto_align = true;
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Pixelization: ");
+                    egui::ComboBox::from_id_source("pixelization")
+                        .selected_text(pixelization.name())
+                        .show_ui(ui, |ui| {
+                            for mode in Pixelization::ALL {
+                                ui.selectable_value(pixelization, mode, mode.name());
+                            }
+                        });
+                    ui.end_row();
+
                     ui.label("Color distortion: ");
                     ui.horizontal(|ui| {
                         ui.add(egui::Slider::new(&mut kmeans_params.k, 2..=20));
                     });
                     ui.end_row();
 
+                    // Animations and superpixels find their palettes without weights.
+                    let weighted = !animated && *pixelization == Pixelization::Blocks;
+                    ui.label("Palette weights: ");
+                    ui.horizontal(|ui| {
+                        ui.add_enabled_ui(weighted, |ui| {
+                            egui::ComboBox::from_id_source("weighting")
+                                .selected_text(weight_params.weighting.name())
+                                .show_ui(ui, |ui| {
+                                    for weighting in Weighting::ALL {
+                                        ui.selectable_value(
+                                            &mut weight_params.weighting,
+                                            weighting,
+                                            weighting.name(),
+                                        );
+                                    }
+                                });
+                            if weight_params.weighting != Weighting::Uniform {
+                                ui.add(
+                                    egui::Slider::new(&mut weight_params.strength, 0.0..=16.0)
+                                        .text("Strength"),
+                                );
+                            }
+                        });
+                        if !weighted {
+                            ui.weak("Not used for animations and superpixels");
+                        }
+                    })
+                    .response
+                    .on_hover_text("Important pixels count more when the palette is found. The mask is painted in the input window.");
+                    ui.end_row();
+
+                    ui.label("Dithering: ");
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Slider::new(dither, 0.0..=1.0));
+                    });
+                    ui.end_row();
+
+                    ui.collapsing("Presets", |ui| {
+                        egui::ComboBox::from_label("Preset")
+                            .selected_text(preset_name.as_str())
+                            .show_ui(ui, |ui| {
+                                for preset in presets.iter() {
+                                    let selected = preset.name == *preset_name;
+                                    if ui.selectable_label(selected, &preset.name).clicked() {
+                                        *preset_name = preset.name.clone();
+                                        apply_preset = Some(preset.settings.clone());
+                                    }
+                                }
+                            });
+                        ui.horizontal(|ui| {
+                            ui.text_edit_singleline(preset_name);
+                            let named = !preset_name.trim().is_empty();
+                            if ui.add_enabled(named, egui::Button::new("Save")).clicked() {
+                                save_preset = Some(preset_name.trim().to_string());
+                            }
+                            if ui.add_enabled(named, egui::Button::new("Delete")).clicked() {
+                                presets
+                                    .retain(|p| !p.name.eq_ignore_ascii_case(preset_name.trim()));
+                            }
+                        });
+
+                        #[cfg(not(target_arch = "wasm32"))]
+                        ui.horizontal(|ui| {
+                            if ui.button("Import").clicked() {
+                                if let Some(path) = rfd::FileDialog::new()
+                                    .add_filter("Presets", &["json"])
+                                    .pick_file()
+                                {
+                                    match std::fs::read(&path)
+                                        .and_then(|bytes| preset::presets_from_json(&bytes))
+                                    {
+                                        Ok(imported) => preset::merge(presets, imported),
+                                        Err(err) => {
+                                            self.information =
+                                                format!("Failed to import presets: {}", err)
+                                        }
+                                    }
+                                }
+                            }
+                            if ui.button("Export").clicked() {
+                                if let Some(path) = rfd::FileDialog::new()
+                                    .add_filter("Presets", &["json"])
+                                    .set_file_name("presets.json")
+                                    .save_file()
+                                {
+                                    let result = preset::presets_to_json(presets)
+                                        .and_then(|json| std::fs::write(&path, json));
+                                    if let Err(err) = result {
+                                        self.information =
+                                            format!("Failed to export presets: {}", err);
+                                    }
+                                }
+                            }
+                        });
+                    });
+
                     ui.collapsing("Advanced", |ui| {
                         ui.horizontal(|ui| {
                             ui.label("Runs: ");
@@ -294,46 +1720,283 @@ impl eframe::App for PixeliteApp {
                         });
                     });
 
-                    if ui.button("Generate").clicked() {
-                        if self.img_dyn.is_some() {
-                            let (rgb_palette, lab_palette) = util::calculate_kmeans(
-                                self.img_dyn.as_ref().unwrap().clone(),
-                                *kmeans_params,
-                            )
-                            .unwrap();
-                            self.color_palette = Some(rgb_palette);
-
-                            let output_size = util::calc_target_size(
-                                self.img_dyn.as_ref().unwrap().clone(),
-                                *pixel_size,
+                    ui.collapsing("Export", |ui| {
+                        let animated = self.output_frames.is_some();
+                        let options = &mut self.export_options;
+                        egui::ComboBox::from_label("Format")
+                            .selected_text(options.format.name())
+                            .show_ui(ui, |ui| {
+                                for format in OutputFormat::ALL {
+                                    ui.selectable_value(&mut options.format, format, format.name());
+                                }
+                            });
+                        if animated && !options.format.can_animate() {
+                            ui.colored_label(
+                                ui.visuals().warn_fg_color,
+                                "Can't hold the animation, use GIF, PNG or a sprite sheet",
                             );
-                            if output_size.is_none() {
-                                self.information = "Pixel size is too large".to_string();
-                            } else {
-                                //let sharpened_img =
-                                //    util::sharpen_filter(self.img_dyn.as_ref().unwrap().clone());
-
-                                let output_img = util::generate_image(
-                                    self.img_dyn.as_ref().unwrap().clone(),
-                                    //sharpened_img,
-                                    *pixel_size,
-                                    output_size.unwrap(),
-                                    lab_palette,
+                        }
+
+                        match options.format {
+                            OutputFormat::Aseprite => {
+                                ui.checkbox(
+                                    &mut options.aseprite_reference,
+                                    "Add the original as a reference layer",
+                                );
+                            }
+                            OutputFormat::Ico => {
+                                ui.horizontal(|ui| {
+                                    ui.label("Sizes: ");
+                                    for size in export::ICO_SIZES {
+                                        let mut checked = options.ico_sizes.contains(&size);
+                                        if ui.checkbox(&mut checked, size.to_string()).changed() {
+                                            options.ico_sizes.retain(|s| *s != size);
+                                            if checked {
+                                                options.ico_sizes.push(size);
+                                                options.ico_sizes.sort_unstable();
+                                            }
+                                        }
+                                    }
+                                });
+                            }
+                            format => {
+                                ui.horizontal(|ui| {
+                                    ui.label("Scale: ");
+                                    ui.add(
+                                        egui::DragValue::new(&mut options.scale)
+                                            .speed(1.0)
+                                            .clamp_range(1..=32)
+                                            .suffix("x"),
+                                    );
+                                });
+                                if format == OutputFormat::Png
+                                    || format == OutputFormat::Bmp
+                                    || format == OutputFormat::SpriteSheet
+                                    || format == OutputFormat::Tileset
+                                {
+                                    ui.checkbox(&mut options.indexed, "Indexed colors");
+                                }
+                                if format == OutputFormat::SpriteSheet {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Columns: ");
+                                        ui.add(
+                                            egui::DragValue::new(&mut options.sheet_columns)
+                                                .speed(1.0)
+                                                .clamp_range(0..=256)
+                                                .custom_formatter(|n, _| {
+                                                    if n == 0.0 {
+                                                        "auto".to_string()
+                                                    } else {
+                                                        n.to_string()
+                                                    }
+                                                }),
+                                        );
+                                        ui.label("Padding: ");
+                                        ui.add(
+                                            egui::DragValue::new(&mut options.sheet_padding)
+                                                .speed(1.0)
+                                                .clamp_range(0..=64),
+                                        );
+                                    });
+                                    ui.checkbox(
+                                        &mut options.hash_atlas,
+                                        "Atlas keyed by frame name (JSON Hash)",
+                                    );
+                                }
+                                if format == OutputFormat::Tileset {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Tile size: ");
+                                        ui.add(
+                                            egui::DragValue::new(&mut options.tile_size)
+                                                .speed(1.0)
+                                                .clamp_range(1..=256)
+                                                .suffix("px"),
+                                        );
+                                    });
+                                    ui.checkbox(
+                                        &mut options.tile_flips,
+                                        "Reuse flipped and rotated tiles",
+                                    );
+                                    egui::ComboBox::from_label("Tilemap")
+                                        .selected_text(options.tilemap_format.name())
+                                        .show_ui(ui, |ui| {
+                                            for map_format in TilemapFormat::ALL {
+                                                ui.selectable_value(
+                                                    &mut options.tilemap_format,
+                                                    map_format,
+                                                    map_format.name(),
+                                                );
+                                            }
+                                        });
+                                }
+                            }
+                        }
+                    });
+
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.collapsing("Sequence", |ui| {
+                        let mut chosen = None;
+                        ui.horizontal(|ui| {
+                            if ui.button("Choose folder").clicked() {
+                                chosen = rfd::FileDialog::new().pick_folder();
+                            }
+                            if ui.button("Choose a frame").clicked() {
+                                chosen = choose_file(frame);
+                            }
+                        });
+                        if let Some(path) = chosen {
+                            self.sequence_frames = sequence::list_sequence(&path);
+                            // Preview the first frame in the input window.
+                            if let Some(first) = self.sequence_frames.first() {
+                                let decoded = std::fs::read(first)
+                                    .ok()
+                                    .and_then(|bytes| decode_input(&bytes, first.to_str()));
+                                if let Some((img, retained, _)) = decoded {
+                                    self.open_file_path = Some(first.clone());
+                                    self.img_dyn = Some(img);
+                                    self.input_revision += 1;
+                                    self.transform = Transform::default();
+                                    self.image = Some(retained);
+                                    self.frames = None;
+                                }
+                            }
+                        }
+                        ui.label(format!("{} frames", self.sequence_frames.len()));
+
+                        let process = ui.add_enabled(
+                            !self.sequence_frames.is_empty(),
+                            egui::Button::new("Process sequence"),
+                        );
+                        if process.clicked() {
+                            if let Some(out_dir) = rfd::FileDialog::new().pick_folder() {
+                                // Checked before the palette, which takes a while.
+                                let result = sequence::check_out_dir(
+                                    &self.sequence_frames,
+                                    &out_dir,
+                                )
+                                .and_then(|()| {
+                                    sequence::sequence_palette(
+                                        &self.sequence_frames,
+                                        *kmeans_params,
+                                        pinned_colors,
+                                    )
+                                })
+                                .and_then(
+                                    |(rgb_palette, lab_palette, coverage)| {
+                                        let sorted: Vec<palette::Lab> = util::sort_palette(
+                                            &lab_palette,
+                                            &coverage,
+                                            self.palette_sort,
+                                        )
+                                        .into_iter()
+                                        .map(|i| lab_palette[i])
+                                        .collect();
+                                        let count = sequence::pixelize_sequence(
+                                            &self.sequence_frames,
+                                            &out_dir,
+                                            *pixel_size,
+                                            &sorted,
+                                            *dither,
+                                            *cleanup_params,
+                                            &self.export_options,
+                                        )?;
+                                        self.color_palette = Some(rgb_palette);
+                                        self.lab_palette = Some(lab_palette);
+                                        self.palette_coverage = Some(coverage);
+                                        Ok(count)
+                                    },
                                 );
-                                self.output_img_dyn = Some(output_img.clone());
-                                self.raw_output = Some(output_img.to_rgb8().to_vec());
+                                self.information = match result {
+                                    Ok(count) => format!("Wrote {} frames.", count),
+                                    Err(err) => format!("Sequence failed: {}", err),
+                                };
+                            }
+                        }
+                    });
+
+                    ui.collapsing("Cleanup", |ui| {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut cleanup_params.remove_orphans, "Remove orphans");
+                            ui.add_enabled(
+                                cleanup_params.remove_orphans,
+                                egui::Slider::new(&mut cleanup_params.orphan_strength, 1..=4),
+                            );
+                        });
+
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut cleanup_params.smooth_jaggies, "Smooth jaggies");
+                            ui.add_enabled(
+                                cleanup_params.smooth_jaggies,
+                                egui::Slider::new(&mut cleanup_params.jaggy_strength, 1..=3),
+                            );
+                        });
+
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut cleanup_params.merge_islands, "Merge islands");
+                            ui.add_enabled(
+                                cleanup_params.merge_islands,
+                                egui::Slider::new(&mut cleanup_params.island_min_size, 2..=16),
+                            );
+                        });
+                    });
 
-                                self.output_image = Some(RetainedImage::from_color_image(
-                                    "output",
-                                    dynamic_image_to_color_image(output_img),
-                                ));
+                    ui.collapsing("Hardware constraints", |ui| {
+                        ui.checkbox(
+                            &mut hardware_params.enabled,
+                            "Limit the colors of every attribute cell",
+                        );
+                        ui.horizontal(|ui| {
+                            ui.label("Presets: ");
+                            for (name, preset) in HardwareParams::PRESETS {
+                                if ui.button(name).clicked() {
+                                    *hardware_params = preset;
+                                }
                             }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("Cell: ");
+                            ui.add(
+                                egui::DragValue::new(&mut hardware_params.cell_width)
+                                    .speed(1.0)
+                                    .clamp_range(1..=32),
+                            );
+                            ui.label("x");
+                            ui.add(
+                                egui::DragValue::new(&mut hardware_params.cell_height)
+                                    .speed(1.0)
+                                    .clamp_range(1..=32),
+                            );
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("Sub-palettes: ");
+                            ui.add(
+                                egui::DragValue::new(&mut hardware_params.sub_palettes)
+                                    .speed(1.0)
+                                    .clamp_range(1..=16),
+                            );
+                            ui.label("Colors each: ");
+                            ui.add(
+                                egui::DragValue::new(&mut hardware_params.colors_per_palette)
+                                    .speed(1.0)
+                                    .clamp_range(1..=16),
+                            );
+                        });
+                    });
 
-                            self.color_palette_window = true;
-                            self.output_window = true;
-                        } else {
-                            self.information = "Please load a picture first!".to_string();
+                    ui.horizontal(|ui| {
+                        if ui.button("Generate").clicked() {
+                            generate = true;
                         }
+                        ui.checkbox(auto_preview, "Auto preview");
+                    });
+                    if ui
+                        .button("Depixelize to native")
+                        .on_hover_text(
+                            "Find the grid of upscaled pixel art and recover its pixels and palette",
+                        )
+                        .clicked()
+                    {
+                        depixelize = true;
                     }
 
                     if self.is_loading {
@@ -354,25 +2017,209 @@ impl eframe::App for PixeliteApp {
 
             if self.input_window {
                 egui::Window::new("Input Image")
-                    .vscroll(true)
-                    .hscroll(true)
                     .resizable(true)
+                    .default_size(egui::vec2(480.0, 400.0))
                     .show(ctx, |ui| {
-                        if let Some(image) = &self.image {
-                            #[cfg(not(target_arch = "wasm32"))]
-                            let window_size = frame.info().window_info.size;
-
-                            #[cfg(target_arch = "wasm32")]
-                            let window_size = egui::vec2(1080.0, 720.0);
-                            let image_size = image.size_vec2();
-                            if image_size.x / window_size.x > image_size.y / window_size.y {
-                                let scale = window_size.x / (2.0 * image_size.x);
-                                image.show_scaled(ui, scale);
-                            } else {
-                                let scale = window_size.y / (2.0 * image_size.y);
-                                image.show_scaled(ui, scale);
+                        let shown = match &self.oriented_image {
+                            Some((_, _, Some(oriented))) => Some(oriented),
+                            _ => self.image.as_ref(),
+                        };
+                        if let Some(image) = shown {
+                            let transform = &mut self.transform;
+                            let orientation = transform.orientation();
+                            ui.horizontal_wrapped(|ui| {
+                                if ui.button("Rotate left").clicked() {
+                                    transform.quarter_turns = (transform.quarter_turns + 3) % 4;
+                                }
+                                if ui.button("Rotate right").clicked() {
+                                    transform.quarter_turns = (transform.quarter_turns + 1) % 4;
+                                }
+                                ui.toggle_value(&mut transform.flip_horizontal, "Flip horizontal");
+                                ui.toggle_value(&mut transform.flip_vertical, "Flip vertical");
+                                if ui.button("Reset").clicked() {
+                                    *transform = Transform::default();
+                                }
+                                ui.checkbox(&mut self.input_grid, "Grid");
+                            });
+                            ui.add(
+                                egui::Slider::new(
+                                    &mut transform.straighten,
+                                    -transform::MAX_STRAIGHTEN..=transform::MAX_STRAIGHTEN,
+                                )
+                                .text("Straighten")
+                                .suffix("°"),
+                            );
+                            // The crop was drawn on the picture as it was turned.
+                            if transform.orientation() != orientation {
+                                transform.crop = None;
+                            }
+
+                            let settings = &mut self.crop_settings;
+                            ui.horizontal(|ui| {
+                                egui::ComboBox::from_label("Crop")
+                                    .selected_text(settings.mode.name())
+                                    .show_ui(ui, |ui| {
+                                        for mode in CropMode::ALL {
+                                            ui.selectable_value(
+                                                &mut settings.mode,
+                                                mode,
+                                                mode.name(),
+                                            );
+                                        }
+                                    });
+                                match settings.mode {
+                                    CropMode::Free => {}
+                                    CropMode::Aspect => {
+                                        ui.add(
+                                            egui::DragValue::new(&mut settings.aspect_width)
+                                                .clamp_range(1..=64),
+                                        );
+                                        ui.label(":");
+                                        ui.add(
+                                            egui::DragValue::new(&mut settings.aspect_height)
+                                                .clamp_range(1..=64),
+                                        );
+                                    }
+                                    CropMode::Grid => {
+                                        ui.add(
+                                            egui::DragValue::new(&mut settings.grid_columns)
+                                                .clamp_range(1..=512),
+                                        );
+                                        ui.label("x");
+                                        ui.add(
+                                            egui::DragValue::new(&mut settings.grid_rows)
+                                                .clamp_range(1..=512),
+                                        );
+                                    }
+                                }
+                                if ui
+                                    .add_enabled(
+                                        transform.crop.is_some(),
+                                        egui::Button::new("Clear"),
+                                    )
+                                    .clicked()
+                                {
+                                    transform.crop = None;
+                                }
+                            });
+
+                            ui.horizontal(|ui| {
+                                ui.toggle_value(&mut self.paint_importance, "Paint importance");
+                                if self.paint_importance {
+                                    ui.add(
+                                        egui::DragValue::new(&mut self.brush_radius)
+                                            .clamp_range(1.0..=512.0)
+                                            .prefix("Brush "),
+                                    );
+                                    ui.toggle_value(&mut self.brush_erase, "Erase");
+                                    if ui.button("Clear mask").clicked() {
+                                        if let Some(mask) = &mut self.importance_mask {
+                                            mask.clear();
+                                        }
+                                    }
+                                }
+                            });
+                            // The mask covers the picture as shown.
+                            let shown_source = self
+                                .oriented_image
+                                .as_ref()
+                                .map(|(revision, orientation, _)| (*revision, *orientation));
+                            if let (true, Some(source)) = (self.paint_importance, shown_source) {
+                                let fresh = matches!(
+                                    &self.importance_mask,
+                                    Some(mask) if mask.source == source
+                                );
+                                if !fresh {
+                                    let revision = self
+                                        .importance_mask
+                                        .as_ref()
+                                        .map_or(0, |mask| mask.revision + 1);
+                                    let extent = [image.size()[0] as u32, image.size()[1] as u32];
+                                    self.importance_mask =
+                                        Some(ImportanceMask::new(revision, source, extent));
+                                }
                             }
 
+                            let shape = match settings.mode {
+                                CropMode::Free => canvas::CropShape::Free,
+                                CropMode::Aspect => canvas::CropShape::Aspect(
+                                    settings.aspect_width as f32 / settings.aspect_height as f32,
+                                ),
+                                CropMode::Grid => canvas::CropShape::Fixed(
+                                    egui::vec2(
+                                        settings.grid_columns as f32,
+                                        settings.grid_rows as f32,
+                                    ) * *pixel_size as f32,
+                                ),
+                            };
+
+                            // The grid starts at the crop, moved by the offset.
+                            let grid = self.input_grid.then(|| {
+                                let size = (*pixel_size).max(1) as u32;
+                                let origin = transform.crop.map_or(egui::Vec2::ZERO, |c| {
+                                    egui::vec2(c.x as f32, c.y as f32)
+                                });
+                                let offset = egui::vec2(
+                                    (grid_offset[0] % size) as f32,
+                                    (grid_offset[1] % size) as f32,
+                                );
+                                (*pixel_size as f32, origin + offset)
+                            });
+                            let info_height = ui.text_style_height(&egui::TextStyle::Body)
+                                + ui.spacing().item_spacing.y;
+                            let size = ui.available_size() - egui::vec2(0.0, info_height);
+                            let layer = canvas::Layer {
+                                texture: image.texture_id(ctx),
+                                offset: egui::Vec2::ZERO,
+                                extent: image.size_vec2(),
+                            };
+                            match &self.mask_image {
+                                Some((_, mask_image)) if self.paint_importance => {
+                                    let stroke = canvas::mask_canvas(
+                                        ui,
+                                        size.max(egui::Vec2::ZERO),
+                                        layer,
+                                        mask_image.texture_id(ctx),
+                                        &mut self.input_view,
+                                        self.brush_radius,
+                                    );
+                                    if let (Some((from, to)), Some(mask)) =
+                                        (stroke, &mut self.importance_mask)
+                                    {
+                                        let value = if self.brush_erase { 0 } else { 255 };
+                                        mask.paint_line(
+                                            (from.x, from.y),
+                                            (to.x, to.y),
+                                            self.brush_radius,
+                                            value,
+                                        );
+                                    }
+                                }
+                                _ => {
+                                    canvas::crop_canvas(
+                                        ui,
+                                        size.max(egui::Vec2::ZERO),
+                                        layer,
+                                        &mut self.input_view,
+                                        &mut transform.crop,
+                                        shape,
+                                        grid,
+                                    );
+                                }
+                            }
+                            let (width, height) = match transform.crop {
+                                Some(crop) => (crop.width, crop.height),
+                                None => (image.size()[0] as u32, image.size()[1] as u32),
+                            };
+                            let blocks = *pixel_size as u32;
+                            ui.label(format!(
+                                "{} x {} px, {} x {} output pixels",
+                                width,
+                                height,
+                                width / blocks,
+                                height / blocks
+                            ));
+
                             self.is_loading = false;
                         } else {
                             ui.label("No image loaded, drag in a image to start.");
@@ -383,24 +2230,104 @@ impl eframe::App for PixeliteApp {
 
             if self.output_window {
                 egui::Window::new("Output Image")
-                    .vscroll(true)
-                    .hscroll(true)
                     .resizable(true)
+                    .default_size(egui::vec2(360.0, 360.0))
                     .show(ctx, |ui| {
-                        if let Some(image) = &self.output_image {
-                            #[cfg(not(target_arch = "wasm32"))]
-                            let window_size = frame.info().window_info.size;
-
-                            #[cfg(target_arch = "wasm32")]
-                            let window_size = egui::vec2(1080.0, 720.0);
-                            let image_size = image.size_vec2();
-                            if image_size.x / window_size.x > image_size.y / window_size.y {
-                                let scale = window_size.x / (4.0 * image_size.x);
-                                image.show_scaled(ui, scale);
+                        let shown = match (&self.output_frame_images, &self.output_frames) {
+                            (Some(images), Some(frames)) => {
+                                // Play animations back with their own timing.
+                                ctx.request_repaint();
+                                let time_ms = (ui.input().time * 1000.0) as u64;
+                                let frame = animation::frame_at(frames, time_ms);
+                                images.get(frame).zip(frames.get(frame).map(|f| &f.image))
+                            }
+                            _ => self.output_image.as_ref().zip(self.output_img_dyn.as_ref()),
+                        };
+                        if let Some((image, shown_img)) = shown {
+                            // Only still outputs can be edited.
+                            let editable = self.output_frames.is_none();
+                            ui.horizontal_wrapped(|ui| {
+                                for tool in Tool::ALL {
+                                    let enabled = editable || !tool.edits();
+                                    ui.add_enabled_ui(enabled, |ui| {
+                                        ui.selectable_value(&mut self.tool, tool, tool.name());
+                                    });
+                                }
+                            });
+                            if let Some(colors) = &self.color_palette {
+                                ui.horizontal_wrapped(|ui| {
+                                    for (i, c) in colors.iter().enumerate() {
+                                        let selected = i == self.tool_color;
+                                        let swatch =
+                                            egui::Button::new("").fill(*c).stroke(if selected {
+                                                egui::Stroke::new(
+                                                    2.0,
+                                                    ui.visuals().strong_text_color(),
+                                                )
+                                            } else {
+                                                egui::Stroke::none()
+                                            });
+                                        if ui.add_sized([18.0, 18.0], swatch).clicked() {
+                                            self.tool_color = i;
+                                        }
+                                    }
+                                });
+                            }
+                            ui.horizontal(|ui| {
+                                ui.checkbox(&mut self.output_grid, "Grid");
+                                if let Some(view) = &self.output_view {
+                                    ui.label(format!("{}%", (view.zoom * 100.0).round()));
+                                }
+                                if ui.button("Fit").clicked() {
+                                    self.output_view = None;
+                                }
+                            });
+
+                            let readout_height = ui.text_style_height(&egui::TextStyle::Body)
+                                + ui.spacing().item_spacing.y;
+                            let size = ui.available_size() - egui::vec2(0.0, readout_height);
+                            let canvas = canvas::pixel_canvas(
+                                ui,
+                                size.max(egui::Vec2::ZERO),
+                                image.texture_id(ctx),
+                                image.size_vec2(),
+                                &mut self.output_view,
+                                self.output_grid,
+                                self.tool == Tool::Pan || !editable,
+                            );
+                            if self.tool == Tool::Pan || !editable {
+                                if canvas.response.dragged() {
+                                    ui.output().cursor_icon = egui::CursorIcon::Grabbing;
+                                }
                             } else {
-                                let scale = window_size.y / (4.0 * image_size.y);
-                                image.show_scaled(ui, scale);
+                                let input = ui.input();
+                                if let Some((x, y)) = canvas.pointer_pixel {
+                                    if canvas.response.hovered()
+                                        && input.pointer.any_pressed()
+                                        && input.pointer.primary_down()
+                                    {
+                                        tool_events.push(ToolEvent::Press(x, y));
+                                    } else if input.pointer.primary_down() {
+                                        tool_events.push(ToolEvent::Drag(x, y));
+                                    }
+                                }
+                                if input.pointer.primary_released() {
+                                    tool_events.push(ToolEvent::Release);
+                                }
+                                // The input lock has to go before touching the output.
+                                drop(input);
+                                if canvas.response.hovered() {
+                                    ui.output().cursor_icon = egui::CursorIcon::Crosshair;
+                                }
                             }
+
+                            let readout = canvas.hovered_pixel.map(|(x, y)| {
+                                let pixel = Some(shown_img)
+                                    .filter(|img| x < img.width() && y < img.height())
+                                    .map(|img| img.get_pixel(x, y).to_rgb().0);
+                                pixel_readout(x, y, pixel, &shown_palette)
+                            });
+                            ui.label(readout.unwrap_or_default());
                         } else {
                             ui.label("No output image yet. Click Generate to generate one.");
                         }
@@ -412,21 +2339,208 @@ impl eframe::App for PixeliteApp {
 
             if self.color_palette_window {
                 egui::Window::new("Color Palette").show(ctx, |ui| {
-                    if self.color_palette.is_none() {
-                        ui.label("No color palette yet. Click Generate to generate one.");
+                    if let (Some(colors), Some(lab), Some(coverage)) = (
+                        &self.color_palette,
+                        &self.lab_palette,
+                        &self.palette_coverage,
+                    ) {
+                        egui::ComboBox::from_label("Sort")
+                            .selected_text(self.palette_sort.name())
+                            .show_ui(ui, |ui| {
+                                for sort in PaletteSort::ALL {
+                                    ui.selectable_value(&mut self.palette_sort, sort, sort.name());
+                                }
+                            });
+                        ui.separator();
+
+                        let rows = if self.palette_sort == PaletteSort::HueRamps {
+                            util::hue_ramps(lab)
+                        } else {
+                            vec![util::sort_palette(lab, coverage, self.palette_sort)]
+                        };
+                        for row in rows {
+                            ui.horizontal(|ui| {
+                                for index in row {
+                                    ui.vertical(|ui| {
+                                        let mut c = colors[index];
+                                        let rgb = [c.r(), c.g(), c.b()];
+                                        let pinned = pinned_colors.contains(&rgb);
+                                        let swatch = ui.color_edit_button_srgba(&mut c);
+                                        if swatch.changed() {
+                                            palette_edit = Some((index, c));
+                                        }
+                                        swatch.context_menu(|ui| {
+                                            let label = if pinned { "Unpin" } else { "Pin" };
+                                            if ui.button(label).clicked() {
+                                                if pinned {
+                                                    pinned_colors.retain(|p| *p != rgb);
+                                                } else {
+                                                    pinned_colors.push(rgb);
+                                                }
+                                                ui.close_menu();
+                                            }
+                                        });
+                                        let share = format!("{:.1}%", coverage[index] * 100.0);
+                                        if pinned {
+                                            ui.label(egui::RichText::new(share).strong())
+                                                .on_hover_text("Pinned");
+                                        } else {
+                                            ui.label(share);
+                                        }
+                                    });
+                                }
+                            });
+                        }
+
+                        if let Some(sub_palettes) = &self.sub_palettes {
+                            ui.separator();
+                            ui.label("Sub-palettes");
+                            for (i, (sub_palette, cells)) in sub_palettes.iter().enumerate() {
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{}:", i));
+                                    for index in sub_palette {
+                                        let mut c = colors[*index];
+                                        ui.color_edit_button_srgba(&mut c);
+                                    }
+                                    ui.label(format!("{} cells", cells));
+                                });
+                            }
+                        }
                     } else {
-                        ui.horizontal(|ui| {
-                            for color in self.color_palette.as_ref().unwrap() {
-                                let mut c = *color;
-                                ui.color_edit_button_srgba(&mut c);
+                        ui.label("No color palette yet. Click Generate to generate one.");
+                    }
+
+                    // Pinned colors are kept by the next Generate.
+                    ui.separator();
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("Pinned:");
+                        let mut unpin = None;
+                        for (i, color) in pinned_colors.iter_mut().enumerate() {
+                            ui.color_edit_button_srgb(color)
+                                .on_hover_text("Right-click to unpin")
+                                .context_menu(|ui| {
+                                    if ui.button("Unpin").clicked() {
+                                        unpin = Some(i);
+                                        ui.close_menu();
+                                    }
+                                });
+                        }
+                        if let Some(i) = unpin {
+                            pinned_colors.remove(i);
+                        }
+                        if ui.button("+").on_hover_text("Pin a color").clicked() {
+                            pinned_colors.push([255, 255, 255]);
+                        }
+                        if ui.button("Black & white").clicked() {
+                            for color in [[0, 0, 0], [255, 255, 255]] {
+                                if !pinned_colors.contains(&color) {
+                                    pinned_colors.push(color);
+                                }
                             }
-                        });
+                        }
+                    });
+                    if !pinned_colors.is_empty() {
+                        ui.label(format!(
+                            "k-means picks {} more",
+                            kmeans_params.k.saturating_sub(pinned_colors.len())
+                        ));
                     }
 
                     egui::warn_if_debug_build(ui);
                 });
             }
 
+            if self.history_window {
+                egui::Window::new("History").vscroll(true).show(ctx, |ui| {
+                    let current = self.history.current();
+                    if current.is_none() {
+                        ui.label("Nothing generated yet.");
+                    }
+                    // Newest first.
+                    for (i, entry) in self.history.entries().iter().enumerate().rev() {
+                        ui.horizontal(|ui| {
+                            if let Some(thumbnail) = &entry.thumbnail {
+                                thumbnail.show(ui);
+                            }
+                            let label = &entry.snapshot.label;
+                            if ui.selectable_label(current == Some(i), label).clicked() {
+                                history_jump = Some(i);
+                            }
+                        });
+                    }
+                });
+            }
+
+            if self.compare_window {
+                egui::Window::new("Compare")
+                    .resizable(true)
+                    .default_size(egui::vec2(480.0, 360.0))
+                    .show(ctx, |ui| {
+                        ui.horizontal(|ui| {
+                            for mode in CompareMode::ALL {
+                                ui.selectable_value(&mut self.compare_mode, mode, mode.name());
+                            }
+                            if self.compare_mode == CompareMode::OnionSkin {
+                                ui.add(
+                                    egui::Slider::new(&mut self.onion_opacity, 0.0..=1.0)
+                                        .text("Opacity"),
+                                );
+                            }
+                            if ui.button("Fit").clicked() {
+                                self.compare_view = None;
+                            }
+                        });
+                        ui.separator();
+
+                        // The output was made from the turned and cropped input.
+                        let shown = match &self.oriented_image {
+                            Some((_, _, Some(oriented))) => Some(oriented),
+                            _ => self.image.as_ref(),
+                        };
+                        match (shown, &self.output_image) {
+                            (Some(source), Some(output)) => {
+                                let source_size = source.size_vec2();
+                                let (crop_offset, cropped) = match self.transform.crop {
+                                    Some(crop) => (
+                                        egui::vec2(crop.x as f32, crop.y as f32),
+                                        egui::vec2(crop.width as f32, crop.height as f32),
+                                    ),
+                                    None => (egui::Vec2::ZERO, source_size),
+                                };
+                                // Blocks start at the grid offset within the crop.
+                                let [grid_x, grid_y] = output_grid_offset;
+                                let grid = egui::vec2(grid_x as f32, grid_y as f32).min(cropped);
+                                let source = canvas::Layer {
+                                    texture: source.texture_id(ctx),
+                                    offset: egui::Vec2::ZERO,
+                                    extent: source_size,
+                                };
+                                let output = canvas::Layer {
+                                    texture: output.texture_id(ctx),
+                                    offset: crop_offset + grid,
+                                    extent: output_extent(
+                                        cropped - grid,
+                                        output.size_vec2(),
+                                        *pixel_size,
+                                    ),
+                                };
+                                canvas::compare(
+                                    ui,
+                                    source,
+                                    output,
+                                    self.compare_mode,
+                                    &mut self.compare_split,
+                                    self.onion_opacity,
+                                    &mut self.compare_view,
+                                );
+                            }
+                            _ => {
+                                ui.label("Generate an output to compare it with the input.");
+                            }
+                        }
+                    });
+            }
+
             // Debug Window
             if DEBUG {
                 egui::Window::new("debug").show(ctx, |ui| {
@@ -439,88 +2553,182 @@ impl eframe::App for PixeliteApp {
             preview_files_being_dropped(ctx);
         });
 
+        if let Some(settings) = apply_preset {
+            self.apply_settings(&settings);
+        }
+        if let Some(name) = save_preset {
+            let preset = Preset {
+                name,
+                settings: self.pipeline_settings(),
+            };
+            preset::merge(&mut self.presets, vec![preset]);
+        }
+        if auto_align {
+            self.auto_align_grid();
+        }
+        if generate {
+            self.generate();
+        }
+        if depixelize {
+            self.depixelize();
+        }
+        for event in tool_events {
+            self.use_tool(event);
+        }
+        if let Some((index, color)) = palette_edit {
+            self.edit_palette_color(index, color);
+        }
+        if undo {
+            self.undo();
+        } else if redo {
+            self.redo();
+        }
+        if let Some(snapshot) = history_jump.and_then(|i| self.history.jump(i).cloned()) {
+            self.restore(snapshot);
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(path) = save_project {
+            let result = self
+                .to_project()
+                .and_then(|project| project.into_bytes())
+                .and_then(|bytes| std::fs::write(&path, bytes));
+            if let Err(err) = result {
+                self.information = format!("Failed to save the project: {}", err);
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(path) = open_project {
+            let result = std::fs::read(&path).and_then(|bytes| Project::from_bytes(&bytes));
+            match result {
+                Ok(project) => {
+                    if self.load_project(project) {
+                        self.open_file_path = Some(path);
+                    } else {
+                        self.information = "The project's picture can't be decoded.".to_string();
+                    }
+                }
+                Err(err) => self.information = format!("Failed to open the project: {}", err),
+            }
+        }
+
         //Drag & Drop related
         if !ctx.input().raw.dropped_files.is_empty() {
             self.dropped_files = ctx.input().raw.dropped_files.clone();
-            if !self.dropped_files.is_empty() {
+            self.dropped_file = self.dropped_files.last().cloned();
+
+            // Formats are recognized by content, the name is only a hint.
+            let dropped = self.dropped_file.as_ref().and_then(|file| {
                 #[cfg(not(target_arch = "wasm32"))]
                 {
-                    let file_copy = self.dropped_files.last().cloned();
-                    let last_file_path = file_copy.unwrap().path;
-                    let last_file_name = last_file_path
-                        .unwrap()
-                        .file_name()
-                        .unwrap()
-                        .to_owned()
-                        .to_str()
-                        .unwrap()
-                        .to_owned();
-
-                    if last_file_name.ends_with("png")
-                        || last_file_name.ends_with("jpg")
-                        || last_file_name.ends_with("jpeg")
-                        || last_file_name.ends_with("webp")
-                    {
-                        self.dropped_file = Some(self.dropped_files.last().cloned().unwrap());
-                    } else {
-                        self.info_window = true;
-                        self.information = "Please drop a picture file.".to_string();
-                        self.dropped_files.clear();
-                        self.dropped_file = None;
-                        self.image = None;
-                    }
+                    let path = file.path.clone()?;
+                    let bytes = std::fs::read(&path).ok()?;
+                    let decoded = decode_input(&bytes, path.to_str())?;
+                    Some((Some(path), bytes, decoded))
                 }
 
                 #[cfg(target_arch = "wasm32")]
                 {
-                    let last_file = self.dropped_files.last().cloned().unwrap();
-                    let last_file_name = last_file.name;
-
-                    if last_file_name.ends_with("png")
-                        || last_file_name.ends_with("jpg")
-                        || last_file_name.ends_with("jpeg")
-                        || last_file_name.ends_with("webp")
-                    {
-                        self.dropped_file = Some(self.dropped_files.last().cloned().unwrap());
-                    } else {
-                        self.info_window = true;
-                        self.information = "Please drop a picture file.".to_string();
-                        self.dropped_files.clear();
-                        self.dropped_file = None;
-                        self.image = None;
-                    }
+                    let bytes = file.bytes.as_ref()?.to_vec();
+                    let decoded = decode_input(&bytes, Some(&file.name))?;
+                    Some((Some(file.name.clone()), bytes, decoded))
                 }
+            });
+
+            if let Some((path, bytes, (img, retained, frames))) = dropped {
+                self.open_file_path = path;
+                self.raw_input = Some(bytes);
+                self.img_dyn = Some(img);
+                self.input_revision += 1;
+                self.transform = Transform::default();
+                self.image = Some(retained);
+                self.frames = frames;
+            } else {
+                self.info_window = true;
+                self.information = "Please drop a picture file.".to_string();
+                self.dropped_files.clear();
+                self.dropped_file = None;
+                self.image = None;
             }
+        }
 
-            if self.dropped_file.is_some() {
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    let file_copy = self.dropped_files.last().cloned();
-                    self.open_file_path = file_copy.unwrap().path;
-                    if let Some(path) = self.open_file_path.as_ref() {
-                        let file_bytes = std::fs::read(path).unwrap();
-                        self.raw_input = Some(file_bytes.clone());
-                        self.img_dyn = Some(image::load_from_memory(&file_bytes).unwrap());
-                        self.image =
-                            Some(RetainedImage::from_image_bytes("process", &file_bytes).unwrap());
-                    }
+        // Render a preview once the settings stop changing.
+        if self.auto_preview && self.img_dyn.is_some() {
+            let key = self.preview_key();
+            let now = ctx.input().time;
+            if self.preview_key.as_ref() != Some(&key) {
+                self.preview_key = Some(key);
+                self.preview_due = Some(now + PREVIEW_DEBOUNCE);
+            }
+            match self.preview_due {
+                Some(due) if now >= due => {
+                    self.preview_due = None;
+                    self.preview();
                 }
-
-                #[cfg(target_arch = "wasm32")]
-                {
-                    self.open_file_path = Some(self.dropped_file.as_ref().unwrap().name.clone());
-                    let file_copy = self.dropped_files.last().cloned();
-                    let raw_bytes = file_copy.unwrap().bytes.unwrap().clone();
-                    self.raw_input = Some(raw_bytes.to_vec().clone());
-                    self.img_dyn = Some(image::load_from_memory(&raw_bytes).unwrap());
-                    self.image =
-                        Some(RetainedImage::from_image_bytes("process", &raw_bytes).unwrap());
+                Some(due) => {
+                    ctx.request_repaint_after(std::time::Duration::from_secs_f64(due - now))
                 }
+                None => {}
             }
         }
     }
 }
 
+/// The palette weights in use. The mask only counts while it was painted
+/// on the input as it is turned now.
+fn palette_weights(
+    params: WeightParams,
+    mask: &Option<ImportanceMask>,
+    source: (u64, Transform),
+) -> Weights<'_> {
+    Weights {
+        params,
+        mask: mask.as_ref().filter(|mask| mask.source == source),
+    }
+}
+
+/// Describes the hovered output pixel: where it is, its color and which
+/// palette entry that is, numbered in the shown order that indexed exports
+/// use too.
+fn pixel_readout(x: u32, y: u32, pixel: Option<[u8; 3]>, palette: &[egui::Color32]) -> String {
+    let mut readout = format!("x {}, y {}", x, y);
+    if let Some([r, g, b]) = pixel {
+        readout += &format!("  #{:02x}{:02x}{:02x}", r, g, b);
+        let index = palette
+            .iter()
+            .position(|c| *c == egui::Color32::from_rgb(r, g, b));
+        if let Some(index) = index {
+            readout += &format!("  palette {}", index);
+        }
+    }
+    readout
+}
+
+/// The area of the source an output covers, in source pixels. Outputs made
+/// with the current pixel size cover the blocks they were averaged from, any
+/// other output is stretched over the whole source.
+fn output_extent(source: egui::Vec2, output: egui::Vec2, pixel_size: usize) -> egui::Vec2 {
+    let pixel_size = pixel_size.max(1) as f32;
+    let blocks = (source / pixel_size).floor();
+    if blocks == output {
+        output * pixel_size
+    } else {
+        source
+    }
+}
+
+/// Decodes a picture of any supported format for the input window.
+/// Animated GIFs and APNGs also return all of their frames.
+fn decode_input(
+    bytes: &[u8],
+    name: Option<&str>,
+) -> Option<(DynamicImage, RetainedImage, Option<Vec<AnimationFrame>>)> {
+    let img = input::load_image(bytes, name)?;
+    let retained =
+        RetainedImage::from_color_image("process", dynamic_image_to_color_image(img.clone()));
+    Some((img, retained, animation::decode_frames(bytes)))
+}
+
 fn preview_files_being_dropped(ctx: &egui::Context) {
     use egui::*;
     use std::fmt::Write as _;
@@ -555,13 +2763,81 @@ fn preview_files_being_dropped(ctx: &egui::Context) {
 #[cfg(not(target_arch = "wasm32"))]
 fn choose_file(_frame: &mut eframe::Frame) -> Option<PathBuf> {
     rfd::FileDialog::new()
-        .add_filter(
-            "image",
-            &["jpg", "jpeg", "png", "bmp", "ico", "tiff", "webp"],
-        )
+        .add_filter("image", input::INPUT_EXTENSIONS)
         .pick_file()
 }
 
+/// Writes the output to `path`. A known file extension picks the format,
+/// otherwise the one selected in the export options is used. Animations
+/// are written whole when the format allows it.
+#[cfg(not(target_arch = "wasm32"))]
+fn save_output(
+    path: &std::path::Path,
+    output: &DynamicImage,
+    output_frames: Option<&[AnimationFrame]>,
+    reference: Option<&DynamicImage>,
+    palette: &[egui::Color32],
+    options: &ExportOptions,
+) -> std::io::Result<()> {
+    let mut options = options.clone();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        let extension = extension.to_ascii_lowercase();
+        if !options.format.extensions().contains(&extension.as_str()) {
+            if let Some(format) = OutputFormat::from_extension(&extension) {
+                options.format = format;
+            }
+        }
+    }
+
+    if options.format == OutputFormat::SpriteSheet {
+        let frames = match output_frames {
+            Some(frames) => frames.to_vec(),
+            None => vec![AnimationFrame {
+                image: output.clone(),
+                delay_ms: 0,
+            }],
+        };
+        let image_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let names: Vec<String> = (0..frames.len()).map(|i| format!("frame_{}", i)).collect();
+        let (sheet, atlas) =
+            export::encode_sprite_sheet(&frames, &names, palette, &options, image_name)?;
+        std::fs::write(path, sheet)?;
+        return std::fs::write(path.with_extension("json"), atlas);
+    }
+    if options.format == OutputFormat::Tileset {
+        if output_frames.is_some() {
+            return Err(export::animation_error(options.format));
+        }
+        let image_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let (tileset, map) = export::encode_tileset(output, palette, &options, image_name)?;
+        std::fs::write(path, tileset)?;
+        return std::fs::write(path.with_extension(options.tilemap_format.extension()), map);
+    }
+
+    let bytes = match output_frames {
+        Some(frames) => export::encode_animation(frames, palette, &options)?,
+        None => export::encode_output(output, reference, palette, &options)?,
+    };
+    std::fs::write(path, bytes)
+}
+
+/// Names the output after the opened file, e.g. `photo_pixel.png`.
+#[cfg(not(target_arch = "wasm32"))]
+fn default_file_name(input: Option<&std::path::Path>, format: OutputFormat) -> String {
+    let stem = input
+        .and_then(|path| path.file_stem())
+        .and_then(|stem| stem.to_str())
+        .map(|stem| format!("{}_pixel", stem))
+        .unwrap_or_else(|| "output".to_string());
+    format!("{}.{}", stem, format.extensions()[0])
+}
+
 /*
 #[cfg(not(target_arch = "wasm32"))]
 fn execute<F: Future<Output = ()> + Send + 'static>(f: F) {
@@ -0,0 +1,274 @@
+//! Minimal writer for the Aseprite file format, see
+//! https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+
+use flate2::{write::ZlibEncoder, Compression};
+use std::io::{self, Write};
+
+use crate::export::IndexedImage;
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_CEL_EXTRA: u16 = 0x2006;
+const CHUNK_PALETTE: u16 = 0x2019;
+
+const LAYER_VISIBLE: u16 = 1;
+const LAYER_EDITABLE: u16 = 2;
+const LAYER_REFERENCE: u16 = 64;
+
+const CEL_COMPRESSED_IMAGE: u16 = 2;
+
+/// Opacity of the reference layer, so the output shows through it.
+const REFERENCE_OPACITY: u8 = 128;
+
+/// Writes `output` as a single frame indexed sprite. If given, `reference`
+/// is added as a reference layer stretched over the whole canvas; it has to
+/// be indexed with the same palette as `output`.
+pub fn write_aseprite<W: Write>(
+    mut w: W,
+    output: &IndexedImage,
+    reference: Option<&IndexedImage>,
+) -> io::Result<()> {
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    chunks.push(palette_chunk(&output.palette));
+    chunks.push(layer_chunk("Pixelite", LAYER_VISIBLE | LAYER_EDITABLE, 255));
+    if reference.is_some() {
+        chunks.push(layer_chunk(
+            "Reference",
+            LAYER_VISIBLE | LAYER_EDITABLE | LAYER_REFERENCE,
+            REFERENCE_OPACITY,
+        ));
+    }
+    chunks.push(cel_chunk(0, output)?);
+    if let Some(reference) = reference {
+        chunks.push(cel_chunk(1, reference)?);
+        chunks.push(cel_extra_chunk(output.width, output.height));
+    }
+
+    let mut frame = Vec::new();
+    let frame_size = 16 + chunks.iter().map(|c| c.len()).sum::<usize>();
+    put_u32(&mut frame, frame_size as u32);
+    put_u16(&mut frame, FRAME_MAGIC);
+    put_u16(&mut frame, chunks.len().min(0xFFFF) as u16);
+    put_u16(&mut frame, 100); // frame duration in ms
+    frame.extend_from_slice(&[0; 2]);
+    put_u32(&mut frame, chunks.len() as u32);
+    for chunk in chunks {
+        frame.extend(chunk);
+    }
+
+    // Without transparent pixels point the transparent index past the
+    // palette, otherwise color 0 would vanish from the layers.
+    let transparent = output
+        .transparent
+        .unwrap_or_else(|| output.palette.len().min(255) as u8);
+
+    let mut header = Vec::with_capacity(128);
+    put_u32(&mut header, (128 + frame.len()) as u32);
+    put_u16(&mut header, HEADER_MAGIC);
+    put_u16(&mut header, 1); // frames
+    put_u16(&mut header, output.width as u16);
+    put_u16(&mut header, output.height as u16);
+    put_u16(&mut header, 8); // bits per pixel, indexed
+    put_u32(&mut header, 1); // layer opacity is valid
+    put_u16(&mut header, 100); // deprecated speed
+    put_u32(&mut header, 0);
+    put_u32(&mut header, 0);
+    header.push(transparent);
+    header.extend_from_slice(&[0; 3]);
+    put_u16(&mut header, (output.palette.len() % 256) as u16);
+    header.push(1); // pixel width
+    header.push(1); // pixel height
+    put_u16(&mut header, 0); // grid x
+    put_u16(&mut header, 0); // grid y
+    put_u16(&mut header, 16); // grid width
+    put_u16(&mut header, 16); // grid height
+    header.resize(128, 0);
+
+    w.write_all(&header)?;
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_u16(buf, value.len() as u16);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn chunk(kind: u16, data: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 6);
+    put_u32(&mut buf, (data.len() + 6) as u32);
+    put_u16(&mut buf, kind);
+    buf.extend(data);
+    buf
+}
+
+fn palette_chunk(palette: &[[u8; 3]]) -> Vec<u8> {
+    let mut data = Vec::new();
+    put_u32(&mut data, palette.len() as u32);
+    put_u32(&mut data, 0);
+    put_u32(&mut data, palette.len().saturating_sub(1) as u32);
+    data.extend_from_slice(&[0; 8]);
+    for color in palette {
+        put_u16(&mut data, 0); // no name
+        data.extend_from_slice(color);
+        data.push(255);
+    }
+    chunk(CHUNK_PALETTE, data)
+}
+
+fn layer_chunk(name: &str, flags: u16, opacity: u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    put_u16(&mut data, flags);
+    put_u16(&mut data, 0); // normal image layer
+    put_u16(&mut data, 0); // child level
+    put_u16(&mut data, 0); // default width, ignored
+    put_u16(&mut data, 0); // default height, ignored
+    put_u16(&mut data, 0); // normal blend mode
+    data.push(opacity);
+    data.extend_from_slice(&[0; 3]);
+    put_string(&mut data, name);
+    chunk(CHUNK_LAYER, data)
+}
+
+fn cel_chunk(layer: u16, image: &IndexedImage) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    put_u16(&mut data, layer);
+    put_u16(&mut data, 0); // x
+    put_u16(&mut data, 0); // y
+    data.push(255); // opacity
+    put_u16(&mut data, CEL_COMPRESSED_IMAGE);
+    put_u16(&mut data, 0); // z-index
+    data.extend_from_slice(&[0; 5]);
+    put_u16(&mut data, image.width as u16);
+    put_u16(&mut data, image.height as u16);
+
+    let mut encoder = ZlibEncoder::new(data, Compression::default());
+    encoder.write_all(&image.indices)?;
+    Ok(chunk(CHUNK_CEL, encoder.finish()?))
+}
+
+/// Precise bounds for the previous cel, used to fit the reference image
+/// onto the canvas whatever its resolution.
+fn cel_extra_chunk(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    put_u32(&mut data, 1); // precise bounds are set
+    put_u32(&mut data, 0); // x, 16.16 fixed point
+    put_u32(&mut data, 0); // y
+    put_u32(&mut data, width << 16);
+    put_u32(&mut data, height << 16);
+    data.extend_from_slice(&[0; 16]);
+    chunk(CHUNK_CEL_EXTRA, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([buf[at], buf[at + 1]])
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+    }
+
+    fn sample(width: u32, height: u32) -> IndexedImage {
+        IndexedImage {
+            width,
+            height,
+            indices: (0..width * height).map(|i| (i % 3) as u8).collect(),
+            palette: vec![[0, 0, 0], [255, 0, 0], [0, 0, 255]],
+            transparent: None,
+        }
+    }
+
+    /// Splits the single frame into `(kind, data)` chunks, checking every
+    /// size field on the way.
+    fn read_chunks(file: &[u8]) -> Vec<(u16, &[u8])> {
+        assert_eq!(u32_at(file, 0) as usize, file.len());
+        assert_eq!(u16_at(file, 4), HEADER_MAGIC);
+        let frame = &file[128..];
+        assert_eq!(u32_at(frame, 0) as usize, frame.len());
+        assert_eq!(u16_at(frame, 4), FRAME_MAGIC);
+        let count = u32_at(frame, 12) as usize;
+        assert_eq!(u16_at(frame, 6) as usize, count);
+
+        let mut chunks = Vec::new();
+        let mut at = 16;
+        for _ in 0..count {
+            let size = u32_at(frame, at) as usize;
+            chunks.push((u16_at(frame, at + 4), &frame[at + 6..at + size]));
+            at += size;
+        }
+        assert_eq!(at, frame.len());
+        chunks
+    }
+
+    #[test]
+    fn header_and_chunks_add_up() {
+        let output = sample(5, 3);
+        let mut file = Vec::new();
+        write_aseprite(&mut file, &output, None).unwrap();
+
+        assert_eq!(u16_at(&file, 6), 1);
+        assert_eq!(u16_at(&file, 8), 5);
+        assert_eq!(u16_at(&file, 10), 3);
+        assert_eq!(u16_at(&file, 12), 8);
+        // No transparent pixels, so the transparent index is past the palette.
+        assert_eq!(file[28], 3);
+        assert_eq!(u16_at(&file, 32), 3);
+
+        let chunks = read_chunks(&file);
+        let kinds: Vec<u16> = chunks.iter().map(|c| c.0).collect();
+        assert_eq!(kinds, [CHUNK_PALETTE, CHUNK_LAYER, CHUNK_CEL]);
+
+        let palette = chunks[0].1;
+        assert_eq!(u32_at(palette, 0), 3);
+        assert_eq!(&palette[28..32], &[255, 0, 0, 255]);
+
+        let cel = chunks[2].1;
+        assert_eq!(u16_at(cel, 7), CEL_COMPRESSED_IMAGE);
+        assert_eq!((u16_at(cel, 16), u16_at(cel, 18)), (5, 3));
+        let mut indices = Vec::new();
+        ZlibDecoder::new(&cel[20..])
+            .read_to_end(&mut indices)
+            .unwrap();
+        assert_eq!(indices, output.indices);
+    }
+
+    #[test]
+    fn reference_adds_a_layer_and_bounds() {
+        let mut file = Vec::new();
+        write_aseprite(&mut file, &sample(4, 4), Some(&sample(16, 16))).unwrap();
+
+        let chunks = read_chunks(&file);
+        let kinds: Vec<u16> = chunks.iter().map(|c| c.0).collect();
+        assert_eq!(
+            kinds,
+            [
+                CHUNK_PALETTE,
+                CHUNK_LAYER,
+                CHUNK_LAYER,
+                CHUNK_CEL,
+                CHUNK_CEL,
+                CHUNK_CEL_EXTRA
+            ]
+        );
+        assert_ne!(u16_at(chunks[2].1, 0) & LAYER_REFERENCE, 0);
+        assert_eq!(u16_at(chunks[4].1, 0), 1);
+        assert_eq!(u32_at(chunks[5].1, 12), 4 << 16);
+    }
+}
@@ -0,0 +1,640 @@
+use egui::{
+    pos2, vec2, Color32, Id, Painter, PointerButton, Pos2, Rect, Response, Sense, Shape, Stroke,
+    TextureId, Ui, Vec2,
+};
+
+use crate::transform::Crop;
+
+const MIN_ZOOM: f32 = 0.05;
+const MAX_ZOOM: f32 = 64.0;
+
+/// How much one point of mouse wheel scrolling zooms.
+const SCROLL_ZOOM_SPEED: f32 = 0.005;
+
+/// Scrolling this many points steps the pixel canvas zoom once, about one
+/// notch of a mouse wheel.
+const SCROLL_STEP: f32 = 50.0;
+
+/// The pixel grid is only drawn once cells are at least this many points wide.
+const MIN_GRID_ZOOM: f32 = 4.0;
+
+/// Grabbing the split line works this many points to either side of it.
+const SPLIT_GRAB_WIDTH: f32 = 6.0;
+
+/// Crop corners can be grabbed this many points away.
+const CROP_HANDLE_SIZE: f32 = 8.0;
+
+/// Zoom and pan of an image view. `zoom` is screen points per image pixel
+/// and `pan` where the image's top left corner sits, relative to the canvas.
+/// Views showing the same `View` stay in sync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct View {
+    pub zoom: f32,
+    pub pan: Vec2,
+}
+
+impl View {
+    /// Shows the whole image, centered.
+    pub fn fit(image_size: Vec2, canvas_size: Vec2) -> View {
+        let zoom = (canvas_size.x / image_size.x)
+            .min(canvas_size.y / image_size.y)
+            .clamp(MIN_ZOOM, MAX_ZOOM);
+        View {
+            zoom,
+            pan: (canvas_size - image_size * zoom) / 2.0,
+        }
+    }
+
+    pub fn image_rect(&self, canvas: Rect, image_size: Vec2) -> Rect {
+        Rect::from_min_size(canvas.min + self.pan, image_size * self.zoom)
+    }
+
+    /// Image coordinates under a screen position.
+    pub fn image_pos(&self, canvas: Rect, pos: Pos2) -> Pos2 {
+        ((pos - canvas.min - self.pan) / self.zoom).to_pos2()
+    }
+
+    /// Zooms keeping the image point under `pos` in place.
+    pub fn zoom_around(&mut self, canvas: Rect, pos: Pos2, zoom: f32) {
+        let anchor = self.image_pos(canvas, pos);
+        self.zoom = zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        self.pan = pos - canvas.min - anchor.to_vec2() * self.zoom;
+    }
+
+    /// Like `fit`, but only zooms to whole factors or their halves so that
+    /// every image pixel covers the same number of screen pixels.
+    pub fn fit_pixels(image_size: Vec2, canvas_size: Vec2) -> View {
+        let fit = View::fit(image_size, canvas_size);
+        let zoom = if fit.zoom >= 1.0 {
+            fit.zoom.floor()
+        } else {
+            0.5f32.powf((1.0 / fit.zoom).log2().ceil())
+        };
+        View {
+            zoom: zoom.clamp(MIN_ZOOM, MAX_ZOOM),
+            pan: ((canvas_size - image_size * zoom) / 2.0).floor(),
+        }
+    }
+
+    /// Image pixel under a screen position, if there is one.
+    pub fn pixel_at(&self, canvas: Rect, pos: Pos2, image_size: Vec2) -> Option<(u32, u32)> {
+        let p = self.image_pos(canvas, pos);
+        if p.x < 0.0 || p.y < 0.0 || p.x >= image_size.x || p.y >= image_size.y {
+            return None;
+        }
+        Some((p.x as u32, p.y as u32))
+    }
+
+    /// Dragging pans, the mouse wheel and pinching zoom around the pointer.
+    pub fn handle_input(&mut self, ui: &Ui, response: &Response, canvas: Rect) {
+        if response.dragged() {
+            self.pan += response.drag_delta();
+        }
+        self.handle_zoom(ui, response, canvas);
+    }
+
+    /// The mouse wheel and pinching zoom around the pointer.
+    pub fn handle_zoom(&mut self, ui: &Ui, response: &Response, canvas: Rect) {
+        if let Some(pos) = response.hover_pos() {
+            let input = ui.input();
+            let factor = input.zoom_delta() * (input.scroll_delta.y * SCROLL_ZOOM_SPEED).exp();
+            if factor != 1.0 {
+                self.zoom_around(canvas, pos, self.zoom * factor);
+            }
+        }
+    }
+}
+
+/// Next zoom level `steps` away: whole factors from 1 up, halves below.
+fn step_zoom(zoom: f32, steps: i32) -> f32 {
+    let mut zoom = zoom;
+    for _ in 0..steps.abs() {
+        zoom = match (steps > 0, zoom >= 1.0) {
+            (true, true) => zoom.floor() + 1.0,
+            (true, false) => zoom * 2.0,
+            (false, true) if zoom > 1.0 => zoom.ceil() - 1.0,
+            (false, _) => zoom / 2.0,
+        };
+    }
+    zoom.clamp(MIN_ZOOM, MAX_ZOOM)
+}
+
+/// What the pointer does on a pixel canvas.
+pub struct CanvasResponse {
+    pub response: Response,
+    /// Image pixel under the pointer.
+    pub hovered_pixel: Option<(u32, u32)>,
+    /// Image coordinates of the pointer, also outside the image, while it is
+    /// over the canvas or held down after pressing there.
+    pub pointer_pixel: Option<(i32, i32)>,
+}
+
+/// Shows an image pixel for pixel: nearest filtering (set on the texture),
+/// zoom in whole steps and an optional grid between the pixels. Dragging
+/// with the middle or right button pans, and with the left one too if
+/// `pan_primary` is set. The mouse wheel zooms around the pointer.
+pub fn pixel_canvas(
+    ui: &mut Ui,
+    size: Vec2,
+    texture: TextureId,
+    image_size: Vec2,
+    view: &mut Option<View>,
+    grid: bool,
+    pan_primary: bool,
+) -> CanvasResponse {
+    let (response, painter) = ui.allocate_painter(size, Sense::click_and_drag());
+    let canvas = response.rect;
+    let painter = painter.with_clip_rect(canvas);
+    let view = view.get_or_insert_with(|| View::fit_pixels(image_size, canvas.size()));
+
+    let panning = (pan_primary && response.dragged_by(PointerButton::Primary))
+        || response.dragged_by(PointerButton::Middle)
+        || response.dragged_by(PointerButton::Secondary);
+    if panning {
+        view.pan += response.drag_delta();
+    }
+    if let Some(pos) = response.hover_pos() {
+        // Wheels send a notch at once, touchpads a trickle of small scrolls.
+        let scroll_id = Id::new("pixel_canvas_scroll").with(response.id);
+        let scroll = ui.input().scroll_delta.y;
+        let scrolled = scroll + ui.data().get_temp(scroll_id).unwrap_or(0.0);
+        let steps = (scrolled / SCROLL_STEP).trunc();
+        ui.data()
+            .insert_temp(scroll_id, scrolled - steps * SCROLL_STEP);
+        if steps != 0.0 {
+            let zoom = step_zoom(view.zoom, steps as i32);
+            view.zoom_around(canvas, pos, zoom);
+        }
+    }
+    // Whole points keep pixel edges sharp.
+    view.pan = view.pan.round();
+
+    let rect = view.image_rect(canvas, image_size);
+    let rect = Rect::from_min_size(painter.round_pos_to_pixels(rect.min), rect.size());
+    paint_image(&painter, texture, rect, Color32::WHITE);
+
+    if grid && view.zoom >= MIN_GRID_ZOOM {
+        let visible = rect.intersect(canvas);
+        let stroke = Stroke::new(1.0, Color32::from_black_alpha(64));
+        let first = view.image_pos(canvas, visible.min).floor();
+        let last = view.image_pos(canvas, visible.max).ceil();
+        for x in first.x as i32..=last.x as i32 {
+            let x = rect.left() + x as f32 * view.zoom;
+            painter.vline(painter.round_to_pixel(x), visible.y_range(), stroke);
+        }
+        for y in first.y as i32..=last.y as i32 {
+            let y = rect.top() + y as f32 * view.zoom;
+            painter.hline(visible.x_range(), painter.round_to_pixel(y), stroke);
+        }
+    }
+
+    let hovered_pixel = response
+        .hover_pos()
+        .and_then(|pos| view.pixel_at(canvas, pos, image_size));
+    if let Some((x, y)) = hovered_pixel {
+        let cell = Rect::from_min_size(
+            rect.min + vec2(x as f32, y as f32) * view.zoom,
+            Vec2::splat(view.zoom),
+        );
+        painter.rect_stroke(cell, 0.0, Stroke::new(1.0, Color32::WHITE));
+    }
+
+    let pointer_pixel = if response.hovered() || response.dragged() {
+        ui.input().pointer.interact_pos().map(|pos| {
+            let p = view.image_pos(canvas, pos).floor();
+            (p.x as i32, p.y as i32)
+        })
+    } else {
+        None
+    };
+
+    CanvasResponse {
+        response,
+        hovered_pixel,
+        pointer_pixel,
+    }
+}
+
+pub fn paint_image(painter: &Painter, texture: TextureId, rect: Rect, tint: Color32) {
+    let uv = Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0));
+    painter.add(Shape::image(texture, rect, uv, tint));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum CompareMode {
+    SideBySide,
+    Split,
+    OnionSkin,
+}
+
+impl CompareMode {
+    pub const ALL: [CompareMode; 3] = [
+        CompareMode::SideBySide,
+        CompareMode::Split,
+        CompareMode::OnionSkin,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompareMode::SideBySide => "Side by side",
+            CompareMode::Split => "Split",
+            CompareMode::OnionSkin => "Onion skin",
+        }
+    }
+}
+
+/// A picture on a canvas, with the area it covers in source pixels.
+#[derive(Clone, Copy)]
+pub struct Layer {
+    pub texture: TextureId,
+    pub offset: Vec2,
+    pub extent: Vec2,
+}
+
+impl Layer {
+    fn rect(&self, view: &View, canvas: Rect) -> Rect {
+        view.image_rect(canvas, self.extent)
+            .translate(self.offset * view.zoom)
+    }
+}
+
+/// Shows the source and the output on one canvas with a shared view.
+/// `split` is the split line position from 0 to 1 and `opacity` that of the
+/// output over the source in onion skin mode.
+pub fn compare(
+    ui: &mut Ui,
+    source: Layer,
+    output: Layer,
+    mode: CompareMode,
+    split: &mut f32,
+    opacity: f32,
+    view: &mut Option<View>,
+) {
+    let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
+    let canvas = response.rect;
+    let painter = painter.with_clip_rect(canvas);
+
+    // Side by side, each half is a canvas of its own.
+    let halves = {
+        let mut left = canvas;
+        left.max.x = canvas.center().x;
+        let mut right = canvas;
+        right.min.x = canvas.center().x;
+        [left, right]
+    };
+    let view_canvas = match mode {
+        CompareMode::SideBySide => halves[0],
+        _ => canvas,
+    };
+    let view = view.get_or_insert_with(|| View::fit(source.extent, view_canvas.size()));
+
+    let split_x = canvas.left() + *split * canvas.width();
+    let dragging_split_id = Id::new("compare_dragging_split").with(response.id);
+    if response.drag_started() {
+        let grabbed = mode == CompareMode::Split
+            && response
+                .interact_pointer_pos()
+                .map_or(false, |pos| (pos.x - split_x).abs() < SPLIT_GRAB_WIDTH);
+        ui.data().insert_temp(dragging_split_id, grabbed);
+    }
+    let dragging_split =
+        response.dragged() && ui.data().get_temp(dragging_split_id).unwrap_or(false);
+
+    if dragging_split {
+        if let Some(pos) = response.interact_pointer_pos() {
+            *split = ((pos.x - canvas.left()) / canvas.width()).clamp(0.0, 1.0);
+        }
+    } else {
+        let hovered = match (mode, response.hover_pos()) {
+            (CompareMode::SideBySide, Some(pos)) if halves[1].contains(pos) => halves[1],
+            _ => view_canvas,
+        };
+        // Both halves share the view, so zooming in either works the same.
+        view.handle_input(ui, &response, hovered);
+    }
+
+    match mode {
+        CompareMode::SideBySide => {
+            for (half, layer) in halves.iter().zip([source, output]) {
+                let rect = layer.rect(view, *half);
+                paint_image(
+                    &painter.with_clip_rect(*half),
+                    layer.texture,
+                    rect,
+                    Color32::WHITE,
+                );
+            }
+            let stroke = ui.visuals().widgets.noninteractive.bg_stroke;
+            painter.line_segment([halves[1].left_top(), halves[1].left_bottom()], stroke);
+        }
+        CompareMode::Split => {
+            paint_image(
+                &painter,
+                source.texture,
+                source.rect(view, canvas),
+                Color32::WHITE,
+            );
+            let mut right = canvas;
+            right.min.x = split_x;
+            paint_image(
+                &painter.with_clip_rect(right),
+                output.texture,
+                output.rect(view, canvas),
+                Color32::WHITE,
+            );
+            let stroke = Stroke::new(2.0, ui.visuals().selection.bg_fill);
+            painter.line_segment(
+                [pos2(split_x, canvas.top()), pos2(split_x, canvas.bottom())],
+                stroke,
+            );
+            painter.circle_filled(pos2(split_x, canvas.center().y), 5.0, stroke.color);
+        }
+        CompareMode::OnionSkin => {
+            paint_image(
+                &painter,
+                source.texture,
+                source.rect(view, canvas),
+                Color32::WHITE,
+            );
+            paint_image(
+                &painter,
+                output.texture,
+                output.rect(view, canvas),
+                Color32::from_white_alpha((opacity.clamp(0.0, 1.0) * 255.0) as u8),
+            );
+        }
+    }
+}
+
+/// The shape a crop keeps while it is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CropShape {
+    Free,
+    /// Width over height.
+    Aspect(f32),
+    /// Size in image pixels.
+    Fixed(Vec2),
+}
+
+/// A crop being dragged. Positions are in image coordinates.
+#[derive(Clone, Copy)]
+enum CropDrag {
+    /// Drawing a new crop from this corner.
+    New(Pos2),
+    /// Moving the crop, grabbed this far from its top left corner.
+    Move(Vec2),
+}
+
+/// Shows a picture with a crop over it. Dragging on the picture draws a new
+/// crop, dragging inside the crop moves it and dragging a corner resizes it.
+/// The middle or right button pans and the mouse wheel zooms. `grid` is
+/// the block size and where the block grid starts, drawn over the crop.
+/// Returns whether the crop changed.
+pub fn crop_canvas(
+    ui: &mut Ui,
+    size: Vec2,
+    image: Layer,
+    view: &mut Option<View>,
+    crop: &mut Option<Crop>,
+    shape: CropShape,
+    grid: Option<(f32, Vec2)>,
+) -> bool {
+    let image_size = image.extent;
+    let (response, painter) = ui.allocate_painter(size, Sense::click_and_drag());
+    let canvas = response.rect;
+    let painter = painter.with_clip_rect(canvas);
+    let view = view.get_or_insert_with(|| View::fit(image_size, canvas.size()));
+
+    if response.dragged_by(PointerButton::Middle) || response.dragged_by(PointerButton::Secondary) {
+        view.pan += response.drag_delta();
+    }
+    view.handle_zoom(ui, &response, canvas);
+
+    let bounds = Rect::from_min_size(Pos2::ZERO, image_size);
+    let mut changed = false;
+    // Crops of a fixed size follow changes of that size.
+    if let (CropShape::Fixed(size), Some(c)) = (shape, &crop) {
+        let min = pos2(c.x as f32, c.y as f32);
+        let rect = keep_inside(Rect::from_min_size(min, size), bounds);
+        let resized = to_crop(rect);
+        if resized != *c {
+            *crop = Some(resized);
+            changed = true;
+        }
+    }
+    let current = crop.map(|c| {
+        Rect::from_min_size(
+            pos2(c.x as f32, c.y as f32),
+            vec2(c.width as f32, c.height as f32),
+        )
+    });
+    let pointer = ui.input().pointer.interact_pos();
+
+    let drag_id = Id::new("crop_drag").with(response.id);
+    if response.drag_started() && response.dragged_by(PointerButton::Primary) {
+        if let Some(pos) = pointer {
+            let p = view.image_pos(canvas, pos);
+            let grab = CROP_HANDLE_SIZE / view.zoom;
+            let drag = match current {
+                Some(rect) => {
+                    let corners = [
+                        (rect.left_top(), rect.right_bottom()),
+                        (rect.right_top(), rect.left_bottom()),
+                        (rect.left_bottom(), rect.right_top()),
+                        (rect.right_bottom(), rect.left_top()),
+                    ];
+                    let corner = corners.iter().find(|(corner, _)| corner.distance(p) < grab);
+                    match corner {
+                        // Resizing draws from the opposite corner.
+                        Some((_, opposite)) if !matches!(shape, CropShape::Fixed(_)) => {
+                            CropDrag::New(*opposite)
+                        }
+                        _ if rect.expand(grab).contains(p) => CropDrag::Move(p - rect.min),
+                        _ => CropDrag::New(bounds.clamp(p)),
+                    }
+                }
+                None => CropDrag::New(bounds.clamp(p)),
+            };
+            ui.data().insert_temp(drag_id, drag);
+        }
+    }
+
+    let drag = ui.data().get_temp::<CropDrag>(drag_id);
+    if let (Some(drag), Some(pos), true) =
+        (drag, pointer, response.dragged_by(PointerButton::Primary))
+    {
+        let p = bounds.clamp(view.image_pos(canvas, pos));
+        let rect = match (drag, shape) {
+            (CropDrag::New(_), CropShape::Fixed(size)) => {
+                Some(keep_inside(Rect::from_center_size(p, size), bounds))
+            }
+            (CropDrag::New(anchor), _) => Some(shaped_rect(anchor, p, shape)),
+            (CropDrag::Move(grabbed), _) => current
+                .map(|rect| keep_inside(Rect::from_min_size(p - grabbed, rect.size()), bounds)),
+        };
+        if let Some(rect) = rect {
+            let new = to_crop(rect);
+            if new.width > 0 && new.height > 0 && *crop != Some(new) {
+                *crop = Some(new);
+                changed = true;
+            }
+        }
+    }
+    if response.drag_released() {
+        ui.data().remove::<CropDrag>(drag_id);
+    }
+
+    let image_rect = view.image_rect(canvas, image_size);
+    paint_image(&painter, image.texture, image_rect, Color32::WHITE);
+
+    if let Some((block, origin)) = grid {
+        let area = match crop {
+            Some(c) => Rect::from_min_size(
+                pos2(c.x as f32, c.y as f32),
+                vec2(c.width as f32, c.height as f32),
+            ),
+            None => Rect::from_min_size(Pos2::ZERO, image_size),
+        };
+        if block * view.zoom >= MIN_GRID_ZOOM {
+            let to_screen = |p: Pos2| image_rect.min + p.to_vec2() * view.zoom;
+            let (top, bottom) = (to_screen(area.min).y, to_screen(area.max).y);
+            let (left, right) = (to_screen(area.min).x, to_screen(area.max).x);
+            let stroke = Stroke::new(1.0, Color32::from_rgba_unmultiplied(255, 0, 255, 128));
+            let mut x = origin.x;
+            while x <= area.max.x {
+                painter.vline(to_screen(pos2(x, 0.0)).x, top..=bottom, stroke);
+                x += block;
+            }
+            let mut y = origin.y;
+            while y <= area.max.y {
+                painter.hline(left..=right, to_screen(pos2(0.0, y)).y, stroke);
+                y += block;
+            }
+        }
+    }
+
+    if let Some(c) = crop {
+        let rect = Rect::from_min_size(
+            image_rect.min + vec2(c.x as f32, c.y as f32) * view.zoom,
+            vec2(c.width as f32, c.height as f32) * view.zoom,
+        );
+        // Dim everything that is cut away.
+        let shade = Color32::from_black_alpha(128);
+        let outside = [
+            Rect::from_min_max(image_rect.min, pos2(image_rect.max.x, rect.min.y)),
+            Rect::from_min_max(pos2(image_rect.min.x, rect.max.y), image_rect.max),
+            Rect::from_min_max(
+                pos2(image_rect.min.x, rect.min.y),
+                pos2(rect.min.x, rect.max.y),
+            ),
+            Rect::from_min_max(
+                pos2(rect.max.x, rect.min.y),
+                pos2(image_rect.max.x, rect.max.y),
+            ),
+        ];
+        for part in outside {
+            painter.rect_filled(part, 0.0, shade);
+        }
+        painter.rect_stroke(rect, 0.0, Stroke::new(1.0, Color32::WHITE));
+        for corner in [
+            rect.left_top(),
+            rect.right_top(),
+            rect.left_bottom(),
+            rect.right_bottom(),
+        ] {
+            painter.rect_filled(
+                Rect::from_center_size(corner, Vec2::splat(6.0)),
+                0.0,
+                Color32::WHITE,
+            );
+        }
+    }
+
+    changed
+}
+
+/// Shows a picture with a mask over it for painting, `mask` being a texture
+/// tinted by the mask. Dragging or clicking with the left button paints
+/// with a round brush of `radius` picture pixels, the middle or right
+/// button pans and the mouse wheel zooms. Returns the stroke painted this
+/// frame, from where the brush was to where it is, in picture pixels.
+pub fn mask_canvas(
+    ui: &mut Ui,
+    size: Vec2,
+    image: Layer,
+    mask: TextureId,
+    view: &mut Option<View>,
+    radius: f32,
+) -> Option<(Pos2, Pos2)> {
+    let (response, painter) = ui.allocate_painter(size, Sense::click_and_drag());
+    let canvas = response.rect;
+    let painter = painter.with_clip_rect(canvas);
+    let view = view.get_or_insert_with(|| View::fit(image.extent, canvas.size()));
+
+    if response.dragged_by(PointerButton::Middle) || response.dragged_by(PointerButton::Secondary) {
+        view.pan += response.drag_delta();
+    }
+    view.handle_zoom(ui, &response, canvas);
+
+    let rect = image.rect(view, canvas);
+    paint_image(&painter, image.texture, rect, Color32::WHITE);
+    paint_image(&painter, mask, rect, Color32::WHITE);
+    if let Some(pos) = response.hover_pos() {
+        painter.circle_stroke(pos, radius * view.zoom, Stroke::new(1.0, Color32::WHITE));
+    }
+
+    let last_id = Id::new("mask_stroke").with(response.id);
+    let painting = response.clicked() || response.dragged_by(PointerButton::Primary);
+    let pointer = ui.input().pointer.interact_pos();
+    let stroke = match (painting, pointer) {
+        (true, Some(pos)) => {
+            let to = view.image_pos(canvas, pos);
+            let last = ui.data().get_temp::<Pos2>(last_id);
+            let from = match last {
+                Some(from) if !response.drag_started() => from,
+                _ => to,
+            };
+            ui.data().insert_temp(last_id, to);
+            Some((from, to))
+        }
+        _ => None,
+    };
+    if !painting {
+        ui.data().remove::<Pos2>(last_id);
+    }
+    stroke
+}
+
+fn to_crop(rect: Rect) -> Crop {
+    Crop {
+        x: rect.min.x.round() as u32,
+        y: rect.min.y.round() as u32,
+        width: rect.width().round() as u32,
+        height: rect.height().round() as u32,
+    }
+}
+
+/// The rectangle from `anchor` to `p`, narrowed to the shape's aspect.
+fn shaped_rect(anchor: Pos2, p: Pos2, shape: CropShape) -> Rect {
+    let delta = p - anchor;
+    match shape {
+        CropShape::Aspect(aspect) if aspect > 0.0 => {
+            let (mut width, mut height) = (delta.x.abs(), delta.y.abs());
+            if width > height * aspect {
+                width = height * aspect;
+            } else {
+                height = width / aspect;
+            }
+            let corner = anchor + vec2(width * delta.x.signum(), height * delta.y.signum());
+            Rect::from_two_pos(anchor, corner)
+        }
+        _ => Rect::from_two_pos(anchor, p),
+    }
+}
+
+/// Moves `rect` into `bounds`, shrinking it only if it is larger.
+fn keep_inside(rect: Rect, bounds: Rect) -> Rect {
+    let size = rect.size().min(bounds.size());
+    let min = rect.min.clamp(bounds.min, bounds.max - size);
+    Rect::from_min_size(min, size)
+}
@@ -0,0 +1,290 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct CleanupParams {
+    pub remove_orphans: bool,
+    /// A pixel with fewer than this many same colored neighbours is an orphan.
+    pub orphan_strength: u8,
+    pub smooth_jaggies: bool,
+    /// Number of jaggy smoothing passes.
+    pub jaggy_strength: u8,
+    pub merge_islands: bool,
+    /// Islands with fewer pixels than this get merged into their surroundings.
+    pub island_min_size: usize,
+}
+
+impl CleanupParams {
+    pub fn is_enabled(&self) -> bool {
+        self.remove_orphans || self.smooth_jaggies || self.merge_islands
+    }
+}
+
+pub fn cleanup_image(image: DynamicImage, params: CleanupParams) -> DynamicImage {
+    let mut img = image.into_rgb8();
+
+    if params.merge_islands {
+        img = merge_islands(&img, params.island_min_size);
+    }
+    if params.remove_orphans {
+        img = remove_orphans(&img, params.orphan_strength);
+    }
+    if params.smooth_jaggies {
+        for _ in 0..params.jaggy_strength {
+            img = smooth_jaggies(&img);
+        }
+    }
+    DynamicImage::ImageRgb8(img)
+}
+
+type Offset = (i64, i64);
+
+const NEIGHBOURS: [Offset; 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+fn neighbour(img: &RgbImage, x: u32, y: u32, dx: i64, dy: i64) -> Option<Rgb<u8>> {
+    let nx = x as i64 + dx;
+    let ny = y as i64 + dy;
+    if nx < 0 || ny < 0 || nx >= img.width() as i64 || ny >= img.height() as i64 {
+        return None;
+    }
+    Some(*img.get_pixel(nx as u32, ny as u32))
+}
+
+/// Returns the most frequent color, ties going to the first one seen.
+fn majority(colors: impl Iterator<Item = Rgb<u8>>) -> Option<Rgb<u8>> {
+    let mut counts: Vec<(Rgb<u8>, usize)> = Vec::new();
+    for color in colors {
+        match counts.iter_mut().find(|(c, _)| *c == color) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((color, 1)),
+        }
+    }
+    let mut best: Option<(Rgb<u8>, usize)> = None;
+    for (color, count) in counts {
+        if best.map_or(true, |(_, c)| count > c) {
+            best = Some((color, count));
+        }
+    }
+    best.map(|(color, _)| color)
+}
+
+/// Majority filter: pixels with fewer than `strength` matching neighbours
+/// take the most common color around them.
+pub fn remove_orphans(img: &RgbImage, strength: u8) -> RgbImage {
+    let mut result = img.clone();
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            let pixel = *img.get_pixel(x, y);
+            let around = NEIGHBOURS
+                .iter()
+                .filter_map(|(dx, dy)| neighbour(img, x, y, *dx, *dy));
+            let same = around.clone().filter(|c| *c == pixel).count();
+            if same >= strength as usize {
+                continue;
+            }
+            if let Some(color) = majority(around.filter(|c| *c != pixel)) {
+                result.put_pixel(x, y, color);
+            }
+        }
+    }
+    result
+}
+
+/// Removes the "L" shaped corner pixels where a one pixel wide line steps
+/// diagonally, so diagonals step cleanly by 1:1 or 2:1. Right angles, like
+/// the corners of a box, stay. Pixels on the image edge are left alone, as
+/// part of their surroundings is cut off.
+pub fn smooth_jaggies(img: &RgbImage) -> RgbImage {
+    let mut result = img.clone();
+    for y in 1..img.height().saturating_sub(1) {
+        for x in 1..img.width().saturating_sub(1) {
+            // Corners already removed count, so only one of the two corners
+            // of a step goes.
+            if !is_jaggy(&result, img, x, y) {
+                continue;
+            }
+            let pixel = *result.get_pixel(x, y);
+            let replacement = majority(
+                NEIGHBOURS
+                    .iter()
+                    .filter_map(|(dx, dy)| neighbour(&result, x, y, *dx, *dy))
+                    .filter(|c| *c != pixel),
+            );
+            if let Some(color) = replacement {
+                result.put_pixel(x, y, color);
+            }
+        }
+    }
+    result
+}
+
+/// Whether the pixel is the corner of an "L" in a thin line: two
+/// orthogonal neighbours of its color with a different color between them,
+/// none on the opposite side, and the line running on diagonally past one
+/// of the arms in the `original` image.
+fn is_jaggy(img: &RgbImage, original: &RgbImage, x: u32, y: u32) -> bool {
+    let pixel = *img.get_pixel(x, y);
+    let same = |(dx, dy): Offset| neighbour(img, x, y, dx, dy) == Some(pixel);
+    let continues = |(dx, dy): Offset| neighbour(original, x, y, dx, dy) == Some(pixel);
+    [(-1, -1), (1, -1), (-1, 1), (1, 1)]
+        .into_iter()
+        .any(|(sx, sy)| {
+            let (a, b) = ((sx, 0), (0, sy));
+            let corner = same(a) && same(b) && !same((sx, sy));
+            let thin = !same((-sx, 0)) && !same((0, -sy)) && !same((-sx, -sy));
+            corner && thin && (continues((sx, -sy)) || continues((-sx, sy)))
+        })
+}
+
+/// Recolors 4-connected regions smaller than `min_size` with the most common
+/// color along their border.
+pub fn merge_islands(img: &RgbImage, min_size: usize) -> RgbImage {
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+    let mut result = img.clone();
+    let mut visited = vec![false; width * height];
+
+    for start in 0..width * height {
+        if visited[start] {
+            continue;
+        }
+        let color = *img.get_pixel((start % width) as u32, (start / width) as u32);
+        let mut region = vec![start];
+        let mut border = Vec::new();
+        let mut stack = vec![start];
+        visited[start] = true;
+
+        while let Some(index) = stack.pop() {
+            let x = (index % width) as u32;
+            let y = (index / width) as u32;
+            for (dx, dy) in [(0, -1), (-1, 0), (1, 0), (0, 1)] {
+                if let Some(c) = neighbour(img, x, y, dx, dy) {
+                    let next = (y as i64 + dy) as usize * width + (x as i64 + dx) as usize;
+                    if c != color {
+                        border.push(c);
+                    } else if !visited[next] {
+                        visited[next] = true;
+                        region.push(next);
+                        stack.push(next);
+                    }
+                }
+            }
+        }
+
+        if region.len() >= min_size {
+            continue;
+        }
+        if let Some(replacement) = majority(border.into_iter()) {
+            for index in region {
+                result.put_pixel((index % width) as u32, (index / width) as u32, replacement);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+    /// Rows of `#` for black and anything else for white.
+    fn picture(rows: &[&str]) -> RgbImage {
+        RgbImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            match rows[y as usize].as_bytes()[x as usize] {
+                b'#' => BLACK,
+                _ => WHITE,
+            }
+        })
+    }
+
+    fn changed(before: &RgbImage, after: &RgbImage) -> Vec<(u32, u32)> {
+        before
+            .enumerate_pixels()
+            .filter(|(x, y, p)| after.get_pixel(*x, *y) != *p)
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+
+    #[test]
+    fn staircase_becomes_a_clean_diagonal() {
+        let staircase = picture(&[
+            "......", //
+            ".##...", "..##..", "...##.", "....#.", "......",
+        ]);
+        let diagonal = picture(&[
+            "......", //
+            ".#....", "..#...", "...#..", "....#.", "......",
+        ]);
+        assert_eq!(smooth_jaggies(&staircase), diagonal);
+    }
+
+    #[test]
+    fn staircase_from_the_edge_loses_its_inner_corners() {
+        let staircase = picture(&[
+            "##....", //
+            ".##...", "..##..", "...#..", "......", "......",
+        ]);
+        let smoothed = smooth_jaggies(&staircase);
+        assert_eq!(changed(&staircase, &smoothed), vec![(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn double_steps_become_two_to_one() {
+        let steps = picture(&[
+            ".......", //
+            ".###...", "...###.", ".......",
+        ]);
+        let smoothed = smooth_jaggies(&steps);
+        assert_eq!(changed(&steps, &smoothed), vec![(3, 1)]);
+        // Still one connected line.
+        assert_eq!(*smoothed.get_pixel(4, 2), BLACK);
+    }
+
+    #[test]
+    fn box_outline_and_image_corners_stay() {
+        let outline = picture(&[
+            "......", //
+            ".####.", ".#..#.", ".#..#.", ".####.", "......",
+        ]);
+        assert_eq!(smooth_jaggies(&outline), outline);
+    }
+
+    #[test]
+    fn orphans_take_the_surrounding_color() {
+        let speckled = picture(&[
+            "......", //
+            ".#....", "..#...", "..#...", "......", "......",
+        ]);
+        // Each black pixel has a black neighbour, diagonal ones count too.
+        assert_eq!(remove_orphans(&speckled, 1), speckled);
+        // The ends of the short line have only one.
+        let cleaned = remove_orphans(&speckled, 2);
+        assert_eq!(changed(&speckled, &cleaned), vec![(1, 1), (2, 3)]);
+        let filled = remove_orphans(&picture(&["...", ".#.", "..."]), 1);
+        assert_eq!(filled, picture(&["...", "...", "..."]));
+    }
+
+    #[test]
+    fn small_islands_merge_into_their_border() {
+        let islands = picture(&[
+            "#.....", //
+            "......", "..##..", "..##..", "......", ".....#",
+        ]);
+        let merged = merge_islands(&islands, 2);
+        assert_eq!(changed(&islands, &merged), vec![(0, 0), (5, 5)]);
+        let merged = merge_islands(&islands, 5);
+        assert_eq!(merged, picture(&["......"; 6]));
+        assert_eq!(merge_islands(&islands, 1), islands);
+    }
+}
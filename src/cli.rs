@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: pixelite [OPTIONS] [INPUT [OUTPUT]]
+
+Opens INPUT in the window. Given an OUTPUT too, pixelizes INPUT into it
+without opening a window; the OUTPUT extension picks the format.
+
+Options:
+  --preset NAME    Use the settings saved in the preset NAME, either one
+                   saved in the window or one read with --presets
+  --presets FILE   Read presets from a JSON file exported by pixelite
+  --pin RRGGBB     Keep this color in the palette, k-means picks the rest.
+                   Repeat to pin more colors
+  -h, --help       Print this help";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CliArgs {
+    pub preset: Option<String>,
+    pub presets_file: Option<PathBuf>,
+    /// Colors always in the palette, replacing those of the preset.
+    pub pinned_colors: Vec<[u8; 3]>,
+    pub input: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl CliArgs {
+    /// Parses the arguments, without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArgs, String> {
+        let mut parsed = CliArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--preset" => {
+                    parsed.preset = Some(args.next().ok_or("--preset needs a preset name")?);
+                }
+                "--presets" => {
+                    let path = args.next().ok_or("--presets needs a file")?;
+                    parsed.presets_file = Some(PathBuf::from(path));
+                }
+                "--pin" => {
+                    let color = args.next().ok_or("--pin needs a color")?;
+                    let rgb = parse_color(&color)
+                        .ok_or_else(|| format!("{} is not a RRGGBB color", color))?;
+                    parsed.pinned_colors.push(rgb);
+                }
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+                _ if parsed.input.is_none() => parsed.input = Some(PathBuf::from(arg)),
+                _ if parsed.output.is_none() => parsed.output = Some(PathBuf::from(arg)),
+                _ => return Err(format!("Unexpected argument {}", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// With an output file the work is done without opening a window.
+    pub fn is_headless(&self) -> bool {
+        self.output.is_some()
+    }
+}
+
+/// Reads a hex color such as `ff8800` or `#FF8800`.
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
@@ -0,0 +1,233 @@
+use image::{Rgba, RgbaImage};
+
+/// What dragging on the output canvas does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Tool {
+    Pan,
+    Pencil,
+    Eraser,
+    Fill,
+    Eyedropper,
+    Line,
+    Rectangle,
+    Replace,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 8] = [
+        Tool::Pan,
+        Tool::Pencil,
+        Tool::Eraser,
+        Tool::Fill,
+        Tool::Eyedropper,
+        Tool::Line,
+        Tool::Rectangle,
+        Tool::Replace,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Pan => "Pan",
+            Tool::Pencil => "Pencil",
+            Tool::Eraser => "Eraser",
+            Tool::Fill => "Fill",
+            Tool::Eyedropper => "Eyedropper",
+            Tool::Line => "Line",
+            Tool::Rectangle => "Rectangle",
+            Tool::Replace => "Replace color",
+        }
+    }
+
+    /// Whether the tool changes the picture, as opposed to looking at it.
+    pub fn edits(&self) -> bool {
+        !matches!(self, Tool::Pan | Tool::Eyedropper)
+    }
+}
+
+/// Pointer input on the output canvas, in pixel coordinates. Positions may
+/// lie outside the picture while a stroke is dragged past its edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolEvent {
+    Press(i32, i32),
+    Drag(i32, i32),
+    Release,
+}
+
+/// A stroke in progress. Lines and rectangles are redrawn over `before`
+/// while they are dragged.
+pub struct ToolStroke {
+    pub start: (i32, i32),
+    pub last: (i32, i32),
+    pub before: RgbaImage,
+}
+
+pub const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+pub fn put_pixel(image: &mut RgbaImage, (x, y): (i32, i32), color: Rgba<u8>) {
+    if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+        image.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+pub fn get_pixel(image: &RgbaImage, (x, y): (i32, i32)) -> Option<Rgba<u8>> {
+    if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+        Some(*image.get_pixel(x as u32, y as u32))
+    } else {
+        None
+    }
+}
+
+/// Bresenham line, both ends included.
+pub fn draw_line(image: &mut RgbaImage, from: (i32, i32), to: (i32, i32), color: Rgba<u8>) {
+    let (mut x, mut y) = from;
+    let dx = (to.0 - x).abs();
+    let dy = -(to.1 - y).abs();
+    let sx = if x < to.0 { 1 } else { -1 };
+    let sy = if y < to.1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    loop {
+        put_pixel(image, (x, y), color);
+        if (x, y) == to {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+/// Outline of the rectangle with corners `a` and `b`.
+pub fn draw_rectangle(image: &mut RgbaImage, a: (i32, i32), b: (i32, i32), color: Rgba<u8>) {
+    draw_line(image, (a.0, a.1), (b.0, a.1), color);
+    draw_line(image, (b.0, a.1), (b.0, b.1), color);
+    draw_line(image, (b.0, b.1), (a.0, b.1), color);
+    draw_line(image, (a.0, b.1), (a.0, a.1), color);
+}
+
+/// Fills the 4-connected area of the clicked pixel's color.
+pub fn flood_fill(image: &mut RgbaImage, start: (i32, i32), color: Rgba<u8>) {
+    let target = match get_pixel(image, start) {
+        Some(target) if target != color => target,
+        _ => return,
+    };
+    let mut stack = vec![start];
+    while let Some(pos) = stack.pop() {
+        if get_pixel(image, pos) != Some(target) {
+            continue;
+        }
+        put_pixel(image, pos, color);
+        let (x, y) = pos;
+        stack.extend([(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)]);
+    }
+}
+
+/// Recolors every pixel of the clicked pixel's color.
+pub fn replace_color(image: &mut RgbaImage, start: (i32, i32), color: Rgba<u8>) {
+    let target = match get_pixel(image, start) {
+        Some(target) => target,
+        None => return,
+    };
+    for pixel in image.pixels_mut() {
+        if *pixel == target {
+            *pixel = color;
+        }
+    }
+}
+
+/// Palette entry of every output pixel, row major, for the still output and
+/// each animation frame. Kept next to the output, so a palette edit only
+/// repaints its own entry even when another entry has the same color.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PaletteIndices {
+    pub output: Vec<Option<u16>>,
+    pub frames: Vec<Vec<Option<u16>>>,
+}
+
+/// Looks the palette entry of every pixel up by its color, the first entry
+/// winning between equal colors. Transparent pixels and colors outside the
+/// palette have none.
+pub fn index_pixels(image: &RgbaImage, palette: &[[u8; 3]]) -> Vec<Option<u16>> {
+    let mut lookup = std::collections::HashMap::new();
+    for (i, color) in palette.iter().enumerate().rev() {
+        lookup.insert(*color, i as u16);
+    }
+    image
+        .pixels()
+        .map(|p| match p.0 {
+            [_, _, _, 0] => None,
+            [r, g, b, _] => lookup.get(&[r, g, b]).copied(),
+        })
+        .collect()
+}
+
+/// Paints the pixels of palette entry `index` with `color`.
+pub fn recolor(image: &mut RgbaImage, indices: &[Option<u16>], index: usize, color: [u8; 3]) {
+    for (pixel, i) in image.pixels_mut().zip(indices) {
+        if *i == Some(index as u16) {
+            pixel.0[..3].copy_from_slice(&color);
+        }
+    }
+}
+
+/// Updates `indices` for the pixels an edit changed from `before` to
+/// `after`. Pixels drawn with the color of entry `drawn` take that entry.
+pub fn reindex(
+    indices: &mut [Option<u16>],
+    before: &RgbaImage,
+    after: &RgbaImage,
+    palette: &[[u8; 3]],
+    drawn: usize,
+) {
+    let pixels = before.pixels().zip(after.pixels()).zip(indices.iter_mut());
+    for ((_, new), index) in pixels.filter(|((old, new), _)| old != new) {
+        let color = [new[0], new[1], new[2]];
+        *index = if new[3] == 0 {
+            None
+        } else if palette.get(drawn) == Some(&color) {
+            Some(drawn as u16)
+        } else {
+            palette.iter().position(|c| *c == color).map(|i| i as u16)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_stay_apart_after_sharing_a_color() {
+        let red = [255, 0, 0];
+        let blue = [0, 0, 255];
+        let mut image = RgbaImage::from_fn(2, 1, |x, _| {
+            let [r, g, b] = if x == 0 { red } else { blue };
+            Rgba([r, g, b, 255])
+        });
+        let indices = index_pixels(&image, &[red, blue]);
+        assert_eq!(indices, [Some(0), Some(1)]);
+
+        // Entry 1 turns red too, then entry 0 turns green alone.
+        recolor(&mut image, &indices, 1, red);
+        recolor(&mut image, &indices, 0, [0, 255, 0]);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 255, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn edits_take_the_drawn_entry() {
+        let palette = [[9, 9, 9], [9, 9, 9]];
+        let before = RgbaImage::from_pixel(3, 1, Rgba([9, 9, 9, 255]));
+        let mut indices = vec![Some(0); 3];
+        let mut after = before.clone();
+        put_pixel(&mut after, (1, 0), Rgba([9, 9, 9, 254]));
+        put_pixel(&mut after, (2, 0), TRANSPARENT);
+        reindex(&mut indices, &before, &after, &palette, 1);
+        assert_eq!(indices, [Some(0), Some(1), None]);
+    }
+}
@@ -0,0 +1,876 @@
+use egui::color::Color32;
+use image::{
+    codecs::{
+        bmp::BmpEncoder,
+        ico::{IcoEncoder, IcoFrame},
+        png::PngEncoder,
+        qoi::QoiEncoder,
+        tga::TgaEncoder,
+        webp::WebPEncoder,
+    },
+    imageops::{self, FilterType},
+    ColorType, DynamicImage, ImageEncoder, ImageError, RgbaImage,
+};
+use std::io::{self, Write};
+
+use crate::animation::AnimationFrame;
+use crate::aseprite;
+use crate::spritesheet::{self, SheetLayout, Sprite};
+use crate::tiles::{self, TilemapFormat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum OutputFormat {
+    Png,
+    Gif,
+    Aseprite,
+    Bmp,
+    Tga,
+    WebP,
+    Qoi,
+    Ico,
+    SpriteSheet,
+    Tileset,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 10] = [
+        OutputFormat::Png,
+        OutputFormat::Gif,
+        OutputFormat::Aseprite,
+        OutputFormat::Bmp,
+        OutputFormat::Tga,
+        OutputFormat::WebP,
+        OutputFormat::Qoi,
+        OutputFormat::Ico,
+        OutputFormat::SpriteSheet,
+        OutputFormat::Tileset,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "PNG",
+            OutputFormat::Gif => "GIF",
+            OutputFormat::Aseprite => "Aseprite",
+            OutputFormat::Bmp => "BMP",
+            OutputFormat::Tga => "TGA",
+            OutputFormat::WebP => "WebP (lossless)",
+            OutputFormat::Qoi => "QOI",
+            OutputFormat::Ico => "ICO",
+            OutputFormat::SpriteSheet => "Sprite sheet (PNG + JSON)",
+            OutputFormat::Tileset => "Tileset (PNG + tilemap)",
+        }
+    }
+
+    /// File extensions, the first one being the default.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Png => &["png"],
+            OutputFormat::Gif => &["gif"],
+            OutputFormat::Aseprite => &["aseprite", "ase"],
+            OutputFormat::Bmp => &["bmp"],
+            OutputFormat::Tga => &["tga"],
+            OutputFormat::WebP => &["webp"],
+            OutputFormat::Qoi => &["qoi"],
+            OutputFormat::Ico => &["ico"],
+            OutputFormat::SpriteSheet => &["png"],
+            OutputFormat::Tileset => &["png"],
+        }
+    }
+
+    /// Whether every frame of an animation is written.
+    pub fn can_animate(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Png | OutputFormat::Gif | OutputFormat::SpriteSheet
+        )
+    }
+
+    pub fn from_extension(extension: &str) -> Option<OutputFormat> {
+        let extension = extension.to_ascii_lowercase();
+        OutputFormat::ALL
+            .into_iter()
+            .find(|format| format.extensions().contains(&extension.as_str()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub format: OutputFormat,
+    /// Integer nearest neighbour upscale, ignored by Aseprite and ICO.
+    pub scale: u32,
+    /// Write PNG and BMP files palettized instead of true color.
+    pub indexed: bool,
+    /// Icon sizes written into ICO files.
+    pub ico_sizes: Vec<u32>,
+    /// Add the original image as a reference layer to Aseprite files.
+    pub aseprite_reference: bool,
+    /// Sprite sheet columns, 0 for a roughly square sheet.
+    pub sheet_columns: u32,
+    pub sheet_padding: u32,
+    /// Key the sprite sheet atlas by frame name (TexturePacker "JSON (Hash)")
+    /// instead of listing the frames.
+    pub hash_atlas: bool,
+    /// Edge of the square tiles, in output pixels.
+    pub tile_size: u32,
+    /// Treat flipped and rotated copies of a tile as the same tile.
+    pub tile_flips: bool,
+    pub tilemap_format: TilemapFormat,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Png,
+            scale: 1,
+            indexed: true,
+            ico_sizes: vec![16, 32, 48],
+            aseprite_reference: false,
+            sheet_columns: 0,
+            sheet_padding: 0,
+            hash_atlas: false,
+            tile_size: 8,
+            tile_flips: true,
+            tilemap_format: TilemapFormat::Tmx,
+        }
+    }
+}
+
+pub const ICO_SIZES: [u32; 6] = [16, 24, 32, 48, 64, 256];
+
+/// A palettized image, ready to be written by the indexed encoders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub indices: Vec<u8>,
+    pub palette: Vec<[u8; 3]>,
+    /// Palette entry used for fully transparent pixels, if there are any.
+    pub transparent: Option<u8>,
+}
+
+/// Maps every pixel of `image` onto `palette`, keeping the palette order.
+/// Pixels that are not exactly in the palette take the closest entry.
+pub fn index_image(image: &DynamicImage, palette: &[Color32]) -> Option<IndexedImage> {
+    if palette.is_empty() || palette.len() > 256 {
+        return None;
+    }
+    let rgba = image.to_rgba8();
+    let mut colors: Vec<[u8; 3]> = palette.iter().map(|c| [c.r(), c.g(), c.b()]).collect();
+
+    let transparent = if rgba.pixels().any(|p| p[3] < 128) {
+        if colors.len() == 256 {
+            return None;
+        }
+        colors.push([0, 0, 0]);
+        Some((colors.len() - 1) as u8)
+    } else {
+        None
+    };
+    let opaque = &colors[..palette.len()];
+
+    let indices = rgba
+        .pixels()
+        .map(|p| match transparent {
+            Some(index) if p[3] < 128 => index,
+            _ => closest_index(opaque, [p[0], p[1], p[2]]),
+        })
+        .collect();
+
+    Some(IndexedImage {
+        width: rgba.width(),
+        height: rgba.height(),
+        indices,
+        palette: colors,
+        transparent,
+    })
+}
+
+fn closest_index(palette: &[[u8; 3]], color: [u8; 3]) -> u8 {
+    let mut closest = 0;
+    let mut min_delta = u32::MAX;
+    for (i, c) in palette.iter().enumerate() {
+        let delta = (0..3)
+            .map(|ch| (c[ch] as i32 - color[ch] as i32).pow(2) as u32)
+            .sum();
+        if delta < min_delta {
+            min_delta = delta;
+            closest = i;
+        }
+    }
+    closest as u8
+}
+
+/// Smallest PNG bit depth able to hold `colors` palette entries.
+pub fn min_bit_depth(colors: usize) -> u8 {
+    match colors {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+/// Writes an indexed PNG with a PLTE chunk, the minimum bit depth and a tRNS
+/// chunk when the image has transparent pixels.
+pub fn write_indexed_png<W: Write>(w: W, image: &IndexedImage) -> io::Result<()> {
+    let depth = min_bit_depth(image.palette.len());
+    let mut encoder = png::Encoder::new(w, image.width, image.height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(bit_depth(depth));
+    encoder.set_palette(image.palette.concat());
+    if let Some(index) = image.transparent {
+        // Entries after the last tRNS value are opaque, so stop at ours.
+        let mut trns = vec![255; index as usize + 1];
+        trns[index as usize] = 0;
+        encoder.set_trns(trns);
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pack_rows(image, depth))?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn bit_depth(depth: u8) -> png::BitDepth {
+    match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    }
+}
+
+/// Packs the indices into rows of `depth` bits per pixel, MSB first.
+fn pack_rows(image: &IndexedImage, depth: u8) -> Vec<u8> {
+    if depth == 8 {
+        return image.indices.clone();
+    }
+    let width = image.width as usize;
+    let per_byte = (8 / depth) as usize;
+    let row_bytes = (width + per_byte - 1) / per_byte;
+    let mut data = vec![0u8; row_bytes * image.height as usize];
+    for (y, row) in image.indices.chunks(width).enumerate() {
+        for (x, index) in row.iter().enumerate() {
+            let shift = 8 - depth as usize * (x % per_byte + 1);
+            data[y * row_bytes + x / per_byte] |= index << shift;
+        }
+    }
+    data
+}
+
+/// Writes a single frame GIF using the image palette as the global color table.
+pub fn write_gif<W: Write>(w: W, image: &IndexedImage) -> io::Result<()> {
+    let mut encoder = gif::Encoder::new(
+        w,
+        image.width as u16,
+        image.height as u16,
+        &image.palette.concat(),
+    )
+    .map_err(gif_error)?;
+    let frame = gif::Frame {
+        width: image.width as u16,
+        height: image.height as u16,
+        buffer: std::borrow::Cow::Borrowed(&image.indices),
+        transparent: image.transparent,
+        ..Default::default()
+    };
+    encoder.write_frame(&frame).map_err(gif_error)
+}
+
+/// Encodes the output in `options.format`. `palette` is the display ordered
+/// palette used by indexed formats; if the output can't be palettized those
+/// fall back to true color where the format allows it.
+pub fn encode_output(
+    output: &DynamicImage,
+    reference: Option<&DynamicImage>,
+    palette: &[Color32],
+    options: &ExportOptions,
+) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    if options.format == OutputFormat::Aseprite {
+        let indexed = index_image(output, palette).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Output has no usable palette")
+        })?;
+        let reference = reference.and_then(|img| {
+            if !options.aseprite_reference {
+                return None;
+            }
+            index_image(&DynamicImage::ImageRgb8(img.to_rgb8()), palette)
+        });
+        aseprite::write_aseprite(&mut buf, &indexed, reference.as_ref())?;
+        return Ok(buf);
+    }
+    if options.format == OutputFormat::Ico {
+        write_ico(&mut buf, output, &options.ico_sizes)?;
+        return Ok(buf);
+    }
+
+    let scaled = scale_image(output, options.scale);
+    let indexed = index_image(&scaled, palette);
+    let rgba = scaled.to_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    match (options.format, indexed) {
+        (OutputFormat::Gif, Some(indexed)) => write_gif(&mut buf, &indexed)?,
+        (OutputFormat::Gif, None) => return Err(gif_palette_error()),
+        (OutputFormat::Png | OutputFormat::SpriteSheet | OutputFormat::Tileset, Some(indexed))
+            if options.indexed =>
+        {
+            write_indexed_png(&mut buf, &indexed)?
+        }
+        (OutputFormat::Bmp, Some(indexed)) if options.indexed && indexed.transparent.is_none() => {
+            BmpEncoder::new(&mut buf)
+                .encode_with_palette(
+                    &indexed.indices,
+                    width,
+                    height,
+                    ColorType::L8,
+                    Some(&indexed.palette),
+                )
+                .map_err(image_error)?
+        }
+        (OutputFormat::Bmp, _) => BmpEncoder::new(&mut buf)
+            .encode(&rgba, width, height, ColorType::Rgba8)
+            .map_err(image_error)?,
+        (OutputFormat::Tga, _) => TgaEncoder::new(&mut buf)
+            .write_image(&rgba, width, height, ColorType::Rgba8)
+            .map_err(image_error)?,
+        (OutputFormat::WebP, _) => WebPEncoder::new_lossless(&mut buf)
+            .encode(&rgba, width, height, ColorType::Rgba8)
+            .map_err(image_error)?,
+        (OutputFormat::Qoi, _) => QoiEncoder::new(&mut buf)
+            .write_image(&rgba, width, height, ColorType::Rgba8)
+            .map_err(image_error)?,
+        (OutputFormat::Png | OutputFormat::SpriteSheet | OutputFormat::Tileset, _) => {
+            PngEncoder::new(&mut buf)
+                .write_image(&rgba, width, height, ColorType::Rgba8)
+                .map_err(image_error)?
+        }
+        (OutputFormat::Aseprite | OutputFormat::Ico, _) => unreachable!("written above"),
+    }
+    Ok(buf)
+}
+
+/// Palettizes all frames onto the same palette. If any frame has
+/// transparency they all get the same transparent entry.
+pub fn index_frames(frames: &[DynamicImage], palette: &[Color32]) -> Option<Vec<IndexedImage>> {
+    let mut indexed = frames
+        .iter()
+        .map(|frame| index_image(frame, palette))
+        .collect::<Option<Vec<_>>>()?;
+    if let Some(with_alpha) = indexed.iter().find(|f| f.transparent.is_some()).cloned() {
+        for frame in indexed.iter_mut() {
+            frame.palette = with_alpha.palette.clone();
+            frame.transparent = with_alpha.transparent;
+        }
+    }
+    Some(indexed)
+}
+
+/// Encodes an animation. GIF keeps every frame and its timing, PNG does so
+/// as APNG. Formats that can't animate fail rather than drop frames.
+pub fn encode_animation(
+    frames: &[AnimationFrame],
+    palette: &[Color32],
+    options: &ExportOptions,
+) -> io::Result<Vec<u8>> {
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Animation has no frames",
+        ));
+    }
+    let images: Vec<DynamicImage> = frames
+        .iter()
+        .map(|f| scale_image(&f.image, options.scale))
+        .collect();
+    let delays: Vec<u32> = frames.iter().map(|f| f.delay_ms).collect();
+    let indexed = index_frames(&images, palette);
+
+    let mut buf = Vec::new();
+    match (options.format, indexed) {
+        (OutputFormat::Gif, Some(indexed)) => write_animated_gif(&mut buf, &indexed, &delays)?,
+        (OutputFormat::Gif, None) => return Err(gif_palette_error()),
+        (OutputFormat::Png, Some(indexed)) if options.indexed => {
+            write_apng(&mut buf, &indexed, &delays)?
+        }
+        (OutputFormat::Png, _) => {
+            let rgba: Vec<RgbaImage> = images.iter().map(|i| i.to_rgba8()).collect();
+            write_apng_rgba(&mut buf, &rgba, &delays)?
+        }
+        (format, _) => return Err(animation_error(format)),
+    }
+    Ok(buf)
+}
+
+/// Writes a looping GIF, delays are rounded down to GIF's 10ms steps.
+pub fn write_animated_gif<W: Write>(
+    w: W,
+    frames: &[IndexedImage],
+    delays_ms: &[u32],
+) -> io::Result<()> {
+    let first = &frames[0];
+    let mut encoder = gif::Encoder::new(
+        w,
+        first.width as u16,
+        first.height as u16,
+        &first.palette.concat(),
+    )
+    .map_err(gif_error)?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(gif_error)?;
+    for (image, delay) in frames.iter().zip(delays_ms) {
+        let frame = gif::Frame {
+            width: image.width as u16,
+            height: image.height as u16,
+            delay: (delay / 10).min(u16::MAX as u32) as u16,
+            dispose: gif::DisposalMethod::Background,
+            buffer: std::borrow::Cow::Borrowed(&image.indices),
+            transparent: image.transparent,
+            ..Default::default()
+        };
+        encoder.write_frame(&frame).map_err(gif_error)?;
+    }
+    Ok(())
+}
+
+/// Writes an indexed, looping APNG.
+pub fn write_apng<W: Write>(w: W, frames: &[IndexedImage], delays_ms: &[u32]) -> io::Result<()> {
+    let first = &frames[0];
+    let depth = min_bit_depth(first.palette.len());
+    let mut encoder = png::Encoder::new(w, first.width, first.height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(bit_depth(depth));
+    encoder.set_palette(first.palette.concat());
+    if let Some(index) = first.transparent {
+        let mut trns = vec![255; index as usize + 1];
+        trns[index as usize] = 0;
+        encoder.set_trns(trns);
+    }
+    let data = frames.iter().map(|f| pack_rows(f, depth)).collect();
+    write_apng_frames(encoder, data, delays_ms)
+}
+
+/// Writes a true color, looping APNG.
+pub fn write_apng_rgba<W: Write>(w: W, frames: &[RgbaImage], delays_ms: &[u32]) -> io::Result<()> {
+    let first = &frames[0];
+    let mut encoder = png::Encoder::new(w, first.width(), first.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data = frames.iter().map(|f| f.as_raw().clone()).collect();
+    write_apng_frames(encoder, data, delays_ms)
+}
+
+fn write_apng_frames<W: Write>(
+    mut encoder: png::Encoder<'_, W>,
+    data: Vec<Vec<u8>>,
+    delays_ms: &[u32],
+) -> io::Result<()> {
+    encoder.set_animated(data.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    for (frame, delay) in data.iter().zip(delays_ms) {
+        writer.set_frame_delay((*delay).min(u16::MAX as u32) as u16, 1000)?;
+        writer.write_image_data(frame)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Packs the frames into a sprite sheet PNG and its JSON atlas, which refers
+/// to the sheet as `image_name` and to each frame by its name in `names`.
+pub fn encode_sprite_sheet(
+    frames: &[AnimationFrame],
+    names: &[String],
+    palette: &[Color32],
+    options: &ExportOptions,
+    image_name: &str,
+) -> io::Result<(Vec<u8>, String)> {
+    let images: Vec<DynamicImage> = frames
+        .iter()
+        .map(|f| scale_image(&f.image, options.scale))
+        .collect();
+    let sprites: Vec<Sprite<'_>> = images
+        .iter()
+        .zip(frames)
+        .zip(names)
+        .map(|((image, frame), name)| Sprite {
+            name: name.clone(),
+            image,
+            duration_ms: frame.delay_ms,
+        })
+        .collect();
+    let layout = SheetLayout {
+        columns: options.sheet_columns,
+        padding: options.sheet_padding,
+        hash_atlas: options.hash_atlas,
+    };
+    let (sheet, atlas) = spritesheet::pack(&sprites, image_name, layout);
+
+    let sheet = DynamicImage::ImageRgba8(sheet);
+    let mut buf = Vec::new();
+    match index_image(&sheet, palette) {
+        Some(indexed) if options.indexed => write_indexed_png(&mut buf, &indexed)?,
+        _ => PngEncoder::new(&mut buf)
+            .write_image(
+                sheet.as_bytes(),
+                sheet.width(),
+                sheet.height(),
+                ColorType::Rgba8,
+            )
+            .map_err(image_error)?,
+    }
+    let json = serde_json::to_string_pretty(&atlas)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok((buf, json))
+}
+
+/// Splits the output into deduplicated tiles. Returns the tileset PNG and the
+/// tilemap in `options.tilemap_format`, which refers to the tileset as
+/// `image_name`.
+pub fn encode_tileset(
+    output: &DynamicImage,
+    palette: &[Color32],
+    options: &ExportOptions,
+    image_name: &str,
+) -> io::Result<(Vec<u8>, String)> {
+    let tileset = tiles::build_tileset(output, options.tile_size, options.tile_flips);
+    let image = DynamicImage::ImageRgba8(tileset.image());
+    let png_options = ExportOptions {
+        format: OutputFormat::Png,
+        ..options.clone()
+    };
+    let png = encode_output(&image, None, palette, &png_options)?;
+
+    let name = image_name
+        .rsplit_once('.')
+        .map_or(image_name, |(stem, _)| stem);
+    let scale = options.scale.max(1);
+    let map = match options.tilemap_format {
+        TilemapFormat::Csv => tileset.to_csv(),
+        TilemapFormat::Tmx => tileset.to_tmx(name, image_name, scale),
+        TilemapFormat::Tmj => tileset.to_tmj(name, image_name, scale),
+    };
+    Ok((png, map))
+}
+
+fn scale_image(image: &DynamicImage, scale: u32) -> DynamicImage {
+    if scale <= 1 {
+        return image.clone();
+    }
+    image.resize_exact(
+        image.width() * scale,
+        image.height() * scale,
+        FilterType::Nearest,
+    )
+}
+
+/// Writes one icon per size, each fitted into a transparent square.
+fn write_ico<W: Write>(w: W, image: &DynamicImage, sizes: &[u32]) -> io::Result<()> {
+    let mut frames = Vec::new();
+    for &size in sizes {
+        let fitted = image.resize(size, size, FilterType::Nearest).to_rgba8();
+        let mut icon = RgbaImage::new(size, size);
+        imageops::overlay(
+            &mut icon,
+            &fitted,
+            ((size - fitted.width()) / 2) as i64,
+            ((size - fitted.height()) / 2) as i64,
+        );
+        frames.push(IcoFrame::as_png(&icon, size, size, ColorType::Rgba8).map_err(image_error)?);
+    }
+    IcoEncoder::new(w)
+        .encode_images(&frames)
+        .map_err(image_error)
+}
+
+fn image_error(err: ImageError) -> io::Error {
+    match err {
+        ImageError::IoError(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+/// For animations written in a format that only holds one picture.
+pub fn animation_error(format: OutputFormat) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "{} can't hold an animation, use GIF, PNG or a sprite sheet",
+            format.name()
+        ),
+    )
+}
+
+/// GIF can only be written through a palette of at most 256 entries,
+/// transparency included.
+fn gif_palette_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "GIF needs at most 256 colors")
+}
+
+fn gif_error(err: gif::EncodingError) -> io::Error {
+    match err {
+        gif::EncodingError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{AnimationDecoder, ImageFormat, Rgba};
+    use std::io::Cursor;
+
+    const RED: Color32 = Color32::from_rgb(255, 0, 0);
+    const BLUE: Color32 = Color32::from_rgb(0, 0, 255);
+
+    fn filled(color: Color32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+            3,
+            2,
+            Rgba([color.r(), color.g(), color.b(), 255]),
+        ))
+    }
+
+    fn animation() -> Vec<AnimationFrame> {
+        vec![
+            AnimationFrame {
+                image: filled(RED),
+                delay_ms: 100,
+            },
+            AnimationFrame {
+                image: filled(BLUE),
+                delay_ms: 200,
+            },
+        ]
+    }
+
+    /// An image using each of the first `colors` entries of a gray palette,
+    /// and that palette.
+    fn gradient(colors: usize, width: u32, height: u32) -> (DynamicImage, Vec<Color32>) {
+        let palette: Vec<Color32> = (0..colors)
+            .map(|i| {
+                let v = (i * 255 / (colors - 1)) as u8;
+                Color32::from_rgb(v, v, 255 - v)
+            })
+            .collect();
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let c = palette[(y * width + x) as usize % colors];
+            Rgba([c.r(), c.g(), c.b(), 255])
+        });
+        (DynamicImage::ImageRgba8(image), palette)
+    }
+
+    /// The decoded header, the palette and the raw (still packed) rows.
+    fn decode_indexed_png(bytes: &[u8]) -> (png::OutputInfo, Vec<u8>, Vec<u8>) {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info().unwrap();
+        let palette = reader.info().palette.as_ref().unwrap().to_vec();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        data.truncate(info.buffer_size());
+        (info, palette, data)
+    }
+
+    #[test]
+    fn indices_follow_the_palette_order() {
+        let palette = [RED, BLUE, Color32::from_rgb(0, 255, 0)];
+        let image = RgbaImage::from_fn(4, 1, |x, _| match x {
+            0 => Rgba([0, 0, 255, 255]),
+            1 => Rgba([255, 0, 0, 255]),
+            // Not in the palette, closest to green.
+            2 => Rgba([10, 240, 20, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        });
+        let indexed = index_image(&DynamicImage::ImageRgba8(image), &palette).unwrap();
+        assert_eq!(indexed.indices, [1, 0, 2, 1]);
+        assert_eq!(indexed.palette, [[255, 0, 0], [0, 0, 255], [0, 255, 0]]);
+        assert_eq!(indexed.transparent, None);
+
+        assert!(index_image(&filled(RED), &[]).is_none());
+        assert!(index_image(&filled(RED), &[RED; 257]).is_none());
+    }
+
+    #[test]
+    fn transparency_takes_an_entry_of_its_own() {
+        let mut image = filled(RED).to_rgba8();
+        image.put_pixel(1, 0, Rgba([0, 0, 255, 0]));
+        let indexed = index_image(&DynamicImage::ImageRgba8(image.clone()), &[RED, BLUE]).unwrap();
+        assert_eq!(indexed.transparent, Some(2));
+        assert_eq!(indexed.indices, [0, 2, 0, 0, 0, 0]);
+        // A full palette leaves no room for it.
+        let image = DynamicImage::ImageRgba8(image);
+        assert!(index_image(&image, &[RED; 256]).is_none());
+
+        let mut buf = Vec::new();
+        write_indexed_png(&mut buf, &indexed).unwrap();
+        let decoded = image::load_from_memory(&buf).unwrap().to_rgba8();
+        assert_eq!(decoded.get_pixel(1, 0)[3], 0);
+        assert_eq!(decoded.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn bit_depth_is_the_smallest_that_fits() {
+        let depths: Vec<u8> = [1, 2, 3, 4, 5, 16, 17, 256]
+            .into_iter()
+            .map(min_bit_depth)
+            .collect();
+        assert_eq!(depths, [1, 1, 2, 2, 4, 4, 8, 8]);
+    }
+
+    #[test]
+    fn sub_byte_rows_are_packed_and_padded() {
+        let image = IndexedImage {
+            width: 5,
+            height: 2,
+            indices: vec![0, 1, 2, 3, 1, 3, 3, 3, 3, 3],
+            palette: vec![[0; 3]; 4],
+            transparent: None,
+        };
+        // Each row starts on a new byte, the unused bits stay zero.
+        assert_eq!(
+            pack_rows(&image, 2),
+            [0b0001_1011, 0b0100_0000, 0b1111_1111, 0b1100_0000]
+        );
+        let image = IndexedImage {
+            width: 3,
+            height: 1,
+            indices: vec![1, 0, 1],
+            palette: vec![[0; 3]; 2],
+            transparent: None,
+        };
+        assert_eq!(pack_rows(&image, 1), [0b1010_0000]);
+        assert_eq!(pack_rows(&image, 8), [1, 0, 1]);
+    }
+
+    #[test]
+    fn indexed_png_round_trips_at_each_depth() {
+        for (colors, depth) in [(2, 1), (4, 2), (16, 4), (256, 8)] {
+            // An odd width, so rows end in padding.
+            let (image, palette) = gradient(colors, 17, 16);
+            let indexed = index_image(&image, &palette).unwrap();
+            let mut buf = Vec::new();
+            write_indexed_png(&mut buf, &indexed).unwrap();
+
+            let (info, plte, data) = decode_indexed_png(&buf);
+            assert_eq!(info.color_type, png::ColorType::Indexed);
+            assert_eq!(info.bit_depth as u8, depth, "{} colors", colors);
+            assert_eq!(plte, indexed.palette.concat());
+            assert_eq!(data, pack_rows(&indexed, depth));
+            let decoded = image::load_from_memory(&buf).unwrap().to_rgba8();
+            assert_eq!(decoded, image.to_rgba8(), "{} colors", colors);
+        }
+    }
+
+    #[test]
+    fn gif_round_trips() {
+        let (image, palette) = gradient(16, 5, 3);
+        let indexed = index_image(&image, &palette).unwrap();
+        let mut buf = Vec::new();
+        write_gif(&mut buf, &indexed).unwrap();
+        let decoded = image::load_from_memory(&buf).unwrap().to_rgba8();
+        assert_eq!(decoded, image.to_rgba8());
+    }
+
+    #[test]
+    fn each_format_decodes_back_to_the_output() {
+        let (image, palette) = gradient(4, 3, 2);
+        let expected = image::imageops::resize(
+            &image.to_rgba8(),
+            6,
+            4,
+            image::imageops::FilterType::Nearest,
+        );
+        let cases = [
+            (OutputFormat::Png, true, ImageFormat::Png),
+            (OutputFormat::Png, false, ImageFormat::Png),
+            (OutputFormat::Gif, true, ImageFormat::Gif),
+            (OutputFormat::Bmp, true, ImageFormat::Bmp),
+            (OutputFormat::Bmp, false, ImageFormat::Bmp),
+            (OutputFormat::Tga, false, ImageFormat::Tga),
+            (OutputFormat::WebP, false, ImageFormat::WebP),
+            (OutputFormat::Qoi, false, ImageFormat::Qoi),
+        ];
+        for (format, indexed, decoded_as) in cases {
+            let options = ExportOptions {
+                format,
+                indexed,
+                scale: 2,
+                ..Default::default()
+            };
+            let bytes = encode_output(&image, None, &palette, &options).unwrap();
+            let decoded = image::load_from_memory_with_format(&bytes, decoded_as)
+                .unwrap()
+                .to_rgba8();
+            assert_eq!(decoded, expected, "{:?}, indexed: {}", format, indexed);
+        }
+    }
+
+    #[test]
+    fn ico_holds_each_size_centered() {
+        let options = ExportOptions {
+            format: OutputFormat::Ico,
+            ico_sizes: vec![16, 32],
+            ..Default::default()
+        };
+        let bytes = encode_output(&filled(RED), None, &[RED], &options).unwrap();
+        // Entries in the directory after the 6 byte header, 16 bytes each.
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), 2);
+        assert_eq!((bytes[6], bytes[22]), (16, 32));
+        // The largest one is decoded, a 3×2 picture fitted into it.
+        let decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Ico)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(decoded.dimensions(), (32, 32));
+        assert_eq!(decoded.get_pixel(16, 16), &Rgba([255, 0, 0, 255]));
+        assert_eq!(decoded.get_pixel(16, 1)[3], 0);
+    }
+
+    #[test]
+    fn aseprite_header_has_the_output_size() {
+        let options = ExportOptions {
+            format: OutputFormat::Aseprite,
+            ..Default::default()
+        };
+        let bytes = encode_output(&filled(RED), None, &[RED, BLUE], &options).unwrap();
+        let word = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        assert_eq!(
+            u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize,
+            bytes.len()
+        );
+        assert_eq!(word(4), 0xA5E0);
+        assert_eq!((word(8), word(10)), (3, 2));
+    }
+
+    #[test]
+    fn animated_gif_keeps_every_frame() {
+        let options = ExportOptions {
+            format: OutputFormat::Gif,
+            ..Default::default()
+        };
+        let bytes = encode_animation(&animation(), &[RED, BLUE], &options).unwrap();
+        let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(bytes)).unwrap();
+        let frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+        assert_eq!(frames[1].delay().numer_denom_ms(), (200, 1));
+    }
+
+    #[test]
+    fn formats_without_animation_fail() {
+        for format in OutputFormat::ALL {
+            if format.can_animate() {
+                continue;
+            }
+            let options = ExportOptions {
+                format,
+                ..Default::default()
+            };
+            let err = encode_animation(&animation(), &[RED, BLUE], &options).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", format);
+        }
+    }
+}
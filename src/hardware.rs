@@ -0,0 +1,378 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use palette::{FromColor, Lab, Srgb};
+
+/// Rounds of reassigning cells and refitting sub-palettes.
+const MAX_ITERATIONS: usize = 16;
+
+/// Passes of the swap search that polishes each sub-palette.
+const MAX_SWAP_PASSES: usize = 8;
+
+/// Old consoles and computers split the screen into attribute cells, each
+/// drawn with one of a few small sub-palettes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct HardwareParams {
+    pub enabled: bool,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// Number of sub-palettes (M).
+    pub sub_palettes: usize,
+    /// Colors in each sub-palette (N).
+    pub colors_per_palette: usize,
+}
+
+impl HardwareParams {
+    pub const PRESETS: [(&'static str, HardwareParams); 3] = [
+        (
+            "NES",
+            HardwareParams {
+                enabled: true,
+                cell_width: 16,
+                cell_height: 16,
+                sub_palettes: 4,
+                colors_per_palette: 4,
+            },
+        ),
+        (
+            "Game Boy Color",
+            HardwareParams {
+                enabled: true,
+                cell_width: 8,
+                cell_height: 8,
+                sub_palettes: 8,
+                colors_per_palette: 4,
+            },
+        ),
+        (
+            "ZX Spectrum",
+            HardwareParams {
+                enabled: true,
+                cell_width: 8,
+                cell_height: 8,
+                sub_palettes: 16,
+                colors_per_palette: 2,
+            },
+        ),
+    ];
+}
+
+pub struct HardwareResult {
+    pub image: DynamicImage,
+    /// Palette indices making up each sub-palette.
+    pub sub_palettes: Vec<Vec<usize>>,
+    /// Sub-palette used by every cell, row major.
+    pub cell_palettes: Vec<usize>,
+    /// Average squared Lab distance the constraint added per pixel.
+    pub error: f32,
+}
+
+/// Redraws a pixelized image so that every attribute cell only uses the
+/// colors of one sub-palette. The sub-palettes are subsets of `colors`,
+/// chosen together with the cell assignment to keep the total color error
+/// low.
+pub fn apply_constraints(
+    image: &DynamicImage,
+    colors: &[Lab],
+    params: HardwareParams,
+) -> Option<HardwareResult> {
+    if colors.is_empty() || params.sub_palettes == 0 || params.colors_per_palette == 0 {
+        return None;
+    }
+    let k = colors.len();
+    let rgb: Vec<[u8; 3]> = colors
+        .iter()
+        .map(|c| {
+            let c: Srgb<u8> = Srgb::from_color(*c).into_format();
+            [c.red, c.green, c.blue]
+        })
+        .collect();
+    let dist: Vec<f32> = colors
+        .iter()
+        .flat_map(|a| colors.iter().map(move |b| delta_e(*a, *b)))
+        .collect();
+
+    // Palette index of every pixel.
+    let input = image.to_rgb8();
+    let (width, height) = input.dimensions();
+    let indices: Vec<usize> = input
+        .pixels()
+        .map(|p| {
+            rgb.iter().position(|c| *c == p.0).unwrap_or_else(|| {
+                let lab = Lab::from_color(Srgb::new(p[0], p[1], p[2]).into_format::<f32>());
+                (0..k)
+                    .min_by(|a, b| {
+                        delta_e(lab, colors[*a])
+                            .partial_cmp(&delta_e(lab, colors[*b]))
+                            .unwrap()
+                    })
+                    .unwrap()
+            })
+        })
+        .collect();
+
+    let cell_width = params.cell_width.max(1);
+    let cell_height = params.cell_height.max(1);
+    let cells_x = (width + cell_width - 1) / cell_width;
+    let cells_y = (height + cell_height - 1) / cell_height;
+    let cell_of = |x: u32, y: u32| ((y / cell_height) * cells_x + x / cell_width) as usize;
+
+    let mut histograms = vec![vec![0.0; k]; (cells_x * cells_y) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            histograms[cell_of(x, y)][indices[(y * width + x) as usize]] += 1.0;
+        }
+    }
+
+    let n = params.colors_per_palette.min(k);
+    let total = sum_histograms(histograms.iter());
+    let mut palettes = vec![fit_palette(&total, n, &dist)];
+
+    // Seed every further sub-palette from the cell served worst so far.
+    while palettes.len() < params.sub_palettes {
+        let worst = histograms
+            .iter()
+            .map(|h| best_palette(h, &palettes, &dist).1)
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        match worst {
+            Some((cell, cost)) if cost > 0.0 => {
+                palettes.push(fit_palette(&histograms[cell], n, &dist));
+            }
+            _ => break,
+        }
+    }
+
+    let mut assignment: Vec<usize> = Vec::new();
+    for _ in 0..MAX_ITERATIONS {
+        let next: Vec<usize> = histograms
+            .iter()
+            .map(|h| best_palette(h, &palettes, &dist).0)
+            .collect();
+        if next == assignment {
+            break;
+        }
+        assignment = next;
+        for (p, palette) in palettes.iter_mut().enumerate() {
+            let members = histograms
+                .iter()
+                .zip(&assignment)
+                .filter(|(_, a)| **a == p)
+                .map(|(h, _)| h);
+            let merged = sum_histograms(members);
+            if merged.iter().any(|w| *w > 0.0) {
+                *palette = fit_palette(&merged, n, &dist);
+            }
+        }
+    }
+
+    let mut output = RgbImage::new(width, height);
+    let mut error = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let index = indices[(y * width + x) as usize];
+            let palette = &palettes[assignment[cell_of(x, y)]];
+            let closest = nearest(index, palette, &dist, k);
+            error += dist[index * k + closest];
+            output.put_pixel(x, y, Rgb(rgb[closest]));
+        }
+    }
+
+    Some(HardwareResult {
+        image: DynamicImage::ImageRgb8(output),
+        sub_palettes: palettes,
+        cell_palettes: assignment,
+        error: error / (width * height).max(1) as f32,
+    })
+}
+
+fn delta_e(a: Lab, b: Lab) -> f32 {
+    (a.a - b.a).powi(2) + (a.b - b.b).powi(2) + (a.l - b.l).powi(2)
+}
+
+fn sum_histograms<'a>(histograms: impl Iterator<Item = &'a Vec<f32>>) -> Vec<f32> {
+    let mut sum: Vec<f32> = Vec::new();
+    for histogram in histograms {
+        sum.resize(histogram.len(), 0.0);
+        for (s, w) in sum.iter_mut().zip(histogram) {
+            *s += w;
+        }
+    }
+    sum
+}
+
+/// The entry of `palette` closest to color `index`. `dist` is the row major
+/// `k` by `k` table of distances between the colors.
+fn nearest(index: usize, palette: &[usize], dist: &[f32], k: usize) -> usize {
+    *palette
+        .iter()
+        .min_by(|a, b| {
+            dist[index * k + **a]
+                .partial_cmp(&dist[index * k + **b])
+                .unwrap()
+        })
+        .unwrap()
+}
+
+/// Error of drawing the colors counted in `histogram` with `palette`.
+fn cost(histogram: &[f32], palette: &[usize], dist: &[f32]) -> f32 {
+    let k = histogram.len();
+    histogram
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0.0)
+        .map(|(i, w)| w * dist[i * k + nearest(i, palette, dist, k)])
+        .sum()
+}
+
+fn best_palette(histogram: &[f32], palettes: &[Vec<usize>], dist: &[f32]) -> (usize, f32) {
+    palettes
+        .iter()
+        .map(|palette| cost(histogram, palette, dist))
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .unwrap()
+}
+
+/// Picks `n` palette colors for the colors counted in `histogram`, greedily
+/// and then improved by swapping single colors.
+fn fit_palette(histogram: &[f32], n: usize, dist: &[f32]) -> Vec<usize> {
+    let k = histogram.len();
+    let mut palette: Vec<usize> = Vec::with_capacity(n);
+    while palette.len() < n {
+        let best = (0..k).filter(|c| !palette.contains(c)).min_by(|a, b| {
+            let with_a = [palette.as_slice(), &[*a]].concat();
+            let with_b = [palette.as_slice(), &[*b]].concat();
+            cost(histogram, &with_a, dist)
+                .partial_cmp(&cost(histogram, &with_b, dist))
+                .unwrap()
+        });
+        match best {
+            Some(c) => palette.push(c),
+            None => break,
+        }
+    }
+
+    let mut current = cost(histogram, &palette, dist);
+    for _ in 0..MAX_SWAP_PASSES {
+        let mut improved = false;
+        for slot in 0..palette.len() {
+            for c in 0..k {
+                if palette.contains(&c) {
+                    continue;
+                }
+                let previous = palette[slot];
+                palette[slot] = c;
+                let swapped = cost(histogram, &palette, dist);
+                if swapped < current {
+                    current = swapped;
+                    improved = true;
+                } else {
+                    palette[slot] = previous;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    palette.sort_unstable();
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLORS: [[u8; 3]; 6] = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [255, 0, 0],
+        [0, 0, 255],
+        [0, 255, 0],
+        [255, 255, 0],
+    ];
+
+    const PARAMS: HardwareParams = HardwareParams {
+        enabled: true,
+        cell_width: 4,
+        cell_height: 4,
+        sub_palettes: 2,
+        colors_per_palette: 2,
+    };
+
+    fn lab(colors: &[[u8; 3]]) -> Vec<Lab> {
+        colors
+            .iter()
+            .map(|c| Lab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>()))
+            .collect()
+    }
+
+    /// Colors of every cell of `image`, row major.
+    fn cell_colors(image: &RgbImage, params: HardwareParams) -> Vec<Vec<[u8; 3]>> {
+        let cells_x = (image.width() + params.cell_width - 1) / params.cell_width;
+        let cells_y = (image.height() + params.cell_height - 1) / params.cell_height;
+        let mut cells = vec![Vec::new(); (cells_x * cells_y) as usize];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let cell =
+                &mut cells[(y / params.cell_height * cells_x + x / params.cell_width) as usize];
+            if !cell.contains(&pixel.0) {
+                cell.push(pixel.0);
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn cells_only_use_their_sub_palette() {
+        // Every pixel a different color from its neighbours, in a 10×6
+        // picture whose cells at the edges are cut short.
+        let image = RgbImage::from_fn(10, 6, |x, y| Rgb(COLORS[((x * 7 + y * 3) % 6) as usize]));
+        let result =
+            apply_constraints(&DynamicImage::ImageRgb8(image), &lab(&COLORS), PARAMS).unwrap();
+        assert!(result.sub_palettes.len() <= PARAMS.sub_palettes);
+        assert!(result
+            .sub_palettes
+            .iter()
+            .all(|p| p.len() <= PARAMS.colors_per_palette));
+        assert!(result.error > 0.0);
+
+        let cells = cell_colors(&result.image.to_rgb8(), PARAMS);
+        assert_eq!(cells.len(), result.cell_palettes.len());
+        for (colors, palette) in cells.iter().zip(&result.cell_palettes) {
+            let allowed: Vec<[u8; 3]> = result.sub_palettes[*palette]
+                .iter()
+                .map(|i| COLORS[*i])
+                .collect();
+            assert!(colors.iter().all(|c| allowed.contains(c)), "{:?}", colors);
+        }
+    }
+
+    #[test]
+    fn pictures_within_the_limits_are_kept() {
+        // Black and white on the left, red and blue on the right.
+        let image = RgbImage::from_fn(8, 4, |x, y| {
+            let pair = if x < 4 { 0 } else { 2 };
+            Rgb(COLORS[pair + ((x + y) % 2) as usize])
+        });
+        let result = apply_constraints(
+            &DynamicImage::ImageRgb8(image.clone()),
+            &lab(&COLORS),
+            PARAMS,
+        )
+        .unwrap();
+        assert_eq!(result.error, 0.0);
+        assert_eq!(result.image.to_rgb8(), image);
+        assert_eq!(result.sub_palettes.len(), 2);
+        assert_ne!(result.cell_palettes[0], result.cell_palettes[1]);
+    }
+
+    #[test]
+    fn empty_settings_do_nothing() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(4, 4));
+        let params = HardwareParams {
+            sub_palettes: 0,
+            ..PARAMS
+        };
+        assert!(apply_constraints(&image, &lab(&COLORS), params).is_none());
+        assert!(apply_constraints(&image, &[], PARAMS).is_none());
+    }
+}
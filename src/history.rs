@@ -0,0 +1,237 @@
+use egui::color::Color32;
+use egui_extras::image::RetainedImage;
+use image::{imageops::FilterType, DynamicImage};
+use palette::Lab;
+
+use crate::animation::AnimationFrame;
+use crate::editor::PaletteIndices;
+use crate::preset::PipelineSettings;
+use crate::util::dynamic_image_to_color_image;
+
+/// Older states are dropped past this many, outputs can be large.
+const MAX_HISTORY: usize = 32;
+
+/// Thumbnails fit in a square this many points wide.
+const THUMBNAIL_SIZE: u32 = 64;
+
+/// Everything a generation or a palette edit changes.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub label: String,
+    pub settings: PipelineSettings,
+    pub color_palette: Option<Vec<Color32>>,
+    pub lab_palette: Option<Vec<Lab>>,
+    pub palette_coverage: Option<Vec<f32>>,
+    pub sub_palettes: Option<Vec<(Vec<usize>, usize)>>,
+    pub output: Option<DynamicImage>,
+    pub output_frames: Option<Vec<AnimationFrame>>,
+    pub output_indices: Option<PaletteIndices>,
+}
+
+pub struct HistoryEntry {
+    pub snapshot: Snapshot,
+    pub thumbnail: Option<RetainedImage>,
+}
+
+/// Undo/redo stack of pipeline states. `current` is the state on screen;
+/// states after it are the ones that can be redone.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    current: usize,
+}
+
+impl History {
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(self.current)
+        }
+    }
+
+    /// Records a new state, dropping the states that could be redone.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if !self.entries.is_empty() {
+            self.entries.truncate(self.current + 1);
+        }
+        self.entries.push(HistoryEntry {
+            thumbnail: snapshot.output.as_ref().map(thumbnail),
+            snapshot,
+        });
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+        self.current = self.entries.len() - 1;
+    }
+
+    /// Updates the current state in place, so a color being dragged around
+    /// doesn't fill the history. Pushes if the current state has another
+    /// label or can be redone past.
+    pub fn amend(&mut self, snapshot: Snapshot) {
+        let is_last = self.current + 1 == self.entries.len();
+        match self.entries.get_mut(self.current) {
+            Some(entry) if is_last && entry.snapshot.label == snapshot.label => {
+                entry.thumbnail = snapshot.output.as_ref().map(thumbnail);
+                entry.snapshot = snapshot;
+            }
+            _ => self.push(snapshot),
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.current > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.current + 1 < self.entries.len()
+    }
+
+    pub fn undo(&mut self) -> Option<&Snapshot> {
+        if !self.can_undo() {
+            return None;
+        }
+        self.jump(self.current - 1)
+    }
+
+    pub fn redo(&mut self) -> Option<&Snapshot> {
+        if !self.can_redo() {
+            return None;
+        }
+        self.jump(self.current + 1)
+    }
+
+    pub fn jump(&mut self, index: usize) -> Option<&Snapshot> {
+        let entry = self.entries.get(index)?;
+        self.current = index;
+        Some(&entry.snapshot)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.current = 0;
+    }
+}
+
+fn thumbnail(image: &DynamicImage) -> RetainedImage {
+    let scale = THUMBNAIL_SIZE as f32 / image.width().max(image.height()).max(1) as f32;
+    let width = ((image.width() as f32 * scale) as u32).max(1);
+    let height = ((image.height() as f32 * scale) as u32).max(1);
+    let small = image.resize_exact(width, height, FilterType::Nearest);
+    RetainedImage::from_color_image("history", dynamic_image_to_color_image(small))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state told apart by its label and its pixel size.
+    fn snapshot(label: &str, pixel_size: usize) -> Snapshot {
+        Snapshot {
+            label: label.to_string(),
+            settings: PipelineSettings {
+                pixel_size: Some(pixel_size),
+                ..Default::default()
+            },
+            color_palette: None,
+            lab_palette: None,
+            palette_coverage: None,
+            sub_palettes: None,
+            output: None,
+            output_frames: None,
+            output_indices: None,
+        }
+    }
+
+    fn states(history: &History) -> Vec<(String, Option<usize>)> {
+        history
+            .entries()
+            .iter()
+            .map(|e| (e.snapshot.label.clone(), e.snapshot.settings.pixel_size))
+            .collect()
+    }
+
+    fn state(label: &str, pixel_size: usize) -> (String, Option<usize>) {
+        (label.to_string(), Some(pixel_size))
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_states() {
+        let mut history = History::default();
+        assert_eq!(history.current(), None);
+        assert!(!history.can_undo() && !history.can_redo());
+        assert!(history.undo().is_none());
+
+        history.push(snapshot("a", 1));
+        history.push(snapshot("b", 2));
+        history.push(snapshot("c", 3));
+        assert_eq!(history.current(), Some(2));
+        assert!(!history.can_redo());
+        assert_eq!(history.undo().unwrap().label, "b");
+        assert_eq!(history.undo().unwrap().label, "a");
+        assert!(history.undo().is_none());
+        assert_eq!(history.redo().unwrap().label, "b");
+        assert_eq!(history.jump(2).unwrap().label, "c");
+        assert!(history.jump(3).is_none());
+        assert_eq!(history.current(), Some(2));
+    }
+
+    #[test]
+    fn push_after_undo_drops_the_redo_states() {
+        let mut history = History::default();
+        for (i, label) in ["a", "b", "c"].into_iter().enumerate() {
+            history.push(snapshot(label, i));
+        }
+        history.undo();
+        history.undo();
+        history.push(snapshot("d", 3));
+        assert_eq!(states(&history), [state("a", 0), state("d", 3)]);
+        assert_eq!(history.current(), Some(1));
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn amend_merges_only_the_same_label() {
+        let mut history = History::default();
+        history.push(snapshot("generate", 1));
+        history.amend(snapshot("edit 1", 2));
+        history.amend(snapshot("edit 1", 3));
+        history.amend(snapshot("edit 2", 4));
+        assert_eq!(
+            states(&history),
+            [state("generate", 1), state("edit 1", 3), state("edit 2", 4)]
+        );
+
+        // A state that can be redone past is kept as it was, the edit is
+        // pushed after it instead.
+        history.undo();
+        history.amend(snapshot("edit 1", 5));
+        assert_eq!(
+            states(&history),
+            [state("generate", 1), state("edit 1", 3), state("edit 1", 5)]
+        );
+
+        let mut empty = History::default();
+        empty.amend(snapshot("edit 1", 1));
+        assert_eq!(states(&empty), [state("edit 1", 1)]);
+    }
+
+    #[test]
+    fn oldest_states_go_past_the_limit() {
+        let mut history = History::default();
+        for i in 0..MAX_HISTORY + 3 {
+            history.push(snapshot("generate", i));
+        }
+        assert_eq!(history.entries().len(), MAX_HISTORY);
+        assert_eq!(history.entries()[0].snapshot.settings.pixel_size, Some(3));
+        assert_eq!(history.current(), Some(MAX_HISTORY - 1));
+
+        history.clear();
+        assert_eq!(history.current(), None);
+        assert!(!history.can_undo());
+    }
+}
@@ -0,0 +1,192 @@
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+/// Extensions offered by the open file dialog.
+pub const INPUT_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "tif", "tiff", "webp", "ico", "tga", "qoi", "avif", "svg",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Raster(ImageFormat),
+    Svg,
+}
+
+/// Figures out the format from the file content. The name is only used as a
+/// hint for formats without a signature, like TGA.
+pub fn sniff_format(bytes: &[u8], name: Option<&str>) -> Option<InputFormat> {
+    if is_svg(bytes) {
+        return Some(InputFormat::Svg);
+    }
+    if let Ok(format) = image::guess_format(bytes) {
+        return Some(InputFormat::Raster(format));
+    }
+    let extension = name?.rsplit('.').next()?.to_ascii_lowercase();
+    match ImageFormat::from_extension(extension)? {
+        ImageFormat::Tga => Some(InputFormat::Raster(ImageFormat::Tga)),
+        _ => None,
+    }
+}
+
+/// Whether the root element is `<svg`, looking past the XML declaration,
+/// comments, processing instructions and a doctype however long they are.
+fn is_svg(bytes: &[u8]) -> bool {
+    let mut rest = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    loop {
+        while let [first, tail @ ..] = rest {
+            if !first.is_ascii_whitespace() {
+                break;
+            }
+            rest = tail;
+        }
+        let skipped = if rest.starts_with(b"<?") {
+            skip_past(rest, b"?>")
+        } else if rest.starts_with(b"<!--") {
+            skip_past(rest, b"-->")
+        } else if rest.starts_with(b"<!DOCTYPE") {
+            // The internal subset in brackets may contain '>' itself.
+            match rest.iter().position(|&b| b == b'[' || b == b'>') {
+                Some(i) if rest[i] == b'[' => {
+                    skip_past(&rest[i..], b"]").and_then(|r| skip_past(r, b">"))
+                }
+                Some(i) => Some(&rest[i + 1..]),
+                None => None,
+            }
+        } else {
+            return rest.starts_with(b"<svg");
+        };
+        match skipped {
+            Some(skipped) => rest = skipped,
+            None => return false,
+        }
+    }
+}
+
+/// The bytes after the first `end`.
+fn skip_past<'a>(bytes: &'a [u8], end: &[u8]) -> Option<&'a [u8]> {
+    let at = bytes.windows(end.len()).position(|w| w == end)?;
+    Some(&bytes[at + end.len()..])
+}
+
+/// Decodes any supported picture. SVGs are rasterized at their own size.
+pub fn load_image(bytes: &[u8], name: Option<&str>) -> Option<DynamicImage> {
+    match sniff_format(bytes, name)? {
+        InputFormat::Svg => {
+            let color_image = egui_extras::image::load_svg_bytes(bytes).ok()?;
+            let [width, height] = color_image.size;
+            let rgba = color_image
+                .pixels
+                .iter()
+                .flat_map(|c| c.to_srgba_unmultiplied())
+                .collect();
+            RgbaImage::from_raw(width as u32, height as u32, rgba).map(DynamicImage::ImageRgba8)
+        }
+        InputFormat::Raster(format) => image::load_from_memory_with_format(bytes, format).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, Rgba};
+    use std::io::Cursor;
+
+    const SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="3"><rect width="4" height="3" fill="#ff0000"/></svg>"##;
+
+    fn sample() -> DynamicImage {
+        let mut image = RgbaImage::new(3, 2);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Rgba([x as u8 * 100, y as u8 * 200, 50, 255]);
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = match format {
+            // BMP and JPEG are written without alpha.
+            ImageFormat::Bmp | ImageFormat::Jpeg => DynamicImage::ImageRgb8(sample().to_rgb8()),
+            _ => sample(),
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        image
+            .write_to(&mut bytes, ImageOutputFormat::from(format))
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn rasters_are_sniffed_and_loaded() {
+        let formats = [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Bmp,
+            ImageFormat::Tiff,
+            ImageFormat::WebP,
+            ImageFormat::Ico,
+            ImageFormat::Qoi,
+        ];
+        for format in formats {
+            let bytes = encode(format);
+            // A misleading name must not matter for formats with a signature.
+            assert_eq!(
+                sniff_format(&bytes, Some("picture.tga")),
+                Some(InputFormat::Raster(format)),
+                "{:?}",
+                format
+            );
+            let image = load_image(&bytes, None).unwrap();
+            assert_eq!((image.width(), image.height()), (3, 2), "{:?}", format);
+            if !matches!(format, ImageFormat::Jpeg | ImageFormat::Gif) {
+                assert_eq!(image.to_rgba8(), sample().to_rgba8(), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn tga_needs_its_extension() {
+        let bytes = encode(ImageFormat::Tga);
+        assert_eq!(sniff_format(&bytes, None), None);
+        assert_eq!(
+            sniff_format(&bytes, Some("picture.TGA")),
+            Some(InputFormat::Raster(ImageFormat::Tga))
+        );
+        let image = load_image(&bytes, Some("picture.tga")).unwrap();
+        assert_eq!(image.to_rgba8(), sample().to_rgba8());
+    }
+
+    #[test]
+    fn avif_is_sniffed() {
+        let bytes = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf";
+        assert_eq!(
+            sniff_format(bytes, None),
+            Some(InputFormat::Raster(ImageFormat::Avif))
+        );
+    }
+
+    #[test]
+    fn svg_is_rasterized() {
+        assert_eq!(sniff_format(SVG.as_bytes(), None), Some(InputFormat::Svg));
+        let image = load_image(SVG.as_bytes(), Some("picture.png")).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3));
+        assert_eq!(image.to_rgba8().get_pixel(1, 1), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn svg_is_found_past_a_long_prolog() {
+        let long_comment = format!("<!-- {} -->", "x".repeat(4096));
+        let doctype =
+            r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "svg11.dtd" [ <!ENTITY a "<b>"> ]>"#;
+        let svg = format!(
+            "\u{feff}<?xml version=\"1.0\"?>\n{}\n<?xml-stylesheet href=\"a.css\"?>\n{}\n{}",
+            long_comment, doctype, SVG
+        );
+        assert!(is_svg(svg.as_bytes()));
+    }
+
+    #[test]
+    fn other_xml_is_not_svg() {
+        assert!(!is_svg(b"<?xml version=\"1.0\"?><html><svg/></html>"));
+        assert!(!is_svg(b"<!-- <svg> never closed"));
+        assert!(!is_svg(b"plain text mentioning <svg"));
+    }
+}
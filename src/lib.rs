@@ -1,5 +1,29 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
+mod animation;
 mod app;
+mod aseprite;
+mod canvas;
+mod cleanup;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+mod editor;
+mod export;
+mod hardware;
+mod history;
+mod input;
+mod native;
+mod pipeline;
+mod preset;
+mod project;
+#[cfg(not(target_arch = "wasm32"))]
+mod sequence;
+mod slic;
+mod spritesheet;
+mod tiles;
+mod transform;
 mod util;
-pub use app::PixeliteApp;
+mod weights;
+pub use app::{PixeliteApp, APP_NAME};
+#[cfg(not(target_arch = "wasm32"))]
+pub use cli::{CliArgs, USAGE};
@@ -7,15 +7,37 @@ fn main() {
     // Log to stdout (if you run with `RUST_LOG=debug`).
     tracing_subscriber::fmt::init();
 
+    let args = match pixelite::CliArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, pixelite::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", pixelite::USAGE);
+        return;
+    }
+    if args.is_headless() {
+        match pixelite::PixeliteApp::run_headless(&args) {
+            Ok(message) => println!("{}", message),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let native_options = eframe::NativeOptions {
         follow_system_theme: true,
         drag_and_drop_support: true,
         ..Default::default()
     };
     eframe::run_native(
-        "pixelite",
+        pixelite::APP_NAME,
         native_options,
-        Box::new(|cc| Box::new(pixelite::PixeliteApp::new(cc))),
+        Box::new(move |cc| Box::new(pixelite::PixeliteApp::with_args(cc, &args))),
     );
 }
 
@@ -0,0 +1,317 @@
+use image::{DynamicImage, Rgb, RgbImage};
+use palette::{FromColor, Lab, Srgb};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Largest pixel scale looked for.
+const MAX_SCALE: usize = 64;
+
+/// Scales whose grid lines catch at least this share of the edges the best
+/// scale catches count as a match, and the largest of those wins. Divisors
+/// of the true scale catch its edges too, multiples only some of them.
+const SCALE_TOLERANCE: f32 = 0.85;
+
+/// Edges at the grid lines have to be this many times stronger than
+/// average for the picture to count as upscaled pixel art.
+const MIN_CONTRAST: f32 = 2.0;
+
+/// Colors closer than this (squared Lab distance) are taken for the same
+/// palette color with compression noise on top.
+const MERGE_DISTANCE: f32 = 64.0;
+
+/// Most colors a recovered palette keeps, what indexed exports can hold.
+const MAX_COLORS: usize = 256;
+
+/// The grid an upscaled pixel art picture was drawn on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NativeGrid {
+    /// Screen pixels per native pixel.
+    pub scale: usize,
+    /// Where the first whole native pixel starts.
+    pub offset: [u32; 2],
+}
+
+pub struct NativeImage {
+    pub grid: NativeGrid,
+    pub image: DynamicImage,
+    /// Recovered palette with the share of native pixels using each color,
+    /// most used first.
+    pub palette: Vec<([u8; 3], f32)>,
+}
+
+/// Finds the scale and phase of the grid from where edges pile up. Returns
+/// `None` when edges don't line up on any grid.
+pub fn detect_grid(image: &DynamicImage) -> Option<NativeGrid> {
+    let rgb = image.to_rgb8();
+    let (columns, rows) = edge_profiles(&rgb);
+    let max_scale = MAX_SCALE.min(columns.len() / 2).min(rows.len() / 2);
+    if max_scale < 2 {
+        return None;
+    }
+
+    let shares: Vec<(usize, f32, [u32; 2])> = (2..=max_scale)
+        .map(|scale| {
+            let (x_share, x_phase) = best_phase(&columns, scale);
+            let (y_share, y_phase) = best_phase(&rows, scale);
+            (scale, (x_share + y_share) / 2.0, [x_phase, y_phase])
+        })
+        .collect();
+    let best = shares.iter().map(|s| s.1).fold(0.0, f32::max);
+    let (scale, share, phase) = shares
+        .iter()
+        .rev()
+        .find(|(_, share, _)| *share >= best * SCALE_TOLERANCE)?;
+    // A grid that catches no more than its share of lines is no grid.
+    if share * (*scale as f32) < MIN_CONTRAST {
+        return None;
+    }
+    Some(NativeGrid {
+        scale: *scale,
+        offset: *phase,
+    })
+}
+
+/// Detects the grid and rebuilds the picture at its native size, with
+/// compression noise cleaned out of the colors.
+pub fn depixelize(image: &DynamicImage) -> Option<NativeImage> {
+    let grid = detect_grid(image)?;
+    let rgb = image.to_rgb8();
+    let scale = grid.scale as u32;
+    let [offset_x, offset_y] = grid.offset;
+    let width = (rgb.width() - offset_x) / scale;
+    let height = (rgb.height() - offset_y) / scale;
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    // The middle of each cell, away from blurred edges.
+    let margin = if scale >= 4 { scale / 4 } else { 0 };
+    let mut cells = RgbImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut samples: [Vec<u8>; 3] = Default::default();
+            for dy in margin..scale - margin {
+                for dx in margin..scale - margin {
+                    let pixel = rgb.get_pixel(offset_x + x * scale + dx, offset_y + y * scale + dy);
+                    for c in 0..3 {
+                        samples[c].push(pixel[c]);
+                    }
+                }
+            }
+            cells.put_pixel(x, y, Rgb(samples.map(|mut s| median(&mut s))));
+        }
+    }
+
+    let (image, palette) = recover_palette(&cells);
+    Some(NativeImage {
+        grid,
+        image: DynamicImage::ImageRgb8(image),
+        palette,
+    })
+}
+
+/// How strongly the colors change between neighbouring columns and rows,
+/// summed along them. Entry `i` is the change between `i - 1` and `i`.
+fn edge_profiles(image: &RgbImage) -> (Vec<f32>, Vec<f32>) {
+    let (width, height) = image.dimensions();
+    let mut columns = vec![0.0; width as usize];
+    let mut rows = vec![0.0; height as usize];
+    let difference = |a: &Rgb<u8>, b: &Rgb<u8>| -> f32 {
+        (0..3).map(|c| (a[c] as f32 - b[c] as f32).abs()).sum()
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = image.get_pixel(x, y);
+            if x > 0 {
+                columns[x as usize] += difference(pixel, image.get_pixel(x - 1, y));
+            }
+            if y > 0 {
+                rows[y as usize] += difference(pixel, image.get_pixel(x, y - 1));
+            }
+        }
+    }
+    (columns, rows)
+}
+
+/// The phase whose positions carry the most edge, with the share of all
+/// edge they carry.
+fn best_phase(profile: &[f32], scale: usize) -> (f32, u32) {
+    let total = profile.iter().sum::<f32>();
+    if total == 0.0 {
+        return (0.0, 0);
+    }
+    (0..scale)
+        .map(|phase| {
+            let at_phase = profile.iter().skip(phase).step_by(scale).sum::<f32>();
+            (at_phase / total, phase as u32)
+        })
+        .fold((0.0, 0), |best, s| if s.0 > best.0 { s } else { best })
+}
+
+fn median(samples: &mut [u8]) -> u8 {
+    samples.sort_unstable();
+    samples[samples.len() / 2]
+}
+
+/// Merges colors that only differ by noise. Each group takes the color of
+/// its most common member, which is most likely the color as drawn. Noisier
+/// pictures get merged harder until at most [`MAX_COLORS`] groups are left.
+fn recover_palette(cells: &RgbImage) -> (RgbImage, Vec<([u8; 3], f32)>) {
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for pixel in cells.pixels() {
+        *counts.entry(pixel.0).or_insert(0) += 1;
+    }
+    // Most common colors first, so they become the group colors.
+    let mut counts: Vec<([u8; 3], usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|(color, n)| (Reverse(*n), *color));
+    let colors: Vec<([u8; 3], Lab, usize)> = counts
+        .into_iter()
+        .map(|(color, n)| {
+            let lab = Lab::from_color(Srgb::new(color[0], color[1], color[2]).into_format());
+            (color, lab, n)
+        })
+        .collect();
+
+    let mut merge_distance = MERGE_DISTANCE;
+    let (groups, group_of) = loop {
+        if let Some(merged) = merge_colors(&colors, merge_distance) {
+            break merged;
+        }
+        merge_distance *= 2.0;
+    };
+
+    let mut image = cells.clone();
+    for pixel in image.pixels_mut() {
+        if let Some(group) = group_of.get(&pixel.0) {
+            *pixel = Rgb(groups[*group].0);
+        }
+    }
+    let total = (cells.width() * cells.height()).max(1) as f32;
+    let mut palette: Vec<([u8; 3], f32)> = groups
+        .iter()
+        .map(|(color, _, count)| (*color, *count as f32 / total))
+        .collect();
+    palette.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    (image, palette)
+}
+
+type Groups = (Vec<([u8; 3], Lab, usize)>, HashMap<[u8; 3], usize>);
+
+/// Puts every color into its nearest group if that is closer than
+/// `merge_distance`, or into a new group. `None` once there would be more than [`MAX_COLORS`].
+fn merge_colors(colors: &[([u8; 3], Lab, usize)], merge_distance: f32) -> Option<Groups> {
+    let distance = |a: Lab, b: Lab| (a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2);
+    let mut groups: Vec<([u8; 3], Lab, usize)> = Vec::new();
+    let mut group_of = HashMap::with_capacity(colors.len());
+    for (color, lab, count) in colors {
+        let nearest = groups
+            .iter()
+            .enumerate()
+            .map(|(i, g)| (i, distance(g.1, *lab)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let group = match nearest {
+            Some((i, d)) if d < merge_distance => i,
+            _ if groups.len() == MAX_COLORS => return None,
+            _ => {
+                groups.push((*color, *lab, 0));
+                groups.len() - 1
+            }
+        };
+        groups[group].2 += count;
+        group_of.insert(*color, group);
+    }
+    Some((groups, group_of))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLORS: [[u8; 3]; 4] = [[20, 20, 40], [200, 60, 60], [240, 220, 120], [60, 160, 90]];
+
+    fn native(x: u32, y: u32) -> [u8; 3] {
+        COLORS[((x * 3 + y * 5 + x * y) % 4) as usize]
+    }
+
+    /// The 8×8 picture drawn at `scale` with its first whole pixel at
+    /// `offset`, the cut off pixels before it showing partly.
+    fn upscaled(scale: u32, offset: [u32; 2], noise: u8) -> DynamicImage {
+        let [ox, oy] = offset;
+        let image = RgbImage::from_fn(ox + 8 * scale, oy + 8 * scale, |x, y| {
+            // Columns and rows before the offset belong to native pixel -1.
+            let nx = (x + scale - ox) / scale;
+            let ny = (y + scale - oy) / scale;
+            let color = native(nx + 7, ny + 7);
+            let jitter = ((x * 7 + y * 13) % 3) as u8 * noise;
+            Rgb(color.map(|c| c.saturating_add(jitter)))
+        });
+        DynamicImage::ImageRgb8(image)
+    }
+
+    #[test]
+    fn finds_scale_and_phase() {
+        let grid = detect_grid(&upscaled(4, [1, 2], 0)).unwrap();
+        assert_eq!(
+            grid,
+            NativeGrid {
+                scale: 4,
+                offset: [1, 2]
+            }
+        );
+    }
+
+    #[test]
+    fn rebuilds_the_native_picture() {
+        let result = depixelize(&upscaled(4, [1, 2], 0)).unwrap();
+        let image = result.image.to_rgb8();
+        assert_eq!(image.dimensions(), (8, 8));
+        for (x, y, pixel) in image.enumerate_pixels() {
+            assert_eq!(pixel.0, native(x + 8, y + 8), "pixel {}, {}", x, y);
+        }
+
+        let mut expected: Vec<([u8; 3], f32)> = COLORS
+            .iter()
+            .map(|c| {
+                let n = image.pixels().filter(|p| p.0 == *c).count();
+                (*c, n as f32 / 64.0)
+            })
+            .filter(|(_, share)| *share > 0.0)
+            .collect();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let shares: Vec<f32> = result.palette.iter().map(|(_, s)| *s).collect();
+        assert_eq!(shares, expected.iter().map(|(_, s)| *s).collect::<Vec<_>>());
+        let mut colors: Vec<[u8; 3]> = result.palette.iter().map(|(c, _)| *c).collect();
+        let mut expected: Vec<[u8; 3]> = expected.iter().map(|(c, _)| *c).collect();
+        colors.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn noise_merges_into_the_drawn_colors() {
+        let result = depixelize(&upscaled(4, [1, 2], 2)).unwrap();
+        assert_eq!(
+            result.grid,
+            NativeGrid {
+                scale: 4,
+                offset: [1, 2]
+            }
+        );
+        assert_eq!(result.palette.len(), 4);
+    }
+
+    #[test]
+    fn smooth_pictures_have_no_grid() {
+        let image = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 128]));
+        assert_eq!(detect_grid(&DynamicImage::ImageRgb8(image)), None);
+    }
+
+    #[test]
+    fn palettes_stay_indexable() {
+        let cells = RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, 0]));
+        let (_, palette) = recover_palette(&cells);
+        assert!(palette.len() <= MAX_COLORS);
+        let total: f32 = palette.iter().map(|(_, s)| s).sum();
+        assert!((total - 1.0).abs() < 1e-3);
+    }
+}
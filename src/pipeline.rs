@@ -0,0 +1,339 @@
+use egui::color::Color32;
+use image::{DynamicImage, RgbImage};
+use palette::Lab;
+
+use crate::animation::{self, AnimationFrame};
+use crate::slic;
+use crate::transform::Transform;
+use crate::util::{self, KmeansParams};
+use crate::weights::{self, WeightParams, Weights};
+
+/// Pixel sizes whose block averages are kept, for going back and forth.
+const MAX_BLOCK_AVERAGES: usize = 4;
+
+/// Palettes kept, full and preview ones together.
+const MAX_PALETTES: usize = 8;
+
+/// Superpixel results kept.
+const MAX_SUPERPIXELS: usize = 2;
+
+/// Colors in display form, in Lab, and the share of the picture each covers.
+pub type Palette = (Vec<Color32>, Vec<Lab>, Vec<f32>);
+
+/// Results kept for the most recently used keys, oldest dropped first.
+struct Memo<K, V> {
+    entries: Vec<(K, V)>,
+    capacity: usize,
+}
+
+impl<K: PartialEq, V> Memo<K, V> {
+    fn new(capacity: usize) -> Self {
+        Memo {
+            entries: Vec::new(),
+            capacity,
+        }
+    }
+
+    fn get_or_insert_with(&mut self, key: K, compute: impl FnOnce() -> Option<V>) -> Option<&V> {
+        match self.entries.iter().position(|(k, _)| *k == key) {
+            // Most recently used last.
+            Some(i) => {
+                let entry = self.entries.remove(i);
+                self.entries.push(entry);
+            }
+            None => {
+                let value = compute()?;
+                if self.entries.len() >= self.capacity {
+                    self.entries.remove(0);
+                }
+                self.entries.push((key, value));
+            }
+        }
+        self.entries.last().map(|(_, v)| v)
+    }
+}
+
+/// The pre-processed picture: a loaded input, transformed.
+#[derive(Clone, Copy, PartialEq)]
+struct SourceKey {
+    revision: u64,
+    transform: Transform,
+}
+
+/// Which palette a cache entry holds.
+#[derive(Clone, PartialEq)]
+struct PaletteKey {
+    source: SourceKey,
+    params: KmeansParams,
+    preview: bool,
+    weights: Option<(WeightParams, Option<u64>)>,
+    pinned: Vec<[u8; 3]>,
+}
+
+/// Which superpixels a cache entry holds.
+#[derive(Clone, PartialEq)]
+struct SuperpixelKey {
+    source: SourceKey,
+    pixel_size: usize,
+    k: usize,
+    pinned: Vec<[u8; 3]>,
+}
+
+/// Everything besides the picture that goes into finding a palette.
+#[derive(Clone, Copy)]
+pub struct PaletteParams<'a> {
+    pub kmeans: KmeansParams,
+    pub weights: Weights<'a>,
+    /// Colors always in the palette, k-means picks the other k - N.
+    pub pinned: &'a [[u8; 3]],
+}
+
+/// The pre-processed picture. Pictures that aren't transformed are used as
+/// they are instead of being copied.
+struct Preprocessed {
+    key: SourceKey,
+    transformed: Option<DynamicImage>,
+}
+
+impl Preprocessed {
+    fn image<'a>(&'a self, source: &'a DynamicImage) -> &'a DynamicImage {
+        self.transformed.as_ref().unwrap_or(source)
+    }
+}
+
+/// The stages of turning a picture into pixel art, each kept until its
+/// inputs change: the decoded picture (identified by its input revision),
+/// the pre-processed picture (cropped, rotated...), block averages per pixel size and palettes
+/// per k-means settings. Changing only the pixel size reuses the palette,
+/// changing only the k-means settings reuses the block averages.
+pub struct Pipeline {
+    preprocessed: Option<Preprocessed>,
+    block_averages: Memo<(SourceKey, usize, [u32; 2]), RgbImage>,
+    palettes: Memo<PaletteKey, Palette>,
+    superpixels: Memo<SuperpixelKey, (RgbImage, Palette)>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            preprocessed: None,
+            block_averages: Memo::new(MAX_BLOCK_AVERAGES),
+            palettes: Memo::new(MAX_PALETTES),
+            superpixels: Memo::new(MAX_SUPERPIXELS),
+        }
+    }
+}
+
+impl Pipeline {
+    /// The picture the later stages work on.
+    pub fn preprocessed<'a>(
+        &'a mut self,
+        revision: u64,
+        source: &'a DynamicImage,
+        transform: &Transform,
+    ) -> &'a DynamicImage {
+        let key = SourceKey {
+            revision,
+            transform: *transform,
+        };
+        let stale = !matches!(&self.preprocessed, Some(stage) if stage.key == key);
+        if stale {
+            self.preprocessed = Some(Preprocessed {
+                key,
+                transformed: (!transform.is_identity()).then(|| transform.apply(source)),
+            });
+        }
+        self.preprocessed.as_ref().unwrap().image(source)
+    }
+
+    /// Averages of the pre-processed picture's blocks with the grid starting
+    /// at `offset`, `None` if the pixel size is too large for it.
+    pub fn block_averages(
+        &mut self,
+        revision: u64,
+        source: &DynamicImage,
+        transform: &Transform,
+        pixel_size: usize,
+        offset: [u32; 2],
+    ) -> Option<&RgbImage> {
+        self.preprocessed(revision, source, transform);
+        let stage = self.preprocessed.as_ref()?;
+        let image = stage.image(source);
+        self.block_averages
+            .get_or_insert_with((stage.key, pixel_size, offset), || {
+                let image = util::offset_grid(image, offset);
+                let size = util::calc_target_size(image.clone(), pixel_size)?;
+                Some(util::block_averages(&image, pixel_size, size))
+            })
+    }
+
+    /// The palette of the pre-processed picture, or of all `frames` when
+    /// the input is animated. The frames are expected transformed already.
+    /// Pixels of a still picture count by their weights.
+    pub fn palette(
+        &mut self,
+        revision: u64,
+        source: &DynamicImage,
+        transform: &Transform,
+        frames: Option<&[AnimationFrame]>,
+        params: PaletteParams<'_>,
+    ) -> Option<&Palette> {
+        self.preprocessed(revision, source, transform);
+        let stage = self.preprocessed.as_ref()?;
+        let image = stage.image(source);
+        let PaletteParams {
+            kmeans,
+            weights,
+            pinned,
+        } = params;
+        let key = PaletteKey {
+            source: stage.key,
+            params: kmeans,
+            preview: false,
+            weights: weights.key().filter(|_| frames.is_none()),
+            pinned: pinned.to_vec(),
+        };
+        self.palettes.get_or_insert_with(key, || match frames {
+            Some(frames) => animation::calculate_shared_kmeans(frames, kmeans, pinned),
+            None => match weights.map(image, transform.crop) {
+                Some(map) => weights::weighted_kmeans(image, &map, kmeans, pinned),
+                None => weights::pinned_kmeans(image.clone(), kmeans, pinned),
+            },
+        })
+    }
+
+    /// A palette good enough for previews: a single k-means run over at most
+    /// `max_pixels` sampled pixels.
+    pub fn preview_palette(
+        &mut self,
+        revision: u64,
+        source: &DynamicImage,
+        transform: &Transform,
+        params: PaletteParams<'_>,
+        max_pixels: u32,
+    ) -> Option<&Palette> {
+        self.preprocessed(revision, source, transform);
+        let stage = self.preprocessed.as_ref()?;
+        let image = stage.image(source);
+        let PaletteParams {
+            kmeans,
+            weights,
+            pinned,
+        } = params;
+        let key = PaletteKey {
+            source: stage.key,
+            params: kmeans,
+            preview: true,
+            weights: weights.key(),
+            pinned: pinned.to_vec(),
+        };
+        self.palettes.get_or_insert_with(key, || {
+            let sample = util::subsample(image, max_pixels);
+            let kmeans = KmeansParams { run: 1, ..kmeans };
+            match weights.map(&sample, transform.crop) {
+                Some(map) => weights::weighted_kmeans(&sample, &map, kmeans, pinned),
+                None => weights::pinned_kmeans(sample, kmeans, pinned),
+            }
+        })
+    }
+
+    /// Superpixel colors on the output grid and the palette found with
+    /// them, with the `pinned` colors first. `None` if the pixel size is too
+    /// large for the picture.
+    pub fn superpixels(
+        &mut self,
+        revision: u64,
+        source: &DynamicImage,
+        transform: &Transform,
+        pixel_size: usize,
+        k: usize,
+        pinned: &[[u8; 3]],
+    ) -> Option<&(RgbImage, Palette)> {
+        self.preprocessed(revision, source, transform);
+        let stage = self.preprocessed.as_ref()?;
+        let image = stage.image(source);
+        let key = SuperpixelKey {
+            source: stage.key,
+            pixel_size,
+            k,
+            pinned: pinned.to_vec(),
+        };
+        self.superpixels
+            .get_or_insert_with(key, || slic::superpixels(image, pixel_size, k, pinned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const KMEANS: KmeansParams = KmeansParams {
+        k: 2,
+        run: 1,
+        max_iter: 10,
+        converge: 1.0,
+        verbose: false,
+        seed: 0,
+    };
+
+    fn picture() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }))
+    }
+
+    fn params(k: usize) -> PaletteParams<'static> {
+        PaletteParams {
+            kmeans: KmeansParams { k, ..KMEANS },
+            weights: Weights {
+                params: WeightParams::default(),
+                mask: None,
+            },
+            pinned: &[],
+        }
+    }
+
+    #[test]
+    fn memo_drops_the_least_recently_used() {
+        let mut memo = Memo::new(2);
+        assert_eq!(memo.get_or_insert_with(1, || Some("one")), Some(&"one"));
+        memo.get_or_insert_with(2, || Some("two"));
+        // A hit doesn't compute, and makes 1 the most recently used.
+        assert_eq!(
+            memo.get_or_insert_with(1, || unreachable!("cached")),
+            Some(&"one")
+        );
+        memo.get_or_insert_with(3, || Some("three"));
+        let keys: Vec<i32> = memo.entries.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, [1, 3]);
+        // Failures aren't kept.
+        assert_eq!(memo.get_or_insert_with(4, || None), None);
+        assert_eq!(memo.entries.len(), 2);
+    }
+
+    #[test]
+    fn stages_are_reused_across_unrelated_changes() {
+        let source = picture();
+        let transform = Transform::default();
+        let mut pipeline = Pipeline::default();
+        pipeline.palette(1, &source, &transform, None, params(2));
+        pipeline.block_averages(1, &source, &transform, 2, [0, 0]);
+
+        // Only k changes: the block averages stay.
+        pipeline.palette(1, &source, &transform, None, params(3));
+        pipeline.block_averages(1, &source, &transform, 2, [0, 0]);
+        assert_eq!(pipeline.palettes.entries.len(), 2);
+        assert_eq!(pipeline.block_averages.entries.len(), 1);
+
+        // Only the pixel size changes: the palette stays.
+        pipeline.palette(1, &source, &transform, None, params(3));
+        pipeline.block_averages(1, &source, &transform, 4, [0, 0]);
+        assert_eq!(pipeline.palettes.entries.len(), 2);
+        assert_eq!(pipeline.block_averages.entries.len(), 2);
+
+        // A new input starts over.
+        pipeline.palette(2, &source, &transform, None, params(3));
+        assert_eq!(pipeline.palettes.entries.len(), 3);
+    }
+}
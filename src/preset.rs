@@ -0,0 +1,136 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io;
+
+use crate::cleanup::CleanupParams;
+use crate::export::ExportOptions;
+use crate::hardware::HardwareParams;
+use crate::slic::Pixelization;
+use crate::util::{KmeansParams, PaletteSort};
+use crate::weights::WeightParams;
+
+/// The pipeline settings kept by presets and projects. Settings left out
+/// keep their current value when applied, so files written before an option
+/// existed still load.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PipelineSettings {
+    pub pixel_size: Option<usize>,
+    pub grid_offset: Option<[u32; 2]>,
+    pub pixelization: Option<Pixelization>,
+    pub kmeans_params: Option<KmeansParams>,
+    pub weight_params: Option<WeightParams>,
+    pub pinned_colors: Option<Vec<[u8; 3]>>,
+    pub dither: Option<f32>,
+    pub cleanup_params: Option<CleanupParams>,
+    pub hardware_params: Option<HardwareParams>,
+    pub palette_sort: Option<PaletteSort>,
+    pub export_options: Option<ExportOptions>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Preset {
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: PipelineSettings,
+}
+
+/// A preset file holds either a list of presets or a single one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PresetFile {
+    Many(Vec<Preset>),
+    One(Box<Preset>),
+}
+
+pub fn presets_to_json(presets: &[Preset]) -> io::Result<String> {
+    serde_json::to_string_pretty(presets).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
+
+pub fn presets_from_json(bytes: &[u8]) -> io::Result<Vec<Preset>> {
+    let file: PresetFile = serde_json::from_slice(bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(match file {
+        PresetFile::Many(presets) => presets,
+        PresetFile::One(preset) => vec![*preset],
+    })
+}
+
+/// Serde helpers for keeping presets in the app state. eframe stores it as
+/// RON, which can't read the flattened settings back, so there each preset
+/// is a name and settings pair.
+pub mod stored {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(presets: &[Preset], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(presets.iter().map(|p| (&p.name, &p.settings)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Preset>, D::Error> {
+        let pairs = Vec::<(String, PipelineSettings)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(name, settings)| Preset { name, settings })
+            .collect())
+    }
+}
+
+/// Looks a preset up by name, ignoring case.
+pub fn find<'a>(presets: &'a [Preset], name: &str) -> Option<&'a Preset> {
+    presets
+        .iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+/// Adds the presets, replacing the ones with the same name.
+pub fn merge(presets: &mut Vec<Preset>, new: Vec<Preset>) {
+    for preset in new {
+        match presets
+            .iter_mut()
+            .find(|p| p.name.eq_ignore_ascii_case(&preset.name))
+        {
+            Some(existing) => *existing = preset,
+            None => presets.push(preset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<Preset> {
+        vec![Preset {
+            name: "Tiny".to_string(),
+            settings: PipelineSettings {
+                pixel_size: Some(16),
+                pinned_colors: Some(vec![[0, 0, 0]]),
+                ..Default::default()
+            },
+        }]
+    }
+
+    #[test]
+    fn json_presets_round_trip() {
+        let json = presets_to_json(&sample()).unwrap();
+        assert!(json.contains("\"pixel_size\": 16"));
+        assert_eq!(presets_from_json(json.as_bytes()).unwrap(), sample());
+        let single = r#"{"name": "One", "pixel_size": 4}"#;
+        assert_eq!(presets_from_json(single.as_bytes()).unwrap()[0].name, "One");
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn stored_presets_round_trip_through_ron() {
+        #[derive(Deserialize, Serialize)]
+        struct State {
+            #[serde(with = "stored")]
+            presets: Vec<Preset>,
+        }
+        let text = ron::to_string(&State { presets: sample() }).unwrap();
+        let state: State = ron::from_str(&text).unwrap();
+        assert_eq!(state.presets, sample());
+    }
+}
@@ -0,0 +1,108 @@
+use egui::color::Color32;
+use image::{DynamicImage, ImageOutputFormat};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor};
+
+use crate::animation::AnimationFrame;
+use crate::preset::PipelineSettings;
+use crate::transform::Transform;
+
+/// File extension of project files.
+pub const PROJECT_EXTENSION: &str = "pixelite";
+
+/// Bumped whenever a change to the format can't be read by older versions.
+const PROJECT_VERSION: u32 = 1;
+
+/// Everything needed to pick up work where it was left: the source picture
+/// as it was loaded, every pipeline setting, the palette and the output.
+/// Stored as JSON with the pictures embedded as base64.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Project {
+    pub version: u32,
+    /// File name of the source picture, a hint for formats without a
+    /// signature.
+    pub source_name: Option<String>,
+    #[serde(with = "base64_bytes")]
+    pub source: Vec<u8>,
+
+    #[serde(flatten)]
+    pub settings: PipelineSettings,
+    /// Crop and rotation of the source.
+    pub transform: Transform,
+
+    pub palette: Option<Vec<PaletteEntry>>,
+    pub sub_palettes: Option<Vec<(Vec<usize>, usize)>>,
+    pub output: Option<ProjectImage>,
+    pub output_frames: Option<Vec<ProjectImage>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PaletteEntry {
+    pub rgb: [u8; 3],
+    pub coverage: f32,
+}
+
+impl PaletteEntry {
+    pub fn color(&self) -> Color32 {
+        Color32::from_rgb(self.rgb[0], self.rgb[1], self.rgb[2])
+    }
+}
+
+/// A PNG encoded picture, with its delay when it's an animation frame.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProjectImage {
+    #[serde(with = "base64_bytes")]
+    pub png: Vec<u8>,
+    pub delay_ms: u32,
+}
+
+impl ProjectImage {
+    pub fn new(image: &DynamicImage, delay_ms: u32) -> io::Result<Self> {
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(Self { png, delay_ms })
+    }
+
+    pub fn decode(&self) -> Option<AnimationFrame> {
+        Some(AnimationFrame {
+            image: image::load_from_memory(&self.png).ok()?,
+            delay_ms: self.delay_ms,
+        })
+    }
+}
+
+impl Project {
+    pub fn into_bytes(mut self) -> io::Result<Vec<u8>> {
+        self.version = PROJECT_VERSION;
+        serde_json::to_vec_pretty(&self).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Project> {
+        let project: Project = serde_json::from_slice(bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if project.version > PROJECT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The project was saved by a newer version",
+            ));
+        }
+        Ok(project)
+    }
+}
+
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        base64::decode(text).map_err(serde::de::Error::custom)
+    }
+}
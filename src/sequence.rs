@@ -0,0 +1,301 @@
+use egui::color::Color32;
+use image::DynamicImage;
+use palette::{FromColor, Lab, Srgb};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::animation::{self, AnimationFrame};
+use crate::cleanup::{self, CleanupParams};
+use crate::export::{self, ExportOptions, OutputFormat};
+use crate::input;
+use crate::util::{self, KmeansParams};
+
+/// At most this many frames are decoded for the global palette.
+const MAX_PALETTE_FRAMES: usize = 16;
+
+/// Finds the frames of a sequence. `path` may be a folder, a numbered
+/// pattern like `frame_####.png` or `frame_*.png`, or any one frame of the
+/// sequence. Frames come back in numeric order.
+pub fn list_sequence(path: &Path) -> Vec<PathBuf> {
+    let (dir, prefix, suffix) = if path.is_dir() {
+        (path.to_path_buf(), String::new(), String::new())
+    } else {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        match split_pattern(name) {
+            Some((prefix, suffix)) => (dir, prefix, suffix),
+            None if path.is_file() => return vec![path.to_path_buf()],
+            None => return Vec::new(),
+        }
+    };
+
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut frames: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_picture(path))
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let middle = name.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+            if prefix.is_empty() && suffix.is_empty() {
+                // Any picture in a folder, sorted by its trailing number.
+                let stem = path.file_stem()?.to_str()?;
+                let number = trailing_digits(stem).parse().unwrap_or(0);
+                return Some((number, path.clone()));
+            }
+            if middle.is_empty() || !middle.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            Some((middle.parse().ok()?, path.clone()))
+        })
+        .collect();
+    frames.sort();
+    frames.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Splits a frame name around its number: `frame_####.png`, `frame_*.png`
+/// and `frame_0001.png` all give `("frame_", ".png")`.
+fn split_pattern(name: &str) -> Option<(String, String)> {
+    if let Some(start) = name.find(['#', '*']) {
+        let end = name[start..]
+            .find(|c| c != '#' && c != '*')
+            .map_or(name.len(), |i| start + i);
+        return Some((name[..start].to_string(), name[end..].to_string()));
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) => name.split_at(dot),
+        None => (name, ""),
+    };
+    let digits = trailing_digits(stem);
+    if digits.is_empty() {
+        return None;
+    }
+    let prefix = &stem[..stem.len() - digits.len()];
+    Some((prefix.to_string(), extension.to_string()))
+}
+
+fn trailing_digits(stem: &str) -> &str {
+    let start = stem
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    &stem[start..]
+}
+
+fn is_picture(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| {
+            input::INPUT_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str())
+        })
+}
+
+fn load_frame(path: &Path) -> io::Result<DynamicImage> {
+    let bytes = std::fs::read(path)?;
+    input::load_image(&bytes, path.to_str()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Can't decode {}", path.display()),
+        )
+    })
+}
+
+/// One palette for the whole sequence, from evenly spaced frames, so colors
+/// don't flicker between frames.
+pub fn sequence_palette(
+    frames: &[PathBuf],
+    params: KmeansParams,
+    pinned: &[[u8; 3]],
+) -> io::Result<(Vec<Color32>, Vec<Lab>, Vec<f32>)> {
+    let step = ((frames.len() + MAX_PALETTE_FRAMES - 1) / MAX_PALETTE_FRAMES).max(1);
+    let sampled = frames
+        .iter()
+        .step_by(step)
+        .map(|path| {
+            Ok(AnimationFrame {
+                image: load_frame(path)?,
+                delay_ms: 0,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    animation::calculate_shared_kmeans(&sampled, params, pinned)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Sequence has no frames"))
+}
+
+/// Name of the sheet written when a sequence is exported as a sprite sheet.
+const SHEET_NAME: &str = "spritesheet";
+
+/// Fails if `out_dir` holds any of the frames, as writing the outputs there
+/// could overwrite them.
+pub fn check_out_dir(frames: &[PathBuf], out_dir: &Path) -> io::Result<()> {
+    let out_dir = out_dir.canonicalize()?;
+    let holds_frames = frames.iter().any(|frame| {
+        frame
+            .parent()
+            .and_then(|dir| dir.canonicalize().ok())
+            .map_or(false, |dir| dir == out_dir)
+    });
+    if holds_frames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Pick another folder than the sequence's, its frames would be overwritten",
+        ));
+    }
+    Ok(())
+}
+
+/// Pixelizes every frame with the same settings and writes it to `out_dir`
+/// under its own name, in the export format, or all of them packed into one
+/// sprite sheet. `colors` is the palette in the order indexed formats should
+/// store it. Fails before writing anything if `out_dir` is the sequence's
+/// own folder. Returns the number of frames.
+pub fn pixelize_sequence(
+    frames: &[PathBuf],
+    out_dir: &Path,
+    pixel_size: usize,
+    colors: &[Lab],
+    dither: f32,
+    cleanup_params: CleanupParams,
+    options: &ExportOptions,
+) -> io::Result<usize> {
+    check_out_dir(frames, out_dir)?;
+    let palette: Vec<Color32> = colors
+        .iter()
+        .map(|c| {
+            let rgb: Srgb<u8> = Srgb::from_color(*c).into_format();
+            Color32::from_rgb(rgb.red, rgb.green, rgb.blue)
+        })
+        .collect();
+    let extension = options.format.extensions()[0];
+    let mut sheet_frames = Vec::new();
+    let mut sheet_names = Vec::new();
+
+    for path in frames {
+        let image = load_frame(path)?;
+        let size = util::calc_target_size(image.clone(), pixel_size).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Pixel size is too large")
+        })?;
+        let output = util::generate_image(image, pixel_size, size, colors.to_vec(), dither);
+        let output = if cleanup_params.is_enabled() {
+            cleanup::cleanup_image(output, cleanup_params)
+        } else {
+            output
+        };
+
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if options.format == OutputFormat::SpriteSheet {
+            sheet_frames.push(AnimationFrame {
+                image: output,
+                delay_ms: 0,
+            });
+            sheet_names.push(stem.to_string());
+            continue;
+        }
+        let bytes = export::encode_output(&output, None, &palette, options)?;
+        std::fs::write(out_dir.join(format!("{}.{}", stem, extension)), bytes)?;
+    }
+
+    if options.format == OutputFormat::SpriteSheet {
+        let image_name = format!("{}.{}", SHEET_NAME, extension);
+        let (sheet, atlas) = export::encode_sprite_sheet(
+            &sheet_frames,
+            &sheet_names,
+            &palette,
+            options,
+            &image_name,
+        )?;
+        std::fs::write(out_dir.join(&image_name), sheet)?;
+        std::fs::write(out_dir.join(format!("{}.json", SHEET_NAME)), atlas)?;
+    }
+    Ok(frames.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder holding empty files with these names.
+    fn folder(test: &str, names: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pixelite-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in names {
+            std::fs::write(dir.join(name), []).unwrap();
+        }
+        dir
+    }
+
+    fn names(frames: &[PathBuf]) -> Vec<String> {
+        frames
+            .iter()
+            .map(|f| f.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn patterns_split_around_the_number() {
+        let split = |name| split_pattern(name).unwrap();
+        let expected = ("frame_".to_string(), ".png".to_string());
+        assert_eq!(split("frame_####.png"), expected);
+        assert_eq!(split("frame_*.png"), expected);
+        assert_eq!(split("frame_0001.png"), expected);
+        assert_eq!(split("frame_12.png"), expected);
+        assert_eq!(split("0007.png"), (String::new(), ".png".to_string()));
+        assert_eq!(split("walk3"), ("walk".to_string(), String::new()));
+        assert_eq!(split_pattern("title.png"), None);
+        assert_eq!(split_pattern("v2_title.png"), None);
+    }
+
+    #[test]
+    fn frames_come_in_numeric_order() {
+        let dir = folder(
+            "numeric",
+            &[
+                "frame_10.png",
+                "frame_9.png",
+                "frame_0011.png",
+                "frame_x.png",
+                "frame_.png",
+                "other_1.png",
+                "frame_3.txt",
+            ],
+        );
+        let expected = ["frame_9.png", "frame_10.png", "frame_0011.png"];
+        assert_eq!(names(&list_sequence(&dir.join("frame_####.png"))), expected);
+        assert_eq!(names(&list_sequence(&dir.join("frame_*.png"))), expected);
+        assert_eq!(names(&list_sequence(&dir.join("frame_9.png"))), expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn folders_give_all_their_pictures() {
+        let dir = folder("folder", &["b2.png", "a10.jpg", "c1.PNG", "notes.txt"]);
+        assert_eq!(names(&list_sequence(&dir)), ["c1.PNG", "b2.png", "a10.jpg"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn single_pictures_and_missing_files() {
+        let dir = folder("single", &["title.png"]);
+        assert_eq!(names(&list_sequence(&dir.join("title.png"))), ["title.png"]);
+        assert!(list_sequence(&dir.join("missing.png")).is_empty());
+        assert!(list_sequence(&dir.join("missing_####.png")).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn outputs_are_not_written_over_the_frames() {
+        let dir = folder("out-dir", &["frame_1.png"]);
+        let out = dir.join("out");
+        std::fs::create_dir(&out).unwrap();
+        let frames = list_sequence(&dir);
+        let err = check_out_dir(&frames, &dir.join(".")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(check_out_dir(&frames, &out).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
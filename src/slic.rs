@@ -0,0 +1,512 @@
+use egui::color::Color32;
+use image::{DynamicImage, Rgb, RgbImage};
+use palette::{FromColor, IntoColor, Lab, Srgb};
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::Palette;
+use crate::util;
+
+/// Superpixels are found in a sample of at most this many input pixels per
+/// output pixel.
+const SAMPLES_PER_PIXEL: u32 = 16;
+
+/// Weight of the distance to a superpixel's center against the color
+/// difference. Higher values keep superpixels closer to squares.
+const COMPACTNESS: f32 = 45.0;
+
+/// How far each superpixel center moves towards its neighbours' after every
+/// step, so the grid stays regular.
+const SMOOTHING: f32 = 0.4;
+
+/// The temperature is multiplied by this once the palette has settled.
+const COOLING: f32 = 0.7;
+
+/// Annealing stops once the palette settles below this temperature.
+const FINAL_TEMPERATURE: f32 = 1.0;
+
+/// The palette has settled when its colors moved less than this in total.
+const PALETTE_CONVERGE: f32 = 0.05;
+
+/// Twin colors this far apart have split into two colors.
+const SPLIT_DISTANCE: f32 = 1.6;
+
+/// How far a new twin starts from its color.
+const PERTURBATION: f32 = 0.8;
+
+const MAX_ITERATIONS: usize = 200;
+
+/// How the input is divided into output pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Pixelization {
+    /// Averages of a uniform grid of blocks, with a k-means palette.
+    Blocks,
+    /// Superpixels following the picture's edges, found together with the
+    /// palette.
+    Superpixels,
+}
+
+impl Pixelization {
+    pub const ALL: [Pixelization; 2] = [Pixelization::Blocks, Pixelization::Superpixels];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pixelization::Blocks => "Grid blocks",
+            Pixelization::Superpixels => "Superpixels (experimental)",
+        }
+    }
+}
+
+type Color = [f32; 3];
+
+struct Superpixel {
+    x: f32,
+    y: f32,
+    color: Color,
+}
+
+/// A palette color. Until the palette is full each color has a twin that
+/// starts next to it, and the two become separate colors once annealing
+/// pulls them apart. Pinned colors never move and have no twin.
+struct Cluster {
+    color: Color,
+    weight: f32,
+    twin: Option<usize>,
+    pinned: bool,
+}
+
+/// Pixelization after Gerstner et al., "Pixelated Image Abstraction": one
+/// SLIC superpixel per output pixel, refined in turns with a palette found
+/// by deterministic annealing that grows to `k` colors, the `pinned` ones
+/// among them. Returns the mean color of each superpixel in its place on
+/// the output grid, and the palette with the pinned colors first. `None` if
+/// the picture is too small for the pixel size.
+pub fn superpixels(
+    image: &DynamicImage,
+    pixel_size: usize,
+    k: usize,
+    pinned: &[[u8; 3]],
+) -> Option<(RgbImage, Palette)> {
+    let size = util::calc_target_size(image.clone(), pixel_size)?;
+    let (width, height) = (size.x as usize, size.y as usize);
+    let sample = util::subsample(image, (width * height) as u32 * SAMPLES_PER_PIXEL).into_rgb8();
+    let (sample_width, sample_height) = (sample.width() as usize, sample.height() as usize);
+    let pixels: Vec<Color> = sample.pixels().map(|p| to_lab(p.0)).collect();
+
+    let step_x = sample_width as f32 / width as f32;
+    let step_y = sample_height as f32 / height as f32;
+    let step = (step_x * step_y).sqrt();
+    let mut superpixels: Vec<Superpixel> = (0..width * height)
+        .map(|i| {
+            let x = ((i % width) as f32 + 0.5) * step_x;
+            let y = ((i / width) as f32 + 0.5) * step_y;
+            let color = pixels[(y as usize).min(sample_height - 1) * sample_width
+                + (x as usize).min(sample_width - 1)];
+            Superpixel { x, y, color }
+        })
+        .collect();
+
+    // Above the critical temperature of the whole picture everything is
+    // one color, besides the pinned ones.
+    let (mean, axis, variance) = principal_axis(&pixels);
+    let mut temperature = 2.2 * variance.max(FINAL_TEMPERATURE);
+    let free = if pinned.len() < k { 2 } else { 0 };
+    let weight = 1.0 / (pinned.len() + free) as f32;
+    let mut clusters: Vec<Cluster> = pinned
+        .iter()
+        .map(|rgb| Cluster {
+            color: to_lab(*rgb),
+            weight,
+            twin: None,
+            pinned: true,
+        })
+        .collect();
+    if free > 0 {
+        let first = clusters.len();
+        clusters.push(Cluster {
+            color: mean,
+            weight,
+            twin: Some(first + 1),
+            pinned: false,
+        });
+        clusters.push(Cluster {
+            color: add(mean, scale(axis, PERTURBATION)),
+            weight,
+            twin: Some(first),
+            pinned: false,
+        });
+    }
+
+    let mut labels = vec![0; pixels.len()];
+    for _ in 0..MAX_ITERATIONS {
+        assign_pixels(
+            &pixels,
+            sample_width,
+            &superpixels,
+            (width, height),
+            (step_x, step_y),
+            step,
+            &mut labels,
+        );
+        update_superpixels(&pixels, sample_width, &labels, &mut superpixels);
+        smooth_positions(&mut superpixels, width, height);
+
+        let change = refine_palette(&superpixels, &mut clusters, temperature);
+        if change < PALETTE_CONVERGE {
+            if temperature <= FINAL_TEMPERATURE {
+                break;
+            }
+            temperature *= COOLING;
+            expand_palette(&mut clusters, &superpixels, k);
+        }
+    }
+    merge_twins(&mut clusters);
+
+    let colors: Vec<Color> = clusters.iter().map(|c| c.color).collect();
+    let mut coverage = vec![0.0; colors.len()];
+    let mut means = RgbImage::new(width as u32, height as u32);
+    for (i, superpixel) in superpixels.iter().enumerate() {
+        coverage[nearest(&colors, superpixel.color)] += 1.0 / superpixels.len() as f32;
+        means.put_pixel(
+            (i % width) as u32,
+            (i / width) as u32,
+            Rgb(to_rgb(superpixel.color)),
+        );
+    }
+    let palette = (
+        colors
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                // Pinned colors exactly as given, not round-tripped through Lab.
+                let [r, g, b] = pinned.get(i).copied().unwrap_or_else(|| to_rgb(*c));
+                Color32::from_rgb(r, g, b)
+            })
+            .collect(),
+        colors.iter().map(|c| Lab::new(c[0], c[1], c[2])).collect(),
+        coverage,
+    );
+    Some((means, palette))
+}
+
+/// Gives each pixel to the closest superpixel among those of its own and
+/// the neighbouring grid cells.
+fn assign_pixels(
+    pixels: &[Color],
+    sample_width: usize,
+    superpixels: &[Superpixel],
+    (width, height): (usize, usize),
+    (step_x, step_y): (f32, f32),
+    step: f32,
+    labels: &mut [usize],
+) {
+    for (i, pixel) in pixels.iter().enumerate() {
+        let (x, y) = (
+            (i % sample_width) as f32 + 0.5,
+            (i / sample_width) as f32 + 0.5,
+        );
+        let cell_x = ((x / step_x) as usize).min(width - 1);
+        let cell_y = ((y / step_y) as usize).min(height - 1);
+        let mut best = (f32::MAX, labels[i]);
+        for gy in cell_y.saturating_sub(1)..=(cell_y + 1).min(height - 1) {
+            for gx in cell_x.saturating_sub(1)..=(cell_x + 1).min(width - 1) {
+                let s = &superpixels[gy * width + gx];
+                let spatial = ((x - s.x).powi(2) + (y - s.y).powi(2)).sqrt();
+                let d = distance(*pixel, s.color).sqrt() + COMPACTNESS * spatial / step;
+                if d < best.0 {
+                    best = (d, gy * width + gx);
+                }
+            }
+        }
+        labels[i] = best.1;
+    }
+}
+
+/// Moves each superpixel to the center of its pixels, with their mean
+/// color. Superpixels without pixels stay as they are.
+fn update_superpixels(
+    pixels: &[Color],
+    sample_width: usize,
+    labels: &[usize],
+    superpixels: &mut [Superpixel],
+) {
+    let mut sums = vec![(0.0, 0.0, [0.0; 3], 0.0); superpixels.len()];
+    for (i, (pixel, label)) in pixels.iter().zip(labels).enumerate() {
+        let sum = &mut sums[*label];
+        sum.0 += (i % sample_width) as f32 + 0.5;
+        sum.1 += (i / sample_width) as f32 + 0.5;
+        sum.2 = add(sum.2, *pixel);
+        sum.3 += 1.0;
+    }
+    for (superpixel, (x, y, color, count)) in superpixels.iter_mut().zip(sums) {
+        if count > 0.0 {
+            superpixel.x = x / count;
+            superpixel.y = y / count;
+            superpixel.color = scale(color, 1.0 / count);
+        }
+    }
+}
+
+/// Laplacian smoothing of the superpixel centers over the output grid.
+fn smooth_positions(superpixels: &mut [Superpixel], width: usize, height: usize) {
+    let positions: Vec<(f32, f32)> = superpixels.iter().map(|s| (s.x, s.y)).collect();
+    for (i, superpixel) in superpixels.iter_mut().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let mut neighbours = Vec::with_capacity(4);
+        if x > 0 {
+            neighbours.push(positions[i - 1]);
+        }
+        if x + 1 < width {
+            neighbours.push(positions[i + 1]);
+        }
+        if y > 0 {
+            neighbours.push(positions[i - width]);
+        }
+        if y + 1 < height {
+            neighbours.push(positions[i + width]);
+        }
+        if neighbours.is_empty() {
+            continue;
+        }
+        let count = neighbours.len() as f32;
+        let mean_x = neighbours.iter().map(|p| p.0).sum::<f32>() / count;
+        let mean_y = neighbours.iter().map(|p| p.1).sum::<f32>() / count;
+        superpixel.x += (mean_x - superpixel.x) * SMOOTHING;
+        superpixel.y += (mean_y - superpixel.y) * SMOOTHING;
+    }
+}
+
+/// One step of mass-constrained deterministic annealing: each superpixel
+/// belongs to every color with a probability falling off with distance at
+/// the temperature, and each color moves to the weighted mean of its
+/// superpixels. Pinned colors keep their place but still take their share.
+/// Returns how far the colors moved in total.
+fn refine_palette(superpixels: &[Superpixel], clusters: &mut [Cluster], temperature: f32) -> f32 {
+    let share = 1.0 / superpixels.len() as f32;
+    let mut weights = vec![0.0; clusters.len()];
+    let mut colors = vec![[0.0; 3]; clusters.len()];
+    let mut probabilities = vec![0.0; clusters.len()];
+    for superpixel in superpixels {
+        let distances: Vec<f32> = clusters
+            .iter()
+            .map(|c| distance(superpixel.color, c.color))
+            .collect();
+        // Relative to the closest color, so the exponentials don't vanish.
+        let closest = distances.iter().copied().fold(f32::MAX, f32::min);
+        for ((p, cluster), d) in probabilities
+            .iter_mut()
+            .zip(clusters.iter())
+            .zip(&distances)
+        {
+            *p = cluster.weight * (-(d - closest) / temperature).exp();
+        }
+        let total: f32 = probabilities.iter().sum();
+        if total <= 0.0 {
+            continue;
+        }
+        for (i, p) in probabilities.iter().enumerate() {
+            let p = p / total * share;
+            weights[i] += p;
+            colors[i] = add(colors[i], scale(superpixel.color, p));
+        }
+    }
+    let mut change = 0.0;
+    for ((cluster, weight), color) in clusters.iter_mut().zip(weights).zip(colors) {
+        if weight > 0.0 && !cluster.pinned {
+            let color = scale(color, 1.0 / weight);
+            change += distance(cluster.color, color).sqrt();
+            cluster.color = color;
+        }
+        cluster.weight = weight;
+    }
+    change
+}
+
+/// Twins that have drifted apart become separate colors with twins of
+/// their own, as long as the palette has room. Once the palette is full
+/// the twins are merged back. Twins are nudged along the direction their
+/// superpixels' colors vary most, where they split first.
+fn expand_palette(clusters: &mut Vec<Cluster>, superpixels: &[Superpixel], k: usize) {
+    for i in 0..clusters.len() {
+        let j = match clusters[i].twin {
+            Some(j) if j > i => j,
+            _ => continue,
+        };
+        if color_count(clusters) >= k {
+            break;
+        }
+        if distance(clusters[i].color, clusters[j].color) > SPLIT_DISTANCE.powi(2) {
+            for parent in [i, j] {
+                let axis = cluster_axis(clusters, superpixels, &[parent]);
+                clusters[parent].weight /= 2.0;
+                clusters[parent].twin = Some(clusters.len());
+                clusters.push(Cluster {
+                    color: add(clusters[parent].color, scale(axis, PERTURBATION)),
+                    weight: clusters[parent].weight,
+                    twin: Some(parent),
+                    pinned: false,
+                });
+            }
+        } else if distance(clusters[i].color, clusters[j].color) < (PERTURBATION / 2.0).powi(2) {
+            // Keep the twins apart, so they can split at a lower temperature.
+            let axis = cluster_axis(clusters, superpixels, &[i, j]);
+            clusters[j].color = add(clusters[i].color, scale(axis, PERTURBATION));
+        }
+    }
+    if color_count(clusters) >= k {
+        merge_twins(clusters);
+    }
+}
+
+/// The principal axis of the colors of the superpixels closest to any of
+/// the `members`.
+fn cluster_axis(clusters: &[Cluster], superpixels: &[Superpixel], members: &[usize]) -> Color {
+    let colors: Vec<Color> = clusters.iter().map(|c| c.color).collect();
+    let closest: Vec<Color> = superpixels
+        .iter()
+        .map(|s| s.color)
+        .filter(|c| members.contains(&nearest(&colors, *c)))
+        .collect();
+    principal_axis(&closest).1
+}
+
+/// Colors in the palette, counting each pair of twins once.
+fn color_count(clusters: &[Cluster]) -> usize {
+    clusters.len() - clusters.iter().filter(|c| c.twin.is_some()).count() / 2
+}
+
+fn merge_twins(clusters: &mut Vec<Cluster>) {
+    let mut merged: Vec<Cluster> = Vec::new();
+    let mut index = vec![usize::MAX; clusters.len()];
+    for (i, cluster) in clusters.iter().enumerate() {
+        match cluster.twin {
+            Some(j) if index[j] != usize::MAX => {
+                let kept = &mut merged[index[j]];
+                let weight = kept.weight + cluster.weight;
+                if weight > 0.0 {
+                    kept.color = scale(
+                        add(
+                            scale(kept.color, kept.weight),
+                            scale(cluster.color, cluster.weight),
+                        ),
+                        1.0 / weight,
+                    );
+                }
+                kept.weight = weight;
+            }
+            _ => {
+                index[i] = merged.len();
+                merged.push(Cluster {
+                    color: cluster.color,
+                    weight: cluster.weight,
+                    twin: None,
+                    pinned: cluster.pinned,
+                });
+            }
+        }
+    }
+    *clusters = merged;
+}
+
+/// The mean color, the direction the colors vary most along and the
+/// variance along it.
+fn principal_axis(pixels: &[Color]) -> (Color, Color, f32) {
+    let count = pixels.len().max(1) as f32;
+    let mean = scale(
+        pixels.iter().fold([0.0; 3], |sum, p| add(sum, *p)),
+        1.0 / count,
+    );
+    let mut covariance = [[0.0f32; 3]; 3];
+    for pixel in pixels {
+        let d = add(*pixel, scale(mean, -1.0));
+        for (row, dr) in covariance.iter_mut().zip(d) {
+            for (cell, dc) in row.iter_mut().zip(d) {
+                *cell += dr * dc / count;
+            }
+        }
+    }
+    // Power iteration.
+    let mut axis = [1.0, 1.0, 1.0];
+    let mut variance = 0.0;
+    for _ in 0..32 {
+        let next = covariance.map(|row| row[0] * axis[0] + row[1] * axis[1] + row[2] * axis[2]);
+        variance = (next[0].powi(2) + next[1].powi(2) + next[2].powi(2)).sqrt();
+        if variance == 0.0 {
+            return (mean, [1.0, 0.0, 0.0], 0.0);
+        }
+        axis = scale(next, 1.0 / variance);
+    }
+    (mean, axis, variance)
+}
+
+fn nearest(colors: &[Color], color: Color) -> usize {
+    colors
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i, distance(*c, color)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map_or(0, |(i, _)| i)
+}
+
+fn to_lab(rgb: [u8; 3]) -> Color {
+    let lab: Lab = Srgb::new(rgb[0], rgb[1], rgb[2])
+        .into_format::<f32>()
+        .into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+fn to_rgb(color: Color) -> [u8; 3] {
+    let rgb: Srgb<u8> = Srgb::from_color(Lab::new(color[0], color[1], color[2])).into_format();
+    [rgb.red, rgb.green, rgb.blue]
+}
+
+fn distance(a: Color, b: Color) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn add(a: Color, b: Color) -> Color {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn scale(a: Color, factor: f32) -> Color {
+    a.map(|c| c * factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red on the left, green on the right.
+    fn halves() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, _| {
+            if x < 16 {
+                Rgb([200, 30, 30])
+            } else {
+                Rgb([30, 200, 30])
+            }
+        }))
+    }
+
+    #[test]
+    fn pinned_colors_stay_first_and_unchanged() {
+        let pinned = [[0, 0, 255]];
+        let (means, (colors, lab, coverage)) = superpixels(&halves(), 4, 3, &pinned).unwrap();
+        assert_eq!((means.width(), means.height()), (8, 8));
+        assert_eq!(colors[0], Color32::from_rgb(0, 0, 255));
+        assert_eq!([lab[0].l, lab[0].a, lab[0].b], to_lab(pinned[0]));
+        assert!(colors.len() <= 3);
+        assert_eq!(colors.len(), coverage.len());
+        // Nothing in the picture is blue.
+        assert_eq!(coverage[0], 0.0);
+    }
+
+    #[test]
+    fn more_pins_than_colors_are_all_kept() {
+        let pinned = [[255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let (_, (colors, _, _)) = superpixels(&halves(), 4, 2, &pinned).unwrap();
+        let expected: Vec<Color32> = pinned
+            .iter()
+            .map(|[r, g, b]| Color32::from_rgb(*r, *g, *b))
+            .collect();
+        assert_eq!(colors, expected);
+    }
+}
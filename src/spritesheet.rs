@@ -0,0 +1,253 @@
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::collections::HashSet;
+
+/// Atlas in the TexturePacker JSON layout, which Aseprite, Phaser and most
+/// engines read. Durations are in milliseconds.
+#[derive(Serialize)]
+pub struct Atlas {
+    pub frames: AtlasFrames,
+    pub meta: AtlasMeta,
+}
+
+/// "JSON (Array)" lists the frames with their `filename`, "JSON (Hash)"
+/// keys them by name instead.
+pub enum AtlasFrames {
+    Array(Vec<AtlasFrame>),
+    Hash(Vec<AtlasFrame>),
+}
+
+impl Serialize for AtlasFrames {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            AtlasFrames::Array(frames) => frames.serialize(serializer),
+            AtlasFrames::Hash(frames) => {
+                // Written by hand to keep the frames in sheet order.
+                let mut map = serializer.serialize_map(Some(frames.len()))?;
+                for frame in frames {
+                    map.serialize_entry(&frame.filename, frame)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AtlasFrame {
+    pub filename: String,
+    pub frame: Rect,
+    pub rotated: bool,
+    pub trimmed: bool,
+    pub sprite_source_size: Rect,
+    pub source_size: Size,
+    pub duration: u32,
+}
+
+#[derive(Serialize)]
+pub struct AtlasMeta {
+    pub app: String,
+    pub version: String,
+    pub image: String,
+    pub format: String,
+    pub size: Size,
+    pub scale: String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+/// A named image to place on a sheet.
+pub struct Sprite<'a> {
+    pub name: String,
+    pub image: &'a DynamicImage,
+    pub duration_ms: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SheetLayout {
+    /// Sprites per row, 0 picks a roughly square sheet.
+    pub columns: u32,
+    /// Transparent pixels between sprites and around the sheet.
+    pub padding: u32,
+    pub hash_atlas: bool,
+}
+
+/// Lays the sprites out row by row in equally sized cells, as large as the
+/// largest sprite. `image_name` is the file name the atlas refers to. A
+/// sprite named like an earlier one gets its index appended, so every
+/// frame keeps its own key.
+pub fn pack(sprites: &[Sprite<'_>], image_name: &str, layout: SheetLayout) -> (RgbaImage, Atlas) {
+    let count = sprites.len() as u32;
+    let columns = match layout.columns {
+        0 => (count as f32).sqrt().ceil().max(1.0) as u32,
+        columns => columns.min(count.max(1)),
+    };
+    let rows = (count + columns - 1) / columns;
+    let cell_w = sprites.iter().map(|s| s.image.width()).max().unwrap_or(0);
+    let cell_h = sprites.iter().map(|s| s.image.height()).max().unwrap_or(0);
+    let padding = layout.padding;
+
+    let mut sheet = RgbaImage::new(
+        columns * (cell_w + padding) + padding,
+        rows * (cell_h + padding) + padding,
+    );
+    let mut frames = Vec::with_capacity(sprites.len());
+    let mut names = HashSet::new();
+
+    for (i, sprite) in sprites.iter().enumerate() {
+        let mut name = sprite.name.clone();
+        while !names.insert(name.clone()) {
+            name = format!("{}_{}", name, i);
+        }
+        let (w, h) = (sprite.image.width(), sprite.image.height());
+        let x = padding + (i as u32 % columns) * (cell_w + padding);
+        let y = padding + (i as u32 / columns) * (cell_h + padding);
+        imageops::replace(&mut sheet, &sprite.image.to_rgba8(), x as i64, y as i64);
+        frames.push(AtlasFrame {
+            filename: name,
+            frame: Rect { x, y, w, h },
+            rotated: false,
+            trimmed: false,
+            sprite_source_size: Rect { x: 0, y: 0, w, h },
+            source_size: Size { w, h },
+            duration: sprite.duration_ms,
+        });
+    }
+
+    let atlas = Atlas {
+        frames: if layout.hash_atlas {
+            AtlasFrames::Hash(frames)
+        } else {
+            AtlasFrames::Array(frames)
+        },
+        meta: AtlasMeta {
+            app: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image: image_name.to_string(),
+            format: "RGBA8888".to_string(),
+            size: Size {
+                w: sheet.width(),
+                h: sheet.height(),
+            },
+            scale: "1".to_string(),
+        },
+    };
+    (sheet, atlas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn block(w: u32, h: u32, shade: u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(w, h, Rgba([shade, 0, 0, 255])))
+    }
+
+    fn sprites<'a>(images: &'a [DynamicImage], names: &[&str]) -> Vec<Sprite<'a>> {
+        images
+            .iter()
+            .zip(names)
+            .enumerate()
+            .map(|(i, (image, name))| Sprite {
+                name: name.to_string(),
+                image,
+                duration_ms: 100 * (i as u32 + 1),
+            })
+            .collect()
+    }
+
+    fn frames(atlas: &Atlas) -> &[AtlasFrame] {
+        match &atlas.frames {
+            AtlasFrames::Array(frames) | AtlasFrames::Hash(frames) => frames,
+        }
+    }
+
+    #[test]
+    fn sprites_fill_padded_cells_row_by_row() {
+        let images = [block(4, 2, 10), block(2, 3, 20), block(3, 3, 30)];
+        let layout = SheetLayout {
+            columns: 0,
+            padding: 1,
+            hash_atlas: false,
+        };
+        let (sheet, atlas) = pack(&sprites(&images, &["a", "b", "c"]), "sheet.png", layout);
+        // Two columns of 4×3 cells, in two rows.
+        assert_eq!(sheet.dimensions(), (2 * 5 + 1, 2 * 4 + 1));
+        let origins: Vec<(u32, u32)> = frames(&atlas)
+            .iter()
+            .map(|f| (f.frame.x, f.frame.y))
+            .collect();
+        assert_eq!(origins, [(1, 1), (6, 1), (1, 5)]);
+        assert_eq!(frames(&atlas)[1].frame.w, 2);
+        assert_eq!(frames(&atlas)[2].duration, 300);
+        assert_eq!(sheet.get_pixel(6, 1), &Rgba([20, 0, 0, 255]));
+        // Padding and the unused part of a cell stay transparent.
+        assert_eq!(sheet.get_pixel(0, 0)[3], 0);
+        assert_eq!(sheet.get_pixel(8, 1)[3], 0);
+        assert_eq!((atlas.meta.size.w, atlas.meta.size.h), sheet.dimensions());
+
+        let layout = SheetLayout {
+            columns: 5,
+            padding: 0,
+            hash_atlas: false,
+        };
+        // More columns than sprites fit them in one row.
+        let (sheet, _) = pack(&sprites(&images, &["a", "b", "c"]), "sheet.png", layout);
+        assert_eq!(sheet.dimensions(), (12, 3));
+    }
+
+    #[test]
+    fn hash_atlas_keys_every_frame_once() {
+        let images = [
+            block(2, 2, 10),
+            block(2, 2, 20),
+            block(2, 2, 30),
+            block(2, 2, 40),
+        ];
+        let layout = SheetLayout {
+            columns: 0,
+            padding: 0,
+            hash_atlas: true,
+        };
+        let names = ["walk", "walk", "walk_2", "run"];
+        let (_, atlas) = pack(&sprites(&images, &names), "sheet.png", layout);
+        let json: serde_json::Value = serde_json::to_value(&atlas).unwrap();
+        let keys: Vec<&String> = json["frames"].as_object().unwrap().keys().collect();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(json["frames"]["walk"]["frame"]["x"], 0);
+        assert_eq!(json["frames"]["walk_1"]["frame"]["x"], 2);
+        assert_eq!(json["frames"]["walk_2"]["duration"], 300);
+        assert_eq!(json["frames"]["run"]["frame"]["y"], 2);
+        assert_eq!(json["meta"]["image"], "sheet.png");
+    }
+
+    #[test]
+    fn array_atlas_lists_frames_in_sheet_order() {
+        let images = [block(2, 2, 10), block(2, 2, 20)];
+        let layout = SheetLayout {
+            columns: 1,
+            padding: 0,
+            hash_atlas: false,
+        };
+        let (_, atlas) = pack(&sprites(&images, &["a", "a"]), "sheet.png", layout);
+        let json = serde_json::to_value(&atlas).unwrap();
+        assert_eq!(json["frames"][0]["filename"], "a");
+        assert_eq!(json["frames"][1]["filename"], "a_1");
+        assert_eq!(json["frames"][1]["frame"]["y"], 2);
+        assert_eq!(json["frames"][1]["sourceSize"]["w"], 2);
+    }
+}
@@ -0,0 +1,334 @@
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Tiled stores tile flips in the top bits of a tile id. Tiled applies the
+/// diagonal flip first, then the horizontal and vertical ones.
+pub const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+pub const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+pub const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+
+/// The eight ways a square tile can be flipped and rotated, identity first.
+const TRANSFORMS: [u32; 8] = [
+    0,
+    FLIPPED_HORIZONTALLY,
+    FLIPPED_VERTICALLY,
+    FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY,
+    FLIPPED_DIAGONALLY,
+    FLIPPED_DIAGONALLY | FLIPPED_HORIZONTALLY,
+    FLIPPED_DIAGONALLY | FLIPPED_VERTICALLY,
+    FLIPPED_DIAGONALLY | FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum TilemapFormat {
+    Csv,
+    Tmx,
+    Tmj,
+}
+
+impl TilemapFormat {
+    pub const ALL: [TilemapFormat; 3] =
+        [TilemapFormat::Csv, TilemapFormat::Tmx, TilemapFormat::Tmj];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TilemapFormat::Csv => "CSV",
+            TilemapFormat::Tmx => "Tiled map (TMX)",
+            TilemapFormat::Tmj => "Tiled map (JSON)",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TilemapFormat::Csv => "csv",
+            TilemapFormat::Tmx => "tmx",
+            TilemapFormat::Tmj => "tmj",
+        }
+    }
+}
+
+/// Unique tiles of an image and the map that rebuilds it from them.
+pub struct Tileset {
+    pub tile_size: u32,
+    pub tiles: Vec<RgbaImage>,
+    /// Map size in tiles.
+    pub map_width: u32,
+    pub map_height: u32,
+    /// Row major Tiled global tile ids: 1 based, with the flip bits set.
+    pub gids: Vec<u32>,
+}
+
+/// Cuts the image into `tile_size` square tiles and keeps each distinct one
+/// once. With `match_flips`, tiles that are a flipped or rotated copy of an
+/// earlier one reuse it with the matching flip bits. Partial tiles on the
+/// right and bottom edges are padded with transparent pixels.
+pub fn build_tileset(image: &DynamicImage, tile_size: u32, match_flips: bool) -> Tileset {
+    let tile_size = tile_size.max(1);
+    let rgba = image.to_rgba8();
+    let map_width = (rgba.width() + tile_size - 1) / tile_size;
+    let map_height = (rgba.height() + tile_size - 1) / tile_size;
+
+    let mut tiles = Vec::new();
+    let mut known: HashMap<Vec<u8>, u32> = HashMap::new();
+    let mut gids = Vec::with_capacity((map_width * map_height) as usize);
+
+    for ty in 0..map_height {
+        for tx in 0..map_width {
+            let mut tile = RgbaImage::from_pixel(tile_size, tile_size, Rgba([0, 0, 0, 0]));
+            let view =
+                imageops::crop_imm(&rgba, tx * tile_size, ty * tile_size, tile_size, tile_size);
+            imageops::replace(&mut tile, &view.to_image(), 0, 0);
+
+            if let Some(gid) = known.get(tile.as_raw()) {
+                gids.push(*gid);
+                continue;
+            }
+            let gid = tiles.len() as u32 + 1;
+            let transforms = if match_flips {
+                &TRANSFORMS[..]
+            } else {
+                &TRANSFORMS[..1]
+            };
+            for flags in transforms {
+                known
+                    .entry(transform(&tile, *flags).into_raw())
+                    .or_insert(gid | flags);
+            }
+            gids.push(gid);
+            tiles.push(tile);
+        }
+    }
+
+    Tileset {
+        tile_size,
+        tiles,
+        map_width,
+        map_height,
+        gids,
+    }
+}
+
+/// How a tile drawn with the Tiled flip bits `flags` looks.
+fn transform(tile: &RgbaImage, flags: u32) -> RgbaImage {
+    let n = tile.width();
+    RgbaImage::from_fn(n, n, |x, y| {
+        let (mut x, mut y) = (x, y);
+        if flags & FLIPPED_VERTICALLY != 0 {
+            y = n - 1 - y;
+        }
+        if flags & FLIPPED_HORIZONTALLY != 0 {
+            x = n - 1 - x;
+        }
+        if flags & FLIPPED_DIAGONALLY != 0 {
+            std::mem::swap(&mut x, &mut y);
+        }
+        *tile.get_pixel(x, y)
+    })
+}
+
+impl Tileset {
+    /// Columns of the tileset image, for a roughly square image.
+    pub fn columns(&self) -> u32 {
+        (self.tiles.len() as f32).sqrt().ceil().max(1.0) as u32
+    }
+
+    pub fn image(&self) -> RgbaImage {
+        let columns = self.columns();
+        let rows = (self.tiles.len() as u32 + columns - 1) / columns;
+        let mut image = RgbaImage::new(columns * self.tile_size, rows.max(1) * self.tile_size);
+        for (i, tile) in self.tiles.iter().enumerate() {
+            let x = (i as u32 % columns) * self.tile_size;
+            let y = (i as u32 / columns) * self.tile_size;
+            imageops::replace(&mut image, tile, x as i64, y as i64);
+        }
+        image
+    }
+
+    /// The map as comma separated tile ids, one map row per line.
+    pub fn to_csv(&self) -> String {
+        self.csv_rows("\n")
+    }
+
+    /// TMX CSV data also puts a comma between the rows.
+    fn csv_rows(&self, row_separator: &str) -> String {
+        self.gids
+            .chunks(self.map_width.max(1) as usize)
+            .map(|row| {
+                row.iter()
+                    .map(|gid| gid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>()
+            .join(row_separator)
+            + "\n"
+    }
+
+    /// A Tiled TMX map using the tileset image `image_name`, drawn at
+    /// `scale` times the tile size.
+    pub fn to_tmx(&self, name: &str, image_name: &str, scale: u32) -> String {
+        let tile = self.tile_size * scale;
+        let columns = self.columns();
+        let rows = (self.tiles.len() as u32 + columns - 1) / columns;
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.10" orientation="orthogonal" renderorder="right-down" width="{width}" height="{height}" tilewidth="{tile}" tileheight="{tile}" infinite="0" nextlayerid="2" nextobjectid="1">
+ <tileset firstgid="1" name="{name}" tilewidth="{tile}" tileheight="{tile}" tilecount="{count}" columns="{columns}">
+  <image source="{image}" width="{image_width}" height="{image_height}"/>
+ </tileset>
+ <layer id="1" name="Tiles" width="{width}" height="{height}">
+  <data encoding="csv">
+{data}</data>
+ </layer>
+</map>
+"#,
+            width = self.map_width,
+            height = self.map_height,
+            tile = tile,
+            name = xml_escape(name),
+            count = self.tiles.len(),
+            columns = columns,
+            image = xml_escape(image_name),
+            image_width = columns * tile,
+            image_height = rows * tile,
+            data = self.csv_rows(",\n"),
+        )
+    }
+
+    /// The same map as [`Tileset::to_tmx`] in Tiled's JSON format.
+    pub fn to_tmj(&self, name: &str, image_name: &str, scale: u32) -> String {
+        let tile = self.tile_size * scale;
+        let columns = self.columns();
+        let rows = (self.tiles.len() as u32 + columns - 1) / columns;
+        let map = json!({
+            "type": "map",
+            "version": "1.10",
+            "orientation": "orthogonal",
+            "renderorder": "right-down",
+            "infinite": false,
+            "width": self.map_width,
+            "height": self.map_height,
+            "tilewidth": tile,
+            "tileheight": tile,
+            "nextlayerid": 2,
+            "nextobjectid": 1,
+            "layers": [{
+                "id": 1,
+                "name": "Tiles",
+                "type": "tilelayer",
+                "x": 0,
+                "y": 0,
+                "width": self.map_width,
+                "height": self.map_height,
+                "opacity": 1,
+                "visible": true,
+                "data": self.gids,
+            }],
+            "tilesets": [{
+                "firstgid": 1,
+                "name": name,
+                "image": image_name,
+                "imagewidth": columns * tile,
+                "imageheight": rows * tile,
+                "tilewidth": tile,
+                "tileheight": tile,
+                "tilecount": self.tiles.len(),
+                "columns": columns,
+                "margin": 0,
+                "spacing": 0,
+            }],
+        });
+        serde_json::to_string_pretty(&map).unwrap_or_default()
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2×2 tile that looks different under every flip and rotation.
+    fn asymmetric() -> RgbaImage {
+        RgbaImage::from_fn(2, 2, |x, y| Rgba([x as u8 * 255, y as u8 * 255, 0, 255]))
+    }
+
+    fn image_of(tiles: &[RgbaImage], columns: u32) -> DynamicImage {
+        let rows = (tiles.len() as u32 + columns - 1) / columns;
+        let mut image = RgbaImage::new(columns * 2, rows * 2);
+        for (i, tile) in tiles.iter().enumerate() {
+            let (x, y) = (i as u32 % columns * 2, i as u32 / columns * 2);
+            imageops::replace(&mut image, tile, x as i64, y as i64);
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    #[test]
+    fn flipped_tiles_reuse_the_first_with_flip_bits() {
+        let tile = asymmetric();
+        let other = RgbaImage::from_pixel(2, 2, Rgba([9, 9, 9, 255]));
+        let flags = [
+            0,
+            FLIPPED_HORIZONTALLY,
+            FLIPPED_VERTICALLY,
+            FLIPPED_DIAGONALLY,
+            FLIPPED_DIAGONALLY | FLIPPED_HORIZONTALLY,
+        ];
+        let mut tiles: Vec<RgbaImage> = flags.iter().map(|f| transform(&tile, *f)).collect();
+        tiles.push(other.clone());
+        tiles.push(tile.clone());
+        tiles.push(other.clone());
+
+        let tileset = build_tileset(&image_of(&tiles, 4), 2, true);
+        assert_eq!((tileset.map_width, tileset.map_height), (4, 2));
+        assert_eq!(tileset.tiles, vec![tile, other]);
+        let expected: Vec<u32> = flags.iter().map(|f| 1 | f).chain([2, 1, 2]).collect();
+        assert_eq!(tileset.gids, expected);
+    }
+
+    #[test]
+    fn flips_are_separate_tiles_without_matching() {
+        let tile = asymmetric();
+        let tiles = [
+            tile.clone(),
+            transform(&tile, FLIPPED_HORIZONTALLY),
+            tile.clone(),
+        ];
+        let tileset = build_tileset(&image_of(&tiles, 3), 2, false);
+        assert_eq!(tileset.gids, [1, 2, 1]);
+        assert_eq!(tileset.tiles.len(), 2);
+        assert_eq!(tileset.tiles[1], transform(&tile, FLIPPED_HORIZONTALLY));
+    }
+
+    #[test]
+    fn gids_rebuild_the_image() {
+        let tile = asymmetric();
+        let tiles: Vec<RgbaImage> = TRANSFORMS.iter().map(|f| transform(&tile, *f)).collect();
+        let image = image_of(&tiles, 3);
+        let tileset = build_tileset(&image, 2, true);
+        assert_eq!(tileset.tiles.len(), 2);
+        let rgba = image.to_rgba8();
+        for (i, gid) in tileset.gids.iter().enumerate() {
+            let flags = gid & (FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY);
+            let drawn = transform(&tileset.tiles[(gid & !flags) as usize - 1], flags);
+            let (x, y) = (i as u32 % 3 * 2, i as u32 / 3 * 2);
+            let cell = imageops::crop_imm(&rgba, x, y, 2, 2).to_image();
+            assert_eq!(drawn, cell, "cell {}", i);
+        }
+    }
+
+    #[test]
+    fn partial_tiles_are_padded() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 1, Rgba([1, 2, 3, 255])));
+        let tileset = build_tileset(&image, 2, true);
+        assert_eq!((tileset.map_width, tileset.map_height), (2, 1));
+        assert_eq!(tileset.tiles[1].get_pixel(1, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(tileset.to_csv(), "1,2\n");
+    }
+}
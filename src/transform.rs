@@ -0,0 +1,315 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::animation::AnimationFrame;
+
+/// Straightening is limited to this many degrees either way.
+pub const MAX_STRAIGHTEN: f32 = 45.0;
+
+/// A region of the oriented picture, in its pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How the source is turned and cut before pixelization. Rotation, flips
+/// and straightening happen first, the crop is taken from the result.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Transform {
+    /// Clockwise quarter turns, 0 to 3.
+    pub quarter_turns: u8,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    /// Fine rotation in degrees, clockwise. The result is cut down to the
+    /// largest rectangle of the same shape without empty corners.
+    pub straighten: f32,
+    pub crop: Option<Crop>,
+}
+
+impl Transform {
+    pub fn is_identity(&self) -> bool {
+        *self == Transform::default()
+    }
+
+    /// The same transform without the crop.
+    pub fn orientation(&self) -> Transform {
+        Transform {
+            crop: None,
+            ..*self
+        }
+    }
+
+    /// Rotates, flips and straightens.
+    pub fn orient(&self, image: &DynamicImage) -> DynamicImage {
+        let turned = match self.quarter_turns % 4 {
+            1 => image.rotate90(),
+            2 => image.rotate180(),
+            3 => image.rotate270(),
+            _ => image.clone(),
+        };
+        let turned = if self.flip_horizontal {
+            turned.fliph()
+        } else {
+            turned
+        };
+        let turned = if self.flip_vertical {
+            turned.flipv()
+        } else {
+            turned
+        };
+        if self.straighten == 0.0 {
+            turned
+        } else {
+            let angle = self.straighten.clamp(-MAX_STRAIGHTEN, MAX_STRAIGHTEN);
+            DynamicImage::ImageRgba8(straighten(&turned.to_rgba8(), angle))
+        }
+    }
+
+    /// Orients and crops. Crops reaching past the picture are cut to fit.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let oriented = if self.orientation().is_identity() {
+            image.clone()
+        } else {
+            self.orient(image)
+        };
+        match self.crop {
+            Some(crop) => {
+                let x = crop.x.min(oriented.width().saturating_sub(1));
+                let y = crop.y.min(oriented.height().saturating_sub(1));
+                let width = crop.width.clamp(1, oriented.width() - x);
+                let height = crop.height.clamp(1, oriented.height() - y);
+                oriented.crop_imm(x, y, width, height)
+            }
+            None => oriented,
+        }
+    }
+
+    pub fn apply_frames(&self, frames: &[AnimationFrame]) -> Vec<AnimationFrame> {
+        frames
+            .iter()
+            .map(|frame| AnimationFrame {
+                image: self.apply(&frame.image),
+                delay_ms: frame.delay_ms,
+            })
+            .collect()
+    }
+}
+
+/// What shape a crop may take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CropMode {
+    Free,
+    /// Keeps the width to height ratio.
+    Aspect,
+    /// A fixed number of output pixels at the current pixel size.
+    Grid,
+}
+
+impl CropMode {
+    pub const ALL: [CropMode; 3] = [CropMode::Free, CropMode::Aspect, CropMode::Grid];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CropMode::Free => "Free",
+            CropMode::Aspect => "Fixed aspect",
+            CropMode::Grid => "Output grid",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CropSettings {
+    pub mode: CropMode,
+    pub aspect_width: u32,
+    pub aspect_height: u32,
+    pub grid_columns: u32,
+    pub grid_rows: u32,
+}
+
+impl Default for CropSettings {
+    fn default() -> Self {
+        CropSettings {
+            mode: CropMode::Free,
+            aspect_width: 1,
+            aspect_height: 1,
+            grid_columns: 32,
+            grid_rows: 32,
+        }
+    }
+}
+
+/// Rotates by `degrees` around the center with bilinear sampling, keeping
+/// the largest centered rectangle of the original shape.
+fn straighten(image: &RgbaImage, degrees: f32) -> RgbaImage {
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (sin, cos_abs) = (sin.abs(), cos.abs());
+    let scale =
+        (width / (width * cos_abs + height * sin)).min(height / (width * sin + height * cos_abs));
+    let out_width = ((width * scale) as u32).max(1);
+    let out_height = ((height * scale) as u32).max(1);
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let center = (width / 2.0, height / 2.0);
+    let out_center = (out_width as f32 / 2.0, out_height as f32 / 2.0);
+    RgbaImage::from_fn(out_width, out_height, |x, y| {
+        // Turn back by the angle to find where the pixel came from.
+        let dx = x as f32 + 0.5 - out_center.0;
+        let dy = y as f32 + 0.5 - out_center.1;
+        let sx = cos * dx + sin * dy + center.0 - 0.5;
+        let sy = -sin * dx + cos * dy + center.1 - 0.5;
+        bilinear(image, sx, sy)
+    })
+}
+
+fn bilinear(image: &RgbaImage, x: f32, y: f32) -> Rgba<u8> {
+    let max_x = image.width() as i64 - 1;
+    let max_y = image.height() as i64 - 1;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let at = |x: i64, y: i64| image.get_pixel(x.clamp(0, max_x) as u32, y.clamp(0, max_y) as u32);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let (a, b, c, d) = (
+        at(x0, y0),
+        at(x0 + 1, y0),
+        at(x0, y0 + 1),
+        at(x0 + 1, y0 + 1),
+    );
+    let mut out = [0u8; 4];
+    for i in 0..4 {
+        let top = a[i] as f32 * (1.0 - fx) + b[i] as f32 * fx;
+        let bottom = c[i] as f32 * (1.0 - fx) + d[i] as f32 * fx;
+        out[i] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Rgba(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 3×2, every pixel telling its origin apart.
+    fn numbered() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 2, |x, y| {
+            Rgba([x as u8, y as u8, 0, 255])
+        }))
+    }
+
+    /// Where each pixel of the result came from, row by row.
+    fn origins(image: &DynamicImage) -> Vec<Vec<(u8, u8)>> {
+        let rgba = image.to_rgba8();
+        (0..rgba.height())
+            .map(|y| {
+                (0..rgba.width())
+                    .map(|x| {
+                        let p = rgba.get_pixel(x, y);
+                        (p[0], p[1])
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn turned(quarter_turns: u8, flip_horizontal: bool, flip_vertical: bool) -> Transform {
+        Transform {
+            quarter_turns,
+            flip_horizontal,
+            flip_vertical,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flips_apply_after_the_turn() {
+        let transpose = vec![
+            vec![(0, 0), (0, 1)],
+            vec![(1, 0), (1, 1)],
+            vec![(2, 0), (2, 1)],
+        ];
+        let anti_transpose = vec![
+            vec![(2, 1), (2, 0)],
+            vec![(1, 1), (1, 0)],
+            vec![(0, 1), (0, 0)],
+        ];
+        assert_eq!(
+            origins(&turned(1, true, false).apply(&numbered())),
+            transpose
+        );
+        assert_eq!(
+            origins(&turned(3, false, true).apply(&numbered())),
+            transpose
+        );
+        assert_eq!(
+            origins(&turned(1, false, true).apply(&numbered())),
+            anti_transpose
+        );
+        assert_eq!(
+            origins(&turned(3, true, false).apply(&numbered())),
+            anti_transpose
+        );
+        assert_eq!(
+            origins(&turned(2, true, true).apply(&numbered())),
+            origins(&numbered())
+        );
+        // Turns wrap around.
+        assert_eq!(
+            origins(&turned(5, true, false).apply(&numbered())),
+            transpose
+        );
+    }
+
+    #[test]
+    fn crops_past_the_picture_are_cut_to_fit() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(5, 4));
+        let cropped = |crop: Crop, quarter_turns: u8| {
+            let transform = Transform {
+                quarter_turns,
+                crop: Some(crop),
+                ..Default::default()
+            };
+            let result = transform.apply(&image);
+            (result.width(), result.height())
+        };
+        let crop = |x, y, width, height| Crop {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(cropped(crop(2, 1, 100, 100), 0), (3, 3));
+        assert_eq!(cropped(crop(10, 10, 3, 3), 0), (1, 1));
+        assert_eq!(cropped(crop(1, 1, 0, 0), 0), (1, 1));
+        // The crop is taken from the turned picture.
+        assert_eq!(cropped(crop(0, 0, 100, 100), 1), (4, 5));
+        assert_eq!(cropped(crop(3, 0, 100, 100), 1), (1, 5));
+    }
+
+    #[test]
+    fn straightening_keeps_the_shape_without_empty_corners() {
+        let image = RgbaImage::from_pixel(40, 20, Rgba([10, 200, 30, 255]));
+        assert_eq!(straighten(&image, 0.0), image);
+        let tilted = straighten(&image, 10.0);
+        assert!(tilted.width() < 40 && tilted.height() < 20);
+        let ratio = tilted.width() as f32 / tilted.height() as f32;
+        assert!((ratio - 2.0).abs() < 0.1, "{}", ratio);
+        // No corner is left over from outside the picture.
+        assert!(tilted.pixels().all(|p| *p == Rgba([10, 200, 30, 255])));
+
+        // Straightening is limited either way.
+        let image = DynamicImage::ImageRgba8(image);
+        let straightened = |straighten| {
+            Transform {
+                straighten,
+                ..Default::default()
+            }
+            .apply(&image)
+            .to_rgba8()
+        };
+        assert_eq!(straightened(90.0), straightened(MAX_STRAIGHTEN));
+        assert_eq!(straightened(-90.0), straightened(-MAX_STRAIGHTEN));
+    }
+}
@@ -1,13 +1,15 @@
 use egui::{color::Color32, ColorImage, Vec2};
-use image::{DynamicImage, ImageBuffer, Rgb, RgbImage, Rgba};
+use image::{imageops::FilterType, DynamicImage, ImageBuffer, Rgb, RgbImage, Rgba};
 use kmeans_colors::{get_kmeans, get_kmeans_hamerly, Calculate, Kmeans, MapColor, Sort};
-use palette::{FromColor, Hsv, IntoColor, Lab, Pixel, Srgb};
+use palette::{FromColor, Hsv, IntoColor, Lab, Lch, Pixel, Srgb};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct KmeansParams {
     pub k: usize,
     pub run: usize,
     pub max_iter: usize,
+    /// A run stops once the centroids moved at most this far in a round,
+    /// measured as the squared length of their summed movement in Lab.
     pub converge: f32,
     pub verbose: bool,
     pub seed: u64,
@@ -16,7 +18,7 @@ pub struct KmeansParams {
 pub fn calculate_kmeans(
     image: DynamicImage,
     params: KmeansParams,
-) -> Option<(Vec<Color32>, Vec<Lab>)> {
+) -> Option<(Vec<Color32>, Vec<Lab>, Vec<f32>)> {
     let img_vec = image.into_rgb8().to_vec();
 
     let lab: Vec<Lab> = Srgb::from_raw_slice(&img_vec)
@@ -49,17 +51,107 @@ pub fn calculate_kmeans(
         color_palette.push(Color32::from_rgb(color.red, color.green, color.blue));
     }
 
-    /* Get one dominant color
-    let buffer = Srgb::map_indices_to_centroids(&rgb, &result.indices);
-    let mut res = Lab::sort_indexed_colors(&result.centroids, &result.indices);
+    // Share of the image covered by each color, in k-means order.
+    let mut coverage = vec![0.0; result.centroids.len()];
+    for data in Lab::sort_indexed_colors(&result.centroids, &result.indices) {
+        coverage[data.index as usize] = data.percentage;
+    }
+
+    Some((color_palette, result.centroids, coverage))
+}
 
-    let dominant_color = Lab::get_dominant_color(&res);
+/// Shrinks a picture to at most `max_pixels` pixels for quick estimates.
+/// Nearest sampling keeps the colors as they are.
+pub fn subsample(image: &DynamicImage, max_pixels: u32) -> DynamicImage {
+    let pixels = image.width() * image.height();
+    if pixels <= max_pixels {
+        return image.clone();
+    }
+    let scale = (max_pixels as f32 / pixels as f32).sqrt();
+    let width = ((image.width() as f32 * scale) as u32).max(1);
+    let height = ((image.height() as f32 * scale) as u32).max(1);
+    image.resize_exact(width, height, FilterType::Nearest)
+}
 
-    res.sort_unstable_by(|a, b| (b.percentage).partial_cmp(&a.percentage).unwrap());
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum PaletteSort {
+    Unsorted,
+    Luminance,
+    Hue,
+    Usage,
+    HueRamps,
+}
+
+impl PaletteSort {
+    pub const ALL: [PaletteSort; 5] = [
+        PaletteSort::Unsorted,
+        PaletteSort::Luminance,
+        PaletteSort::Hue,
+        PaletteSort::Usage,
+        PaletteSort::HueRamps,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaletteSort::Unsorted => "Unsorted",
+            PaletteSort::Luminance => "Luminance",
+            PaletteSort::Hue => "Hue",
+            PaletteSort::Usage => "Usage",
+            PaletteSort::HueRamps => "Hue ramps",
+        }
+    }
+}
+
+/// Colors with less chroma than this are treated as grays when building ramps.
+const GRAY_CHROMA: f32 = 10.0;
+
+/// Width of the hue buckets that make up a ramp, in degrees.
+const RAMP_HUE_STEP: f32 = 30.0;
+
+fn hue_of(color: Lab) -> f32 {
+    Lch::from_color(color).hue.to_positive_degrees()
+}
+
+/// Returns the palette indices in display order.
+pub fn sort_palette(colors: &[Lab], coverage: &[f32], sort: PaletteSort) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..colors.len()).collect();
+    match sort {
+        PaletteSort::Unsorted => {}
+        PaletteSort::Luminance => {
+            order.sort_by(|a, b| colors[*a].l.total_cmp(&colors[*b].l));
+        }
+        PaletteSort::Hue => {
+            order.sort_by(|a, b| hue_of(colors[*a]).total_cmp(&hue_of(colors[*b])));
+        }
+        PaletteSort::Usage => {
+            order.sort_by(|a, b| coverage[*b].total_cmp(&coverage[*a]));
+        }
+        PaletteSort::HueRamps => {
+            order = hue_ramps(colors).concat();
+        }
+    }
+    order
+}
 
-    let dominant_color = res.first().unwrap().centroid;
-    */
-    Some((color_palette, result.centroids))
+/// Groups the palette into ramps of similar hue, each going dark to light.
+/// Grays come first, the remaining ramps follow the hue wheel.
+pub fn hue_ramps(colors: &[Lab]) -> Vec<Vec<usize>> {
+    let buckets = (360.0 / RAMP_HUE_STEP) as usize;
+    let mut ramps: Vec<Vec<usize>> = vec![Vec::new(); buckets + 1];
+    for (i, color) in colors.iter().enumerate() {
+        let lch = Lch::from_color(*color);
+        if lch.chroma < GRAY_CHROMA {
+            ramps[0].push(i);
+        } else {
+            let bucket = (lch.hue.to_positive_degrees() / RAMP_HUE_STEP) as usize;
+            ramps[1 + bucket.min(buckets - 1)].push(i);
+        }
+    }
+    ramps.retain(|ramp| !ramp.is_empty());
+    for ramp in ramps.iter_mut() {
+        ramp.sort_by(|a, b| colors[*a].l.total_cmp(&colors[*b].l));
+    }
+    ramps
 }
 
 pub fn calc_target_size(image: DynamicImage, pixel_size: usize) -> Option<Vec2> {
@@ -75,14 +167,112 @@ pub fn calc_target_size(image: DynamicImage, pixel_size: usize) -> Option<Vec2>
     Some(Vec2::new(target_width.floor(), target_height.floor()))
 }
 
+/// Drops the columns and rows before the first block, so the block grid
+/// starts at `offset`.
+pub fn offset_grid(image: &DynamicImage, offset: [u32; 2]) -> DynamicImage {
+    let x = offset[0].min(image.width().saturating_sub(1));
+    let y = offset[1].min(image.height().saturating_sub(1));
+    if x == 0 && y == 0 {
+        return image.clone();
+    }
+    image.crop_imm(x, y, image.width() - x, image.height() - y)
+}
+
+/// Finds the grid offset whose blocks vary the least inside, so that edges
+/// fall between blocks instead of being averaged away. The variance of
+/// every offset comes from summed-area tables, which keeps the search linear
+/// in the number of pixels.
+pub fn best_grid_offset(image: &DynamicImage, pixel_size: usize) -> [u32; 2] {
+    let rgb = image.to_rgb8();
+    let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+    let size = pixel_size.max(1);
+    if width < 2 * size || height < 2 * size {
+        return [0, 0];
+    }
+
+    // Sums over the rectangle from the origin to each pixel, per channel,
+    // and of the squares of all channels.
+    let stride = width + 1;
+    let mut sums = vec![[0.0f64; 3]; stride * (height + 1)];
+    let mut squares = vec![0.0f64; stride * (height + 1)];
+    for y in 0..height {
+        let mut row = [0.0f64; 3];
+        let mut row_squares = 0.0;
+        for x in 0..width {
+            let pixel = rgb.get_pixel(x as u32, y as u32);
+            for c in 0..3 {
+                let value = pixel[c] as f64;
+                row[c] += value;
+                row_squares += value * value;
+            }
+            let i = (y + 1) * stride + x + 1;
+            let above = y * stride + x + 1;
+            for c in 0..3 {
+                sums[i][c] = sums[above][c] + row[c];
+            }
+            squares[i] = squares[above] + row_squares;
+        }
+    }
+
+    let area = (size * size) as f64;
+    let block_cost = |x: usize, y: usize| {
+        let (a, b) = (y * stride + x, y * stride + x + size);
+        let (c, d) = ((y + size) * stride + x, (y + size) * stride + x + size);
+        let squared = squares[d] - squares[b] - squares[c] + squares[a];
+        let mean_part: f64 = (0..3)
+            .map(|ch| {
+                let sum = sums[d][ch] - sums[b][ch] - sums[c][ch] + sums[a][ch];
+                sum * sum / area
+            })
+            .sum();
+        squared - mean_part
+    };
+
+    let mut best = ([0, 0], f64::MAX);
+    for offset_y in 0..size {
+        for offset_x in 0..size {
+            let columns = (width - offset_x) / size;
+            let rows = (height - offset_y) / size;
+            let mut cost = 0.0;
+            for row in 0..rows {
+                for column in 0..columns {
+                    cost += block_cost(offset_x + column * size, offset_y + row * size);
+                }
+            }
+            // Offsets cover different numbers of blocks, compare their means.
+            let cost = cost / (columns * rows) as f64;
+            if cost < best.1 {
+                best = ([offset_x as u32, offset_y as u32], cost);
+            }
+        }
+    }
+    best.0
+}
+
+/// 4x4 Bayer matrix for ordered dithering.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Largest offset ordered dithering adds to a channel, at full strength.
+const DITHER_SPREAD: f32 = 64.0;
+
+/// `dither` is the ordered dithering strength from 0 (off) to 1. Ordered
+/// dithering only depends on the pixel position, so unchanged areas stay the
+/// same from one frame to the next.
 pub fn generate_image(
     image: DynamicImage,
     pixel_size: usize,
     size: Vec2,
     colors: Vec<Lab>,
+    dither: f32,
 ) -> DynamicImage {
-    let img_vec = image.into_rgb8();
-    let mut output_img = RgbImage::new(size.x as u32, size.y as u32);
+    map_to_palette(&block_averages(&image, pixel_size, size), &colors, dither)
+}
+
+/// Averages every `pixel_size` wide square of the picture into one pixel of
+/// an image of `size`.
+pub fn block_averages(image: &DynamicImage, pixel_size: usize, size: Vec2) -> RgbImage {
+    let img_vec = image.to_rgb8();
+    let mut averages = RgbImage::new(size.x as u32, size.y as u32);
 
     for i in 0..size.x as usize {
         for j in 0..size.y as usize {
@@ -102,14 +292,37 @@ pub fn generate_image(
             let pixel_avg_g = pixel_sum_g / (pixel_size * pixel_size);
             let pixel_avg_b = pixel_sum_b / (pixel_size * pixel_size);
             let pixel_avg = Rgb([pixel_avg_r as u8, pixel_avg_g as u8, pixel_avg_b as u8]);
-            let output_pixel = choose_closest_color(pixel_avg, colors.clone());
-            output_img.put_pixel(i as u32, j as u32, output_pixel);
+            averages.put_pixel(i as u32, j as u32, pixel_avg);
         }
     }
+    averages
+}
+
+/// Replaces every block average with the closest palette color, dithered
+/// by `dither`.
+pub fn map_to_palette(averages: &RgbImage, colors: &[Lab], dither: f32) -> DynamicImage {
+    let mut output_img = RgbImage::new(averages.width(), averages.height());
+    for (i, j, pixel_avg) in averages.enumerate_pixels() {
+        let pixel_avg = if dither > 0.0 {
+            ordered_dither(*pixel_avg, i as usize, j as usize, dither)
+        } else {
+            *pixel_avg
+        };
+        let output_pixel = choose_closest_color(pixel_avg, colors);
+        output_img.put_pixel(i, j, output_pixel);
+    }
     DynamicImage::ImageRgb8(output_img)
 }
 
-fn choose_closest_color(pixel: Rgb<u8>, pixels: Vec<Lab>) -> Rgb<u8> {
+fn ordered_dither(pixel: Rgb<u8>, x: usize, y: usize, strength: f32) -> Rgb<u8> {
+    let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5;
+    let offset = threshold * strength * DITHER_SPREAD;
+    Rgb(pixel
+        .0
+        .map(|c| (c as f32 + offset).round().clamp(0.0, 255.0) as u8))
+}
+
+fn choose_closest_color(pixel: Rgb<u8>, pixels: &[Lab]) -> Rgb<u8> {
     let binding = Srgb::from_raw_slice(&[pixel[0], pixel[1], pixel[2]])
         .iter()
         .map(|x| x.into_format().into_color())
@@ -118,10 +331,10 @@ fn choose_closest_color(pixel: Rgb<u8>, pixels: Vec<Lab>) -> Rgb<u8> {
     let mut closest: Lab = *pixels.first().unwrap();
     let mut min_delta = std::f32::MAX;
     for c in pixels {
-        let delta = delta_e(*pixel_lab, c);
+        let delta = delta_e(*pixel_lab, *c);
         if delta < min_delta {
             min_delta = delta;
-            closest = c;
+            closest = *c;
         }
     }
 
@@ -224,3 +437,93 @@ pub fn sharpen_filter(image: DynamicImage) -> DynamicImage {
     let matrix = [[0, -1, 0], [-1, 5, -1], [0, -1, 0]];
     convolver(image, matrix, 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flat blocks of `size` pixels, the grid starting at `offset`, each
+    /// block a different color.
+    fn blocks(width: u32, height: u32, size: u32, offset: [u32; 2]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            // The partial blocks before the offset count as blocks too.
+            let column = (x + size - offset[0]) / size;
+            let row = (y + size - offset[1]) / size;
+            let seed = column * 7 + row * 13;
+            Rgb([
+                (seed * 37 % 256) as u8,
+                (seed * 91 % 256) as u8,
+                (seed * 53 % 256) as u8,
+            ])
+        }))
+    }
+
+    fn lab(rgb: [u8; 3]) -> Lab {
+        Srgb::new(rgb[0], rgb[1], rgb[2]).into_format().into_color()
+    }
+
+    /// Blue, dark gray, red, white, dark red, yellow and black.
+    fn palette() -> Vec<Lab> {
+        [
+            [0, 0, 255],
+            [90, 90, 90],
+            [255, 0, 0],
+            [255, 255, 255],
+            [120, 0, 0],
+            [255, 255, 0],
+            [0, 0, 0],
+        ]
+        .into_iter()
+        .map(lab)
+        .collect()
+    }
+
+    const COVERAGE: [f32; 7] = [0.1, 0.3, 0.05, 0.2, 0.15, 0.12, 0.08];
+
+    #[test]
+    fn palette_sorts_order_the_indices() {
+        let sorted = |sort| sort_palette(&palette(), &COVERAGE, sort);
+        assert_eq!(sorted(PaletteSort::Unsorted), [0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(sorted(PaletteSort::Luminance), [6, 4, 0, 1, 2, 5, 3]);
+        assert_eq!(sorted(PaletteSort::Usage), [1, 3, 4, 5, 0, 6, 2]);
+        // Reds, yellow, then blue; grays have no hue to speak of.
+        let by_hue: Vec<usize> = sorted(PaletteSort::Hue)
+            .into_iter()
+            .filter(|i| ![1, 3, 6].contains(i))
+            .collect();
+        assert_eq!(by_hue, [4, 2, 5, 0]);
+        assert_eq!(sorted(PaletteSort::HueRamps), [6, 1, 3, 4, 2, 5, 0]);
+    }
+
+    #[test]
+    fn ramps_group_similar_hues_dark_to_light() {
+        assert_eq!(
+            hue_ramps(&palette()),
+            [vec![6, 1, 3], vec![4, 2], vec![5], vec![0]]
+        );
+        assert!(hue_ramps(&[]).is_empty());
+    }
+
+    #[test]
+    fn sorting_survives_nan() {
+        let mut colors = palette();
+        colors[2] = Lab::new(f32::NAN, f32::NAN, f32::NAN);
+        let mut coverage = COVERAGE;
+        coverage[0] = f32::NAN;
+        for sort in PaletteSort::ALL {
+            let mut order = sort_palette(&colors, &coverage, sort);
+            order.sort_unstable();
+            assert_eq!(order, [0, 1, 2, 3, 4, 5, 6], "{:?}", sort);
+        }
+    }
+
+    #[test]
+    fn grid_offset_is_found_where_the_blocks_start() {
+        for offset in [[0, 0], [1, 3], [3, 2]] {
+            let image = blocks(27, 30, 4, offset);
+            assert_eq!(best_grid_offset(&image, 4), offset);
+        }
+        // A single block along one side leaves nothing to compare.
+        assert_eq!(best_grid_offset(&blocks(7, 30, 4, [1, 1]), 4), [0, 0]);
+    }
+}
@@ -0,0 +1,498 @@
+use egui::color::Color32;
+use image::{imageops, imageops::FilterType, DynamicImage, GrayImage, Luma};
+use palette::{FromColor, IntoColor, Lab, Srgb};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::pipeline::Palette;
+use crate::transform::{Crop, Transform};
+use crate::util::{self, KmeansParams};
+
+/// Longest side of a painted mask. Importance is coarse, and a small mask
+/// is quick to show while painting.
+pub const MASK_SIZE: u32 = 256;
+
+/// Where the importance of each pixel for palette extraction comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Weighting {
+    /// All pixels count the same.
+    Uniform,
+    /// The mask painted over the input.
+    Mask,
+    /// Pixels count more the closer they are to the center.
+    Center,
+    /// Pixels count more where the picture has edges and detail.
+    Edges,
+}
+
+impl Weighting {
+    pub const ALL: [Weighting; 4] = [
+        Weighting::Uniform,
+        Weighting::Mask,
+        Weighting::Center,
+        Weighting::Edges,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Weighting::Uniform => "Uniform",
+            Weighting::Mask => "Painted mask",
+            Weighting::Center => "Center",
+            Weighting::Edges => "Edges (saliency)",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WeightParams {
+    pub weighting: Weighting,
+    /// The most important pixels count this many times more, on top of
+    /// the weight every pixel has.
+    pub strength: f32,
+}
+
+impl Default for WeightParams {
+    fn default() -> Self {
+        WeightParams {
+            weighting: Weighting::Uniform,
+            strength: 4.0,
+        }
+    }
+}
+
+/// Importance painted over the input as it is turned, before cropping,
+/// scaled down to at most `MASK_SIZE` pixels on the longer side.
+#[derive(Clone)]
+pub struct ImportanceMask {
+    /// Changes with every edit, so palettes of an older mask aren't reused.
+    pub revision: u64,
+    /// The input revision and orientation the mask was painted on.
+    pub source: (u64, Transform),
+    /// Size of the picture the mask covers.
+    pub extent: [u32; 2],
+    pub image: GrayImage,
+}
+
+impl ImportanceMask {
+    pub fn new(revision: u64, source: (u64, Transform), extent: [u32; 2]) -> Self {
+        let scale = (MASK_SIZE as f32 / extent[0].max(extent[1]).max(1) as f32).min(1.0);
+        let width = ((extent[0] as f32 * scale).round() as u32).max(1);
+        let height = ((extent[1] as f32 * scale).round() as u32).max(1);
+        ImportanceMask {
+            revision,
+            source,
+            extent,
+            image: GrayImage::new(width, height),
+        }
+    }
+
+    /// Paints round dabs of `radius` along the line from `from` to `to`,
+    /// all in pixels of the picture. `value` 255 is most important, 0 erases.
+    pub fn paint_line(&mut self, from: (f32, f32), to: (f32, f32), radius: f32, value: u8) {
+        let scale = self.image.width() as f32 / self.extent[0].max(1) as f32;
+        let radius = (radius * scale).max(0.5);
+        let (from, to) = (
+            (from.0 * scale, from.1 * scale),
+            (to.0 * scale, to.1 * scale),
+        );
+        let length = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+        let dabs = (length / (radius / 2.0)).ceil().max(1.0) as usize;
+        for i in 0..=dabs {
+            let t = i as f32 / dabs as f32;
+            let center = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+            self.dab(center, radius, value);
+        }
+        self.revision += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.image.pixels_mut().for_each(|p| *p = Luma([0]));
+        self.revision += 1;
+    }
+
+    /// The part of the mask under `crop`, given in pixels of the picture.
+    pub fn cropped(&self, crop: Option<Crop>) -> GrayImage {
+        let crop = match crop {
+            Some(crop) => crop,
+            None => return self.image.clone(),
+        };
+        let scale = self.image.width() as f32 / self.extent[0].max(1) as f32;
+        let x = ((crop.x as f32 * scale) as u32).min(self.image.width() - 1);
+        let y = ((crop.y as f32 * scale) as u32).min(self.image.height() - 1);
+        let width = ((crop.width as f32 * scale).ceil() as u32).clamp(1, self.image.width() - x);
+        let height = ((crop.height as f32 * scale).ceil() as u32).clamp(1, self.image.height() - y);
+        imageops::crop_imm(&self.image, x, y, width, height).to_image()
+    }
+
+    fn dab(&mut self, center: (f32, f32), radius: f32, value: u8) {
+        let (width, height) = self.image.dimensions();
+        let left = (center.0 - radius).floor().max(0.0) as u32;
+        let top = (center.1 - radius).floor().max(0.0) as u32;
+        let right = ((center.0 + radius).ceil().max(0.0) as u32).min(width);
+        let bottom = ((center.1 + radius).ceil().max(0.0) as u32).min(height);
+        for y in top..bottom {
+            for x in left..right {
+                let (dx, dy) = (x as f32 + 0.5 - center.0, y as f32 + 0.5 - center.1);
+                if dx * dx + dy * dy <= radius * radius {
+                    self.image.put_pixel(x, y, Luma([value]));
+                }
+            }
+        }
+    }
+}
+
+/// The weighting settings with the mask they may use.
+#[derive(Clone, Copy)]
+pub struct Weights<'a> {
+    pub params: WeightParams,
+    pub mask: Option<&'a ImportanceMask>,
+}
+
+impl Weights<'_> {
+    /// What tells palettes of these weights apart, `None` when all pixels
+    /// count the same.
+    pub fn key(&self) -> Option<(WeightParams, Option<u64>)> {
+        match (self.params.weighting, self.mask) {
+            (Weighting::Uniform, _) | (Weighting::Mask, None) => None,
+            (Weighting::Mask, Some(mask)) => Some((self.params, Some(mask.revision))),
+            _ => Some((self.params, None)),
+        }
+    }
+
+    /// The weight of each pixel of `image`, row by row. `crop` is where the
+    /// image was cut from the picture the mask was painted on.
+    pub fn map(&self, image: &DynamicImage, crop: Option<Crop>) -> Option<Vec<f32>> {
+        self.key()?;
+        let (width, height) = (image.width(), image.height());
+        let importance: Vec<f32> = match self.params.weighting {
+            Weighting::Uniform => return None,
+            Weighting::Mask => {
+                let mask = self.mask?.cropped(crop);
+                imageops::resize(&mask, width, height, FilterType::Triangle)
+                    .pixels()
+                    .map(|p| p[0] as f32 / 255.0)
+                    .collect()
+            }
+            Weighting::Center => (0..height)
+                .flat_map(|y| {
+                    (0..width).map(move |x| {
+                        let dx = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+                        let dy = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+                        (1.0 - (dx * dx + dy * dy)).max(0.0)
+                    })
+                })
+                .collect(),
+            Weighting::Edges => edges(image),
+        };
+        Some(
+            importance
+                .iter()
+                .map(|i| 1.0 + self.params.strength * i)
+                .collect(),
+        )
+    }
+}
+
+/// Gradient strength of the slightly blurred lightness, from 0 to 1.
+fn edges(image: &DynamicImage) -> Vec<f32> {
+    let luma = image.blur(1.0).to_luma8();
+    let (width, height) = luma.dimensions();
+    let at = |x: i64, y: i64| {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        luma.get_pixel(x, y)[0] as f32
+    };
+    let gradient: Vec<f32> = (0..height as i64)
+        .flat_map(|y| {
+            (0..width as i64).map(move |x| {
+                (at(x + 1, y) - at(x - 1, y)).abs() + (at(x, y + 1) - at(x, y - 1)).abs()
+            })
+        })
+        .collect();
+    let max = gradient.iter().copied().fold(0.0, f32::max);
+    if max == 0.0 {
+        return vec![0.0; gradient.len()];
+    }
+    gradient.iter().map(|g| g / max).collect()
+}
+
+/// K-means in Lab where each pixel counts by its weight, seeded with
+/// k-means++. The `pinned` colors come first in the palette and stay as
+/// they are, k-means picks the other k - N. The best of `params.run` runs
+/// is kept. Coverage is still the share of pixels, not of weight.
+pub fn weighted_kmeans(
+    image: &DynamicImage,
+    weights: &[f32],
+    params: KmeansParams,
+    pinned: &[[u8; 3]],
+) -> Option<Palette> {
+    if (image.width() * image.height()) as usize != weights.len() {
+        return None;
+    }
+    kmeans(&gather(image, |i| weights[i]), params, pinned)
+}
+
+/// The palette of `image` with the `pinned` colors always in it, first.
+/// Without pins this is the plain k-means of [`util::calculate_kmeans`].
+pub fn pinned_kmeans(
+    image: DynamicImage,
+    params: KmeansParams,
+    pinned: &[[u8; 3]],
+) -> Option<Palette> {
+    if pinned.is_empty() {
+        return util::calculate_kmeans(image, params);
+    }
+    kmeans(&gather(&image, |_| 1.0), params, pinned)
+}
+
+/// Pixels of one color, gathered into a single k-means point.
+struct Point {
+    lab: [f32; 3],
+    weight: f32,
+    pixels: usize,
+}
+
+fn gather(image: &DynamicImage, weight: impl Fn(usize) -> f32) -> Vec<Point> {
+    let mut points: Vec<Point> = Vec::new();
+    let mut point_of: HashMap<[u8; 3], usize> = HashMap::new();
+    for (i, pixel) in image.to_rgb8().pixels().enumerate() {
+        let at = *point_of.entry(pixel.0).or_insert_with(|| {
+            points.push(Point {
+                lab: to_lab(pixel.0),
+                weight: 0.0,
+                pixels: 0,
+            });
+            points.len() - 1
+        });
+        points[at].weight += weight(i);
+        points[at].pixels += 1;
+    }
+    points
+}
+
+/// Weighted Lloyd iterations over the gathered colors. Stops like
+/// `kmeans_colors` does, see [`KmeansParams::converge`].
+fn kmeans(points: &[Point], params: KmeansParams, pinned: &[[u8; 3]]) -> Option<Palette> {
+    let lab: Vec<[f32; 3]> = points.iter().map(|p| p.lab).collect();
+    let weights: Vec<f32> = points.iter().map(|p| p.weight).collect();
+    let k = params.k.max(pinned.len());
+    if lab.is_empty() || k == 0 {
+        return None;
+    }
+    let fixed: Vec<[f32; 3]> = pinned.iter().map(|c| to_lab(*c)).collect();
+
+    let mut best: Option<(f32, Vec<[f32; 3]>, Vec<usize>)> = None;
+    for run in 0..params.run.max(1) {
+        let mut rng = Rng(params.seed.wrapping_add(run as u64) | 1);
+        let mut centroids = seed_centroids(&lab, &weights, fixed.clone(), k, &mut rng);
+        let mut indices = vec![0; lab.len()];
+        for _ in 0..params.max_iter.max(1) {
+            for (index, color) in indices.iter_mut().zip(&lab) {
+                *index = nearest(&centroids, color).0;
+            }
+            let mut sums = vec![([0.0; 3], 0.0); centroids.len()];
+            for ((color, weight), index) in lab.iter().zip(&weights).zip(&indices) {
+                let (sum, total) = &mut sums[*index];
+                for c in 0..3 {
+                    sum[c] += color[c] * weight;
+                }
+                *total += weight;
+            }
+            let mut shift = [0.0; 3];
+            let free = centroids.iter_mut().zip(sums).skip(fixed.len());
+            for (centroid, (sum, total)) in free {
+                // Empty clusters stay where they are.
+                if total > 0.0 {
+                    let moved = sum.map(|s| s / total);
+                    for c in 0..3 {
+                        shift[c] += moved[c] - centroid[c];
+                    }
+                    *centroid = moved;
+                }
+            }
+            if shift.iter().map(|s| s * s).sum::<f32>() <= params.converge {
+                break;
+            }
+        }
+        for (index, color) in indices.iter_mut().zip(&lab) {
+            *index = nearest(&centroids, color).0;
+        }
+        let score: f32 = lab
+            .iter()
+            .zip(&weights)
+            .zip(&indices)
+            .map(|((color, weight), index)| weight * distance(color, &centroids[*index]))
+            .sum();
+        if best.as_ref().map_or(true, |b| score < b.0) {
+            best = Some((score, centroids, indices));
+        }
+    }
+
+    let (_, centroids, indices) = best?;
+    let total: usize = points.iter().map(|p| p.pixels).sum();
+    let mut coverage = vec![0.0; centroids.len()];
+    for (point, index) in points.iter().zip(indices) {
+        coverage[index] += point.pixels as f32 / total as f32;
+    }
+    let lab_palette: Vec<Lab> = centroids
+        .iter()
+        .map(|c| Lab::new(c[0], c[1], c[2]))
+        .collect();
+    // Pinned colors exactly as given, not through Lab and back.
+    let colors = lab_palette
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match pinned.get(i) {
+            Some([r, g, b]) => Color32::from_rgb(*r, *g, *b),
+            None => {
+                let rgb: Srgb<u8> = Srgb::from_color(*c).into_format();
+                Color32::from_rgb(rgb.red, rgb.green, rgb.blue)
+            }
+        })
+        .collect();
+    Some((colors, lab_palette, coverage))
+}
+
+/// k-means++ after the `centroids` given: each next centroid is picked with
+/// a chance growing with its weight and its squared distance to the
+/// centroids so far.
+fn seed_centroids(
+    lab: &[[f32; 3]],
+    weights: &[f32],
+    mut centroids: Vec<[f32; 3]>,
+    k: usize,
+    rng: &mut Rng,
+) -> Vec<[f32; 3]> {
+    // Distance of every pixel to its closest centroid so far, kept up to
+    // date with each pick instead of searching all centroids again.
+    let mut closest: Vec<f32> = lab.iter().map(|c| nearest(&centroids, c).1).collect();
+    let mut chances: Vec<f32> = if centroids.is_empty() {
+        weights.to_vec()
+    } else {
+        closest.iter().zip(weights).map(|(d, w)| w * d).collect()
+    };
+    while centroids.len() < k {
+        let total: f32 = chances.iter().sum();
+        let pick = if total > 0.0 {
+            let mut target = rng.next_f32() * total;
+            chances
+                .iter()
+                .position(|c| {
+                    target -= c;
+                    target <= 0.0
+                })
+                .unwrap_or(lab.len() - 1)
+        } else {
+            // Fewer distinct colors than k.
+            (rng.next_f32() * lab.len() as f32) as usize % lab.len()
+        };
+        let new = lab[pick];
+        centroids.push(new);
+        let pixels = chances.iter_mut().zip(&mut closest).zip(lab).zip(weights);
+        for (((chance, closest), color), weight) in pixels {
+            *closest = closest.min(distance(&new, color));
+            *chance = weight * *closest;
+        }
+    }
+    centroids
+}
+
+fn to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    let lab: Lab = Srgb::new(rgb[0], rgb[1], rgb[2])
+        .into_format::<f32>()
+        .into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+fn nearest(centroids: &[[f32; 3]], color: &[f32; 3]) -> (usize, f32) {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i, distance(c, color)))
+        .fold((0, f32::MAX), |best, d| if d.1 < best.1 { d } else { best })
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+/// xorshift64, enough for picking seeds.
+struct Rng(u64);
+
+impl Rng {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    const PARAMS: KmeansParams = KmeansParams {
+        k: 3,
+        run: 3,
+        max_iter: 20,
+        converge: 1.0,
+        verbose: false,
+        seed: 0,
+    };
+
+    /// Half dark blue, a quarter each of two reds.
+    fn picture() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| match (x < 4, y < 4) {
+            (true, _) => Rgb([10, 10, 80]),
+            (false, true) => Rgb([220, 30, 30]),
+            (false, false) => Rgb([180, 20, 40]),
+        }))
+    }
+
+    #[test]
+    fn pinned_colors_come_first_as_given() {
+        let pinned = [[255, 255, 255], [1, 2, 3]];
+        let (colors, lab, coverage) = pinned_kmeans(picture(), PARAMS, &pinned).unwrap();
+        assert_eq!(colors.len(), 3);
+        assert_eq!(lab.len(), 3);
+        assert_eq!(colors[0], Color32::from_rgb(255, 255, 255));
+        assert_eq!(colors[1], Color32::from_rgb(1, 2, 3));
+        // Nothing is close to white, the dark pixels go to the dark pin.
+        assert_eq!(coverage[0], 0.0);
+        assert_eq!(coverage[1], 0.5);
+        assert!((coverage.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn more_pins_than_colors_are_all_kept() {
+        let pinned = [[0, 0, 0], [255, 0, 0], [0, 0, 255], [255, 255, 255]];
+        let (colors, _, _) = pinned_kmeans(picture(), PARAMS, &pinned).unwrap();
+        let expected: Vec<Color32> = pinned
+            .iter()
+            .map(|[r, g, b]| Color32::from_rgb(*r, *g, *b))
+            .collect();
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn heavy_pixels_pull_the_colors() {
+        let mut image = picture().to_rgb8();
+        image.put_pixel(0, 0, Rgb([250, 250, 0]));
+        let image = DynamicImage::ImageRgb8(image);
+        let params = KmeansParams { k: 1, ..PARAMS };
+
+        let uniform = vec![1.0; 64];
+        let (colors, _, _) = weighted_kmeans(&image, &uniform, params, &[]).unwrap();
+        assert!(colors[0].g() < 100);
+
+        let mut weights = uniform;
+        weights[0] = 1000.0;
+        let (colors, _, coverage) = weighted_kmeans(&image, &weights, params, &[]).unwrap();
+        assert!(colors[0].r() > 200 && colors[0].g() > 200, "{:?}", colors);
+        // Coverage still counts pixels, not weight.
+        assert_eq!(coverage, [1.0]);
+    }
+}